@@ -0,0 +1,151 @@
+use crate::{Ray, Vec3};
+
+/// An axis-aligned bounding box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    min: Vec3,
+    max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(a: Vec3, b: Vec3) -> Self {
+        Self {
+            min: a.inf(b),
+            max: a.sup(b),
+        }
+    }
+
+    /// An inverted box that acts as the identity for `union`.
+    pub fn empty() -> Self {
+        Self {
+            min: Vec3::from([f64::INFINITY; 3]),
+            max: Vec3::from([f64::NEG_INFINITY; 3]),
+        }
+    }
+
+    pub fn min(&self) -> Vec3 {
+        self.min
+    }
+
+    pub fn max(&self) -> Vec3 {
+        self.max
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            min: self.min.inf(other.min),
+            max: self.max.sup(other.max),
+        }
+    }
+
+    pub fn grow(&self, point: Vec3) -> Self {
+        Self {
+            min: self.min.inf(point),
+            max: self.max.sup(point),
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        0.5 * (self.min + self.max)
+    }
+
+    pub fn extent(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let d = self.extent();
+        if d.x() < 0.0 || d.y() < 0.0 || d.z() < 0.0 {
+            0.0
+        } else {
+            2.0 * (d.x() * d.y() + d.y() * d.z() + d.z() * d.x())
+        }
+    }
+
+    /// Index of the axis along which the box is widest.
+    pub fn longest_axis(&self) -> usize {
+        let d = self.extent();
+        if d.x() > d.y() && d.x() > d.z() {
+            0
+        } else if d.y() > d.z() {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// Slab test against a ray whose reciprocal direction has already been
+    /// computed, returning whether the box overlaps `[t_min, t_max]`.
+    pub fn hit_inv(&self, origin: Vec3, inv_dir: Vec3, t_min: f64, t_max: f64) -> bool {
+        self.clip(origin, inv_dir, t_min, t_max).is_some()
+    }
+
+    pub fn hit(&self, ray: Ray, t_min: f64, t_max: f64) -> bool {
+        self.hit_inv(ray.origin(), 1.0 / ray.direction(), t_min, t_max)
+    }
+
+    /// The part of `[t_min, t_max]` in which `ray` is inside the box, if any.
+    pub fn interval(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<(f64, f64)> {
+        self.clip(ray.origin(), 1.0 / ray.direction(), t_min, t_max)
+    }
+
+    fn clip(
+        &self,
+        origin: Vec3,
+        inv_dir: Vec3,
+        mut t_min: f64,
+        mut t_max: f64,
+    ) -> Option<(f64, f64)> {
+        for axis in 0..3 {
+            let t0 = (self.min[axis] - origin[axis]) * inv_dir[axis];
+            let t1 = (self.max[axis] - origin[axis]) * inv_dir[axis];
+            let (t0, t1) = if inv_dir[axis] < 0.0 {
+                (t1, t0)
+            } else {
+                (t0, t1)
+            };
+            // written so that NaNs from 0 * inf leave the interval untouched
+            t_min = if t0 > t_min { t0 } else { t_min };
+            t_max = if t1 < t_max { t1 } else { t_max };
+            if t_max < t_min {
+                return None;
+            }
+        }
+        Some((t_min, t_max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Aabb;
+    use crate::Ray;
+
+    #[test]
+    fn test_union_and_area() {
+        let a = Aabb::new(vec3![0, 0, 0], vec3![1, 1, 1]);
+        let b = Aabb::new(vec3![2, 2, 2], vec3![1, 1, 1]);
+        let u = a.union(&b);
+        assert_eq!(u.min(), vec3![0, 0, 0]);
+        assert_eq!(u.max(), vec3![2, 2, 2]);
+        assert_eq!(u.surface_area(), 24.0);
+        assert_eq!(Aabb::empty().union(&a), a);
+        assert_eq!(Aabb::empty().surface_area(), 0.0);
+    }
+
+    #[test]
+    fn test_hit() {
+        let b = Aabb::new(vec3![-1, -1, -1], vec3![1, 1, 1]);
+        assert!(b.hit(Ray::new(vec3![0, 0, -5], vec3![0, 0, 1]), 0.0, f64::MAX));
+        assert!(!b.hit(Ray::new(vec3![0, 0, -5], vec3![0, 0, -1]), 0.0, f64::MAX));
+        assert!(!b.hit(Ray::new(vec3![0, 2, -5], vec3![0, 0, 1]), 0.0, f64::MAX));
+        assert!(!b.hit(Ray::new(vec3![0, 0, -5], vec3![0, 0, 1]), 0.0, 3.0));
+        assert_eq!(
+            b.interval(Ray::new(vec3![0, 0, -5], vec3![0, 0, 2]), 0.0, f64::MAX),
+            Some((2.0, 3.0))
+        );
+        assert_eq!(
+            b.interval(Ray::new(vec3![0, 0, 0], vec3![0, 0, 1]), 0.5, 0.75),
+            Some((0.5, 0.75))
+        );
+    }
+}
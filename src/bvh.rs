@@ -0,0 +1,491 @@
+use crate::{Aabb, HitRecord, Hittable, MediumHit, Ray, Vec3};
+
+const NBINS: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;
+const TRAVERSAL_COST: f64 = 0.125;
+/// Past this depth nodes are split at the centroid median, which keeps the
+/// tree shallow enough for the fixed size traversal stack.
+const MAX_SAH_DEPTH: usize = 32;
+const STACK_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+enum Node {
+    Leaf {
+        bbox: Aabb,
+        start: usize,
+        len: usize,
+    },
+    /// The left child is always the next node; `right` is the index of the
+    /// right child.
+    Interior {
+        bbox: Aabb,
+        axis: usize,
+        right: usize,
+    },
+}
+
+impl Node {
+    fn bbox(&self) -> &Aabb {
+        match self {
+            Node::Leaf { bbox, .. } | Node::Interior { bbox, .. } => bbox,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Primitive {
+    index: usize,
+    bbox: Aabb,
+    centroid: Vec3,
+}
+
+/// A bounding volume hierarchy built with a binned surface area heuristic.
+///
+/// Hittables without a bounding box (e.g. infinite planes) are kept out of
+/// the tree and tested linearly.
+pub struct Bvh<H> {
+    hittables: Vec<H>,
+    /// The position of each hittable in the vector the tree was built from.
+    ids: Vec<usize>,
+    unbounded: Vec<(usize, H)>,
+    nodes: Vec<Node>,
+}
+
+impl<H> Bvh<H>
+where
+    H: Hittable,
+{
+    pub fn new(hittables: Vec<H>) -> Self {
+        let (bounded, unbounded): (Vec<_>, Vec<_>) = hittables
+            .into_iter()
+            .enumerate()
+            .map(|(id, hittable)| (hittable.bounding_box(), id, hittable))
+            .partition(|(bbox, _, _)| bbox.is_some());
+
+        let mut primitives = bounded
+            .iter()
+            .enumerate()
+            .map(|(index, (bbox, _, _))| {
+                let bbox = bbox.expect("partitioned on bounding box");
+                Primitive {
+                    index,
+                    bbox,
+                    centroid: bbox.centroid(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut nodes = Vec::with_capacity(2 * primitives.len());
+        if !primitives.is_empty() {
+            build(&mut nodes, &mut primitives, 0, 0);
+        }
+
+        // reorder the hittables so that every leaf refers to a contiguous range
+        let mut bounded = bounded
+            .into_iter()
+            .map(|(_, id, hittable)| Some((id, hittable)))
+            .collect::<Vec<_>>();
+        let (ids, hittables) = primitives
+            .iter()
+            .map(|primitive| {
+                bounded[primitive.index]
+                    .take()
+                    .expect("each index is unique")
+            })
+            .unzip();
+
+        Self {
+            hittables,
+            ids,
+            unbounded: unbounded
+                .into_iter()
+                .map(|(_, id, hittable)| (id, hittable))
+                .collect(),
+            nodes,
+        }
+    }
+}
+
+fn make_leaf(nodes: &mut Vec<Node>, bbox: Aabb, start: usize, len: usize) -> usize {
+    nodes.push(Node::Leaf { bbox, start, len });
+    nodes.len() - 1
+}
+
+fn build(nodes: &mut Vec<Node>, primitives: &mut [Primitive], start: usize, depth: usize) -> usize {
+    let len = primitives.len();
+    let bbox = primitives
+        .iter()
+        .fold(Aabb::empty(), |acc, primitive| acc.union(&primitive.bbox));
+
+    if len == 1 {
+        return make_leaf(nodes, bbox, start, len);
+    }
+
+    let centroid_bounds = primitives
+        .iter()
+        .fold(Aabb::empty(), |acc, primitive| acc.grow(primitive.centroid));
+    let axis = centroid_bounds.longest_axis();
+    let cmin = centroid_bounds.min()[axis];
+    let cextent = centroid_bounds.extent()[axis];
+
+    if cextent <= 0.0 {
+        // every centroid coincides, so no split can separate them
+        return if len <= MAX_LEAF_SIZE {
+            make_leaf(nodes, bbox, start, len)
+        } else {
+            split(nodes, primitives, start, depth, bbox, axis, len / 2)
+        };
+    }
+
+    if depth >= MAX_SAH_DEPTH {
+        primitives.select_nth_unstable_by(len / 2, |a, b| {
+            a.centroid[axis].total_cmp(&b.centroid[axis])
+        });
+        return split(nodes, primitives, start, depth, bbox, axis, len / 2);
+    }
+
+    let bin_of = |primitive: &Primitive| {
+        (((primitive.centroid[axis] - cmin) / cextent * NBINS as f64) as usize).min(NBINS - 1)
+    };
+
+    let mut counts = [0usize; NBINS];
+    let mut bounds = [Aabb::empty(); NBINS];
+    for primitive in primitives.iter() {
+        let bin = bin_of(primitive);
+        counts[bin] += 1;
+        bounds[bin] = bounds[bin].union(&primitive.bbox);
+    }
+
+    // sweep from the right so that each split's right side is available
+    let mut right_areas = [0.0; NBINS];
+    let mut right_counts = [0usize; NBINS];
+    let (mut acc_bbox, mut acc_count) = (Aabb::empty(), 0);
+    for bin in (1..NBINS).rev() {
+        acc_bbox = acc_bbox.union(&bounds[bin]);
+        acc_count += counts[bin];
+        right_areas[bin] = acc_bbox.surface_area();
+        right_counts[bin] = acc_count;
+    }
+
+    let area = bbox.surface_area();
+    let (mut best_split, mut best_cost) = (0, f64::INFINITY);
+    let (mut acc_bbox, mut acc_count) = (Aabb::empty(), 0);
+    for bin in 1..NBINS {
+        acc_bbox = acc_bbox.union(&bounds[bin - 1]);
+        acc_count += counts[bin - 1];
+        let cost = TRAVERSAL_COST
+            + (acc_count as f64 * acc_bbox.surface_area()
+                + right_counts[bin] as f64 * right_areas[bin])
+                / area;
+        if cost < best_cost {
+            best_split = bin;
+            best_cost = cost;
+        }
+    }
+
+    if len <= MAX_LEAF_SIZE && best_cost >= len as f64 {
+        return make_leaf(nodes, bbox, start, len);
+    }
+
+    let mid = itertools::partition(primitives.iter_mut(), |primitive| {
+        bin_of(primitive) < best_split
+    });
+    let mid = if mid == 0 || mid == len {
+        primitives.select_nth_unstable_by(len / 2, |a, b| {
+            a.centroid[axis].total_cmp(&b.centroid[axis])
+        });
+        len / 2
+    } else {
+        mid
+    };
+    split(nodes, primitives, start, depth, bbox, axis, mid)
+}
+
+fn split(
+    nodes: &mut Vec<Node>,
+    primitives: &mut [Primitive],
+    start: usize,
+    depth: usize,
+    bbox: Aabb,
+    axis: usize,
+    mid: usize,
+) -> usize {
+    let index = nodes.len();
+    nodes.push(Node::Interior {
+        bbox,
+        axis,
+        right: 0,
+    });
+    let (left, right) = primitives.split_at_mut(mid);
+    build(nodes, left, start, depth + 1);
+    let right_index = build(nodes, right, start + mid, depth + 1);
+    if let Node::Interior { right, .. } = &mut nodes[index] {
+        *right = right_index;
+    }
+    index
+}
+
+impl<H> Bvh<H>
+where
+    H: Hittable,
+{
+    /// The closest hit along with the position of the hittable that produced
+    /// it in the vector the hierarchy was built from.
+    pub fn hit_index(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<(usize, HitRecord<'_>)> {
+        self.hit_object(ray, t_min, t_max)
+            .map(|(id, _, rec)| (id, rec))
+    }
+
+    /// The closest hit along with the hittable that produced it and its
+    /// position.
+    pub(crate) fn hit_object(
+        &self,
+        ray: Ray,
+        t_min: f64,
+        t_max: f64,
+    ) -> Option<(usize, &H, HitRecord<'_>)> {
+        self.closest(ray, t_min, t_max, |hittable, closest_so_far| {
+            let rec = hittable.hit(ray, t_min, closest_so_far)?;
+            Some((rec.t, rec))
+        })
+    }
+
+    /// The result of `hit` for the hittable that gives the smallest
+    /// distance, where `hit` is given the distance to beat and returns that
+    /// distance along with the result.
+    fn closest<'a, R>(
+        &'a self,
+        ray: Ray,
+        t_min: f64,
+        t_max: f64,
+        hit: impl Fn(&'a H, f64) -> Option<(f64, R)>,
+    ) -> Option<(usize, &'a H, R)> {
+        let mut rec = None;
+        let mut closest_so_far = t_max;
+
+        for (id, hittable) in &self.unbounded {
+            if let Some((t, temp_rec)) = hit(hittable, closest_so_far) {
+                closest_so_far = t;
+                rec = Some((*id, hittable, temp_rec));
+            }
+        }
+
+        if self.nodes.is_empty() {
+            return rec;
+        }
+
+        let origin = ray.origin();
+        let inv_dir = 1.0 / ray.direction();
+        let mut stack = [0usize; STACK_SIZE];
+        let mut stack_len = 0;
+        let mut index = 0;
+
+        loop {
+            let node = &self.nodes[index];
+            if node.bbox().hit_inv(origin, inv_dir, t_min, closest_so_far) {
+                match *node {
+                    Node::Leaf { start, len, .. } => {
+                        let range = start..start + len;
+                        for (id, hittable) in
+                            self.ids[range.clone()].iter().zip(&self.hittables[range])
+                        {
+                            if let Some((t, temp_rec)) = hit(hittable, closest_so_far) {
+                                closest_so_far = t;
+                                rec = Some((*id, hittable, temp_rec));
+                            }
+                        }
+                    }
+                    Node::Interior { axis, right, .. } => {
+                        // visit the near child first so the far one can be culled
+                        let (near, far) = if inv_dir[axis] < 0.0 {
+                            (right, index + 1)
+                        } else {
+                            (index + 1, right)
+                        };
+                        stack[stack_len] = far;
+                        stack_len += 1;
+                        index = near;
+                        continue;
+                    }
+                }
+            }
+            if stack_len == 0 {
+                break;
+            }
+            stack_len -= 1;
+            index = stack[stack_len];
+        }
+        rec
+    }
+}
+
+impl<H> Hittable for Bvh<H>
+where
+    H: Hittable,
+{
+    fn hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        self.hit_object(ray, t_min, t_max).map(|(_, _, rec)| rec)
+    }
+
+    fn occluded(&self, ray: Ray, t_min: f64, t_max: f64) -> bool {
+        if self
+            .unbounded
+            .iter()
+            .any(|(_, hittable)| hittable.occluded(ray, t_min, t_max))
+        {
+            return true;
+        }
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        // any hit will do, so children are visited in whatever order
+        let origin = ray.origin();
+        let inv_dir = 1.0 / ray.direction();
+        let mut stack = [0usize; STACK_SIZE];
+        let mut stack_len = 0;
+        let mut index = 0;
+        loop {
+            let node = &self.nodes[index];
+            if node.bbox().hit_inv(origin, inv_dir, t_min, t_max) {
+                match *node {
+                    Node::Leaf { start, len, .. } => {
+                        if self.hittables[start..start + len]
+                            .iter()
+                            .any(|hittable| hittable.occluded(ray, t_min, t_max))
+                        {
+                            return true;
+                        }
+                    }
+                    Node::Interior { right, .. } => {
+                        stack[stack_len] = right;
+                        stack_len += 1;
+                        index += 1;
+                        continue;
+                    }
+                }
+            }
+            if stack_len == 0 {
+                return false;
+            }
+            stack_len -= 1;
+            index = stack[stack_len];
+        }
+    }
+
+    fn medium_hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<MediumHit<'_>> {
+        self.closest(ray, t_min, t_max, |hittable, closest_so_far| {
+            let rec = hittable.medium_hit(ray, t_min, closest_so_far)?;
+            Some((rec.t_enter, rec))
+        })
+        .map(|(_, _, rec)| rec)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        if self.unbounded.is_empty() {
+            self.nodes.first().map(|node| *node.bbox())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bvh;
+    use crate::{
+        utils::{rand, randvec, seeded_rng},
+        Hittable, HittableList, Lambertian, Ray, Sphere, Vec3,
+    };
+
+    fn spheres(params: &[(Vec3, f64)]) -> Vec<Sphere> {
+        params
+            .iter()
+            .map(|&(center, radius)| Sphere::new(center, radius, Lambertian::new(vec3![1, 1, 1])))
+            .collect()
+    }
+
+    #[test]
+    fn test_bvh_matches_list() {
+        let rng = &mut seeded_rng(0);
+        let params = (0..500)
+            .map(|_| (20.0 * randvec(rng) - 10.0, 0.1 + rand(rng)))
+            .collect::<Vec<_>>();
+        let list = HittableList::new(spheres(&params));
+        let bvh = Bvh::new(spheres(&params));
+        assert_eq!(list.bounding_box(), bvh.bounding_box());
+
+        for _ in 0..2_000 {
+            let ray = Ray::new(30.0 * randvec(rng) - 15.0, randvec(rng) - 0.5);
+            let expected = list.hit(ray, 0.001, f64::MAX);
+            let result = bvh.hit(ray, 0.001, f64::MAX);
+            assert_eq!(
+                expected.map(|rec| (rec.t, rec.point, rec.normal)),
+                result.map(|rec| (rec.t, rec.point, rec.normal))
+            );
+        }
+    }
+
+    #[test]
+    fn test_occluded_matches_hit() {
+        let rng = &mut seeded_rng(1);
+        let params = (0..200)
+            .map(|_| (20.0 * randvec(rng) - 10.0, 0.1 + rand(rng)))
+            .collect::<Vec<_>>();
+        let bvh = Bvh::new(spheres(&params));
+        let mut occluded = 0;
+        for _ in 0..2_000 {
+            let ray = Ray::new(30.0 * randvec(rng) - 15.0, randvec(rng) - 0.5);
+            let t_max = 40.0 * rand(rng);
+            let hit = bvh.hit(ray, 0.001, t_max).is_some();
+            assert_eq!(bvh.occluded(ray, 0.001, t_max), hit);
+            occluded += usize::from(hit);
+        }
+        assert!(occluded > 100 && occluded < 1900, "{}", occluded);
+    }
+
+    #[test]
+    fn test_hit_index() {
+        let bvh = Bvh::new(spheres(&[
+            (vec3![0, 0, 5], 1.0),
+            (vec3![0, 0, 10], 1.0),
+            (vec3![0, 0, 2], 0.5),
+        ]));
+        let ray = Ray::new(vec3![0, 0, 0], vec3![0, 0, 1]);
+        assert_eq!(bvh.hit_index(ray, 0.0, f64::MAX).map(|(id, _)| id), Some(2));
+        assert_eq!(bvh.hit_index(ray, 3.0, f64::MAX).map(|(id, _)| id), Some(0));
+        assert_eq!(bvh.hit_index(ray, 7.0, f64::MAX).map(|(id, _)| id), Some(1));
+    }
+
+    #[test]
+    fn test_empty_bvh() {
+        let bvh = Bvh::new(spheres(&[]));
+        assert!(bvh.bounding_box().is_none());
+        assert!(bvh
+            .hit(Ray::new(vec3![0, 0, 0], vec3![1, 0, 0]), 0.0, f64::MAX)
+            .is_none());
+    }
+
+    #[test]
+    fn test_bvh_respects_t_max() {
+        let bvh = Bvh::new(vec![Sphere::new(
+            vec3![0, 0, 5],
+            1.0,
+            Lambertian::new(vec3![1, 1, 1]),
+        )]);
+        let ray = Ray::new(vec3![0, 0, 0], vec3![0, 0, 1]);
+        assert_eq!(bvh.hit(ray, 0.0, f64::MAX).map(|rec| rec.t), Some(4.0));
+        assert!(bvh.hit(ray, 0.0, 3.0).is_none());
+    }
+
+    #[test]
+    fn test_nan_bounds() {
+        // a stray NaN from a scene file mustn't stop the others being built
+        let mut params = vec![(vec3![f64::NAN, 0, 0], 1.0); 8];
+        params.extend((0..8).map(|i| (vec3![0, 0, 3 * i + 5], 1.0)));
+        params.extend(vec![(vec3![0, 0, 0], f64::NAN); 8]);
+        let bvh = Bvh::new(spheres(&params));
+        let ray = Ray::new(vec3![0, 0, 0], vec3![0, 0, 1]);
+        assert_eq!(bvh.hit(ray, 0.0, f64::MAX).map(|rec| rec.t), Some(4.0));
+    }
+}
@@ -1,4 +1,8 @@
-use crate::{ray::Ray, utils::rand, vec3::Vec3};
+use crate::{
+    ray::Ray,
+    utils::{rand, Rng},
+    vec3::Vec3,
+};
 
 pub struct Camera {
     origin: Vec3,
@@ -8,13 +12,15 @@ pub struct Camera {
     u: Vec3,
     v: Vec3,
     lens_radius: f64,
+    /// The times the shutter opens and closes.
+    shutter: (f64, f64),
 }
 
-fn random_in_unit_disk() -> Vec3 {
+fn random_in_unit_disk(rng: &mut Rng) -> Vec3 {
     let one_one_zero = vec3![1, 1, 0];
-    let mut p = 2.0 * vec3![rand(), rand(), 0.0] - one_one_zero;
+    let mut p = 2.0 * vec3![rand(rng), rand(rng), 0.0] - one_one_zero;
     while p.norm2() >= 1.0 {
-        p = 2.0 * vec3![rand(), rand(), 0.0] - one_one_zero;
+        p = 2.0 * vec3![rand(rng), rand(rng), 0.0] - one_one_zero;
     }
     p
 }
@@ -48,15 +54,34 @@ impl Camera {
             u,
             v,
             lens_radius,
+            shutter: (0.0, 0.0),
         }
     }
 
-    pub fn ray(&self, s: f64, t: f64) -> Ray {
-        let rd = self.lens_radius * random_in_unit_disk();
+    /// Keep the shutter open from time `open` to `close`, so that objects
+    /// moving in the meantime are blurred. The shutter is instantaneous at
+    /// time 0 by default.
+    pub fn with_shutter(self, open: f64, close: f64) -> Self {
+        Self {
+            shutter: (open, close),
+            ..self
+        }
+    }
+
+    pub fn ray(&self, s: f64, t: f64, rng: &mut Rng) -> Ray {
+        let rd = self.lens_radius * random_in_unit_disk(rng);
         let offset = self.u * rd.x() + self.v * rd.y();
-        Ray::new(
+        let (open, close) = self.shutter;
+        // an instantaneous shutter leaves the random stream as it was
+        let time = if open == close {
+            open
+        } else {
+            open + rand(rng) * (close - open)
+        };
+        Ray::with_time(
             self.origin + offset,
             self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset,
+            time,
         )
     }
 }
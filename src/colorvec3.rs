@@ -22,12 +22,14 @@ impl ColorVec3 {
     }
 }
 
+/// Rounds each channel in `[0, 1]` to the nearest 8-bit value, clamping
+/// anything outside.
 impl From<Vec3> for ColorVec3 {
     fn from(vec: Vec3) -> Self {
         Self(
             (vec * f64::from(u8::MAX))
                 .into_inner()
-                .map(|value| value as u8),
+                .map(|value| value.round().clamp(0.0, f64::from(u8::MAX)) as u8),
         )
     }
 }
@@ -63,4 +65,10 @@ mod tests {
         assert_eq!(u.g(), (255.0 * 2.0) as u8);
         assert_eq!(u.b(), (255.0 * 3.0) as u8);
     }
+
+    #[test]
+    fn test_color_vec_rounds() {
+        let u = ColorVec3::from(Vec3::from([0.999, -0.5, 0.5]));
+        assert_eq!(u.into_array(), [255, 0, 128]);
+    }
 }
@@ -0,0 +1,280 @@
+//! Light arriving from infinitely far away, seen by rays that escape the
+//! scene.
+
+use crate::{
+    output::Image,
+    tonemap::luminance,
+    utils::{self, Rng},
+    Vec3,
+};
+use std::f64::consts::PI;
+
+pub trait Environment {
+    /// The radiance seen looking along `direction`, which needn't be a unit
+    /// vector.
+    fn radiance(&self, direction: Vec3) -> Vec3;
+
+    /// Whether light sampling should pick directions towards the
+    /// environment, which pays off for bright, small features such as the
+    /// sun, but not for smooth skies.
+    fn sampled(&self) -> bool {
+        false
+    }
+
+    /// A unit vector sampled in proportion to roughly how much light
+    /// arrives along it, uniformly by default.
+    fn sample(&self, rng: &mut Rng) -> Vec3 {
+        utils::random_unit_vector(rng)
+    }
+
+    /// The solid angle density with which `sample` picks `direction`.
+    fn pdf(&self, _direction: Vec3) -> f64 {
+        1.0 / (4.0 * PI)
+    }
+}
+
+/// The same radiance from every direction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Constant {
+    radiance: Vec3,
+}
+
+impl Constant {
+    pub fn new(radiance: Vec3) -> Self {
+        Self { radiance }
+    }
+}
+
+impl Environment for Constant {
+    fn radiance(&self, _direction: Vec3) -> Vec3 {
+        self.radiance
+    }
+}
+
+/// A blend from `bottom` straight down to `top` straight up, linear in the
+/// height of the direction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gradient {
+    bottom: Vec3,
+    top: Vec3,
+}
+
+impl Gradient {
+    pub fn new(bottom: Vec3, top: Vec3) -> Self {
+        Self { bottom, top }
+    }
+}
+
+impl Default for Gradient {
+    /// White to sky blue.
+    fn default() -> Self {
+        Self::new(Vec3::ones(), vec3![0.5, 0.7, 1.0])
+    }
+}
+
+impl Environment for Gradient {
+    fn radiance(&self, direction: Vec3) -> Vec3 {
+        self.bottom
+            .lerp(self.top, 0.5 * (direction.unitize().y() + 1.0))
+    }
+}
+
+/// An equirectangular (latitude-longitude) image, with straight up along
+/// the top row and the image's centre looking along -z, importance sampled
+/// by luminance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Equirectangular {
+    image: Image,
+    /// The rotation about the y axis, in radians.
+    rotation: f64,
+    intensity: f64,
+    /// The running sums of each row's weight.
+    marginal: Vec<f64>,
+    /// The running sums of the weights along each row, row after row.
+    conditional: Vec<f64>,
+}
+
+/// The index of the bin of `cdf`, a running sum, that `target` falls in.
+fn find_bin(cdf: &[f64], target: f64) -> usize {
+    cdf.partition_point(|&sum| sum <= target).min(cdf.len() - 1)
+}
+
+impl Equirectangular {
+    /// Panics if the image is empty. `rotation` turns the image about the y
+    /// axis, in degrees, and `intensity` scales its radiance.
+    pub fn new(image: Image, rotation: f64, intensity: f64) -> Self {
+        let (width, height) = (image.width(), image.height());
+        assert!(width > 0 && height > 0, "environment maps can't be empty");
+
+        // each pixel's luminance times the solid angle it covers
+        let mut marginal = Vec::with_capacity(height);
+        let mut conditional = Vec::with_capacity(width * height);
+        let mut total = 0.0;
+        for (y, row) in image.pixels().chunks(width).enumerate() {
+            let sin_theta = (PI * (y as f64 + 0.5) / height as f64).sin();
+            let mut sum = 0.0;
+            for &pixel in row {
+                sum += luminance(pixel).max(0.0) * sin_theta;
+                conditional.push(sum);
+            }
+            total += sum;
+            marginal.push(total);
+        }
+
+        Self {
+            image,
+            rotation: rotation.to_radians(),
+            intensity,
+            marginal,
+            conditional,
+        }
+    }
+
+    /// The image coordinates, each in `[0, 1]`, that `direction` looks at.
+    fn uv(&self, direction: Vec3) -> (f64, f64) {
+        let d = direction.unitize();
+        let phi = d.x().atan2(-d.z()) - self.rotation;
+        let u = (0.5 + phi / (2.0 * PI)).rem_euclid(1.0);
+        (u, d.y().clamp(-1.0, 1.0).acos() / PI)
+    }
+
+    /// The pixel at image coordinates `(u, v)`.
+    fn pixel(&self, u: f64, v: f64) -> (usize, usize) {
+        let (width, height) = (self.image.width(), self.image.height());
+        (
+            ((u * width as f64) as usize).min(width - 1),
+            ((v * height as f64) as usize).min(height - 1),
+        )
+    }
+
+    fn total(&self) -> f64 {
+        self.marginal.last().copied().unwrap_or(0.0)
+    }
+}
+
+impl Environment for Equirectangular {
+    fn radiance(&self, direction: Vec3) -> Vec3 {
+        let (u, v) = self.uv(direction);
+        let (x, y) = self.pixel(u, v);
+        self.intensity * self.image.pixels()[y * self.image.width() + x]
+    }
+
+    fn sampled(&self) -> bool {
+        self.total() > 0.0
+    }
+
+    fn sample(&self, rng: &mut Rng) -> Vec3 {
+        let width = self.image.width();
+        let y = find_bin(&self.marginal, utils::rand(rng) * self.total());
+        let row = &self.conditional[y * width..(y + 1) * width];
+        let x = find_bin(row, utils::rand(rng) * row[width - 1]);
+
+        // uniformly within the pixel
+        let u = (x as f64 + utils::rand(rng)) / width as f64;
+        let v = (y as f64 + utils::rand(rng)) / self.image.height() as f64;
+        let phi = (u - 0.5) * 2.0 * PI + self.rotation;
+        let (sin_theta, cos_theta) = (v * PI).sin_cos();
+        vec3![sin_theta * phi.sin(), cos_theta, -sin_theta * phi.cos()]
+    }
+
+    fn pdf(&self, direction: Vec3) -> f64 {
+        let (u, v) = self.uv(direction);
+        let sin_theta = (v * PI).sin();
+        let total = self.total();
+        if sin_theta <= 0.0 || total <= 0.0 {
+            return 0.0;
+        }
+        let (width, height) = (self.image.width(), self.image.height());
+        let (x, y) = self.pixel(u, v);
+        let index = y * width + x;
+        let weight = self.conditional[index]
+            - if x > 0 {
+                self.conditional[index - 1]
+            } else {
+                0.0
+            };
+        // from the pixel's probability, through its area in the unit square,
+        // to solid angle
+        weight / total * (width * height) as f64 / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Environment, Equirectangular, Gradient};
+    use crate::{output::Image, utils::seeded_rng, Vec3};
+
+    /// A dim image with one bright pixel.
+    fn hotspot() -> Equirectangular {
+        let mut pixels = vec![vec3![0.1, 0.1, 0.1]; 16 * 8];
+        pixels[3 * 16 + 5] = vec3![100, 50, 20];
+        Equirectangular::new(Image::new(16, 8, pixels), 30.0, 2.0)
+    }
+
+    #[test]
+    fn test_gradient() {
+        let sky = Gradient::default();
+        assert_eq!(sky.radiance(vec3![0, -3, 0]), Vec3::ones());
+        assert_eq!(sky.radiance(vec3![0, 1, 0]), vec3![0.5, 0.7, 1.0]);
+        assert!(!sky.sampled());
+    }
+
+    #[test]
+    fn test_equirectangular_sampling() {
+        let environment = hotspot();
+        let rng = &mut seeded_rng(0);
+        let mut bright = 0;
+        for _ in 0..1000 {
+            let direction = environment.sample(rng);
+            assert!((direction.norm() - 1.0).abs() < 1e-9);
+            // samples land in their own pixels, rotation and all
+            let (u, v) = environment.uv(direction);
+            if environment.pixel(u, v) == (5, 3) {
+                bright += 1;
+                assert_eq!(environment.radiance(direction), vec3![200, 100, 40]);
+            }
+        }
+        assert!(bright > 800, "{}", bright);
+
+        // the density integrates to one over the sphere
+        let n = 200_000;
+        let total: f64 = (0..n)
+            .map(|_| environment.pdf(crate::utils::random_unit_vector(rng)))
+            .sum();
+        let integral = total / n as f64 * 4.0 * std::f64::consts::PI;
+        assert!((integral - 1.0).abs() < 0.05, "{}", integral);
+    }
+
+    #[test]
+    fn test_estimates_irradiance() {
+        // the cosine weighted average over the upper hemisphere, estimated by
+        // sampling the environment
+        let environment = hotspot();
+        let rng = &mut seeded_rng(1);
+        let n = 100_000;
+        let sampled = (0..n)
+            .map(|_| {
+                let direction = environment.sample(rng);
+                environment.radiance(direction).x() * direction.y().max(0.0)
+                    / environment.pdf(direction)
+            })
+            .sum::<f64>()
+            / n as f64;
+        let uniform = (0..n)
+            .map(|_| {
+                let direction = crate::utils::random_unit_vector(rng);
+                environment.radiance(direction).x()
+                    * direction.y().max(0.0)
+                    * 4.0
+                    * std::f64::consts::PI
+            })
+            .sum::<f64>()
+            / n as f64;
+        assert!(
+            (sampled - uniform).abs() < 0.1 * uniform,
+            "{} {}",
+            sampled,
+            uniform
+        );
+    }
+}
@@ -0,0 +1,699 @@
+//! Estimating the light arriving along camera rays by path tracing.
+
+use crate::{
+    material,
+    utils::{self, Rng},
+    HitRecord, Hittable, MediumEvent, Ray, Scene, Vec3,
+};
+
+/// A way of estimating what's seen along camera rays, usually the radiance
+/// but possibly something else to debug a scene.
+pub trait Integrator {
+    fn radiance(&self, ray: Ray, scene: &Scene, rng: &mut Rng) -> Vec3;
+}
+
+/// Where a ray ends up after passing through any media.
+enum Vertex<'a> {
+    /// A surface, or a point where the ray scatters within a medium, and the
+    /// weight of the light that leaves it.
+    Hit(HitRecord<'a>, Vec3),
+    Absorbed,
+    Escaped,
+}
+
+/// Follow `ray` through the scene's media to the surface it hits, returning
+/// the light that the media emit along the way and where the ray ends up.
+///
+/// Each medium is crossed whole before looking for the next, so a medium
+/// overlapping the stretch of one entered earlier is skipped there.
+fn trace<'a>(ray: Ray, scene: &'a Scene, rng: &mut Rng) -> (Vec3, Vertex<'a>) {
+    let rec = scene.world.hit(ray, 0.001, f64::MAX);
+    let t_max = rec.as_ref().map_or(f64::MAX, |rec| rec.t);
+    let mut emitted = Vec3::zeros();
+    let mut t_min = 0.001;
+    while let Some(hit) = scene.world.medium_hit(ray, t_min, t_max) {
+        let (light, event) = hit.medium.sample(&hit.ray, hit.t_enter, hit.t_exit, rng);
+        emitted += light;
+        match event {
+            MediumEvent::Pass => t_min = hit.t_exit,
+            MediumEvent::Absorb => return (emitted, Vertex::Absorbed),
+            MediumEvent::Scatter { t, weight } => {
+                let rec = HitRecord {
+                    t,
+                    point: ray.point(t),
+                    normal: -ray.direction().unitize(),
+                    u: 0.0,
+                    v: 0.0,
+                    barycentrics: None,
+                    material: hit.medium.phase(),
+                };
+                return (emitted, Vertex::Hit(rec, weight));
+            }
+        }
+    }
+    let vertex = match rec {
+        Some(rec) => Vertex::Hit(rec, Vec3::ones()),
+        None => Vertex::Escaped,
+    };
+    (emitted, vertex)
+}
+
+/// The fraction of light that travels along `ray` between `t_min` and
+/// `t_max` without being scattered or absorbed by the scene's media, which,
+/// as in `trace`, mustn't overlap.
+fn transmittance(ray: Ray, scene: &Scene, mut t_min: f64, t_max: f64, rng: &mut Rng) -> f64 {
+    let mut result = 1.0;
+    while let Some(hit) = scene.world.medium_hit(ray, t_min, t_max) {
+        result *= hit
+            .medium
+            .transmittance(&hit.ray, hit.t_enter, hit.t_exit, rng);
+        t_min = hit.t_exit;
+    }
+    result
+}
+
+/// How many lights `sample_light` chooses between, counting the environment
+/// when it's worth sampling.
+fn light_count(scene: &Scene) -> usize {
+    scene.lights.len() + scene.delta_lights.len() + usize::from(scene.environment.sampled())
+}
+
+/// The density with which `sample_light` picks `direction` from `origin`
+/// towards one of the scene's lights.
+fn light_pdf(scene: &Scene, origin: Vec3, direction: Vec3, time: f64) -> f64 {
+    scene
+        .lights
+        .iter()
+        .map(|light| light.pdf_value(origin, direction, time))
+        .sum::<f64>()
+        / light_count(scene) as f64
+}
+
+/// The density with which `sample_light` picks `direction` towards the
+/// environment.
+fn environment_pdf(scene: &Scene, direction: Vec3) -> f64 {
+    if scene.environment.sampled() {
+        scene.environment.pdf(direction) / light_count(scene) as f64
+    } else {
+        0.0
+    }
+}
+
+/// Direct light at `rec` from a point sampled on a randomly chosen light, or
+/// from a direction sampled towards the environment, weighted against BSDF
+/// sampling. Punctual lights can't be found by BSDF sampling, so their light
+/// is taken as it is.
+fn sample_light(ray: &Ray, rec: &HitRecord, scene: &Scene, rng: &mut Rng) -> Vec3 {
+    let nlights = light_count(scene);
+    let index = ((utils::rand(rng) * nlights as f64) as usize).min(nlights - 1);
+    if let Some(light) = index
+        .checked_sub(scene.lights.len())
+        .and_then(|index| scene.delta_lights.get(index))
+    {
+        let illumination = match light.illuminate(rec.point) {
+            Some(illumination) => illumination,
+            None => return Vec3::zeros(),
+        };
+        let shadow_ray = ray.spawn(rec.point, illumination.direction);
+        if scene
+            .world
+            .occluded(shadow_ray, 0.001, illumination.distance)
+        {
+            return Vec3::zeros();
+        }
+        return rec.material.eval(ray, rec, &shadow_ray)
+            * illumination.irradiance
+            * (transmittance(shadow_ray, scene, 0.001, illumination.distance, rng)
+                * nlights as f64);
+    }
+
+    let (shadow_ray, t_max, radiance, pdf) = match scene.lights.get(index) {
+        Some(light) => {
+            let point = match light.sample_point(rec.point, ray.time(), rng) {
+                Some(point) => point,
+                None => return Vec3::zeros(),
+            };
+            let shadow_ray = ray.spawn(rec.point, point - rec.point);
+            // the sampled point is at t = 1, so anything hit well before it
+            // occludes it
+            let light_rec = match scene.world.hit(shadow_ray, 0.001, 1.0 + 1e-6) {
+                Some(light_rec) if light_rec.t > 1.0 - 1e-6 => light_rec,
+                _ => return Vec3::zeros(),
+            };
+            let pdf = light_pdf(scene, rec.point, shadow_ray.direction(), ray.time());
+            (
+                shadow_ray,
+                light_rec.t,
+                light_rec.material.emitted(&light_rec),
+                pdf,
+            )
+        }
+        None => {
+            let direction = scene.environment.sample(rng);
+            let shadow_ray = ray.spawn(rec.point, direction);
+            if scene.world.occluded(shadow_ray, 0.001, f64::MAX) {
+                return Vec3::zeros();
+            }
+            (
+                shadow_ray,
+                f64::MAX,
+                scene.environment.radiance(direction),
+                environment_pdf(scene, direction),
+            )
+        }
+    };
+
+    if pdf <= 0.0 {
+        return Vec3::zeros();
+    }
+    let bsdf_pdf = rec.material.pdf(ray, rec, &shadow_ray);
+    rec.material.eval(ray, rec, &shadow_ray)
+        * radiance
+        * (transmittance(shadow_ray, scene, 0.001, t_max, rng)
+            * utils::power_heuristic(pdf, bsdf_pdf)
+            / pdf)
+}
+
+/// A unidirectional path tracer, which follows each path from the camera for
+/// up to `max_depth` bounces.
+///
+/// Past `min_depth` bounces, Russian roulette ends paths at random, with a
+/// chance that grows as their throughput falls, and boosts the paths that
+/// survive to make up for the ones that don't.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PathTracer {
+    /// With no bounces, only what emits light directly is seen.
+    pub max_depth: usize,
+    pub min_depth: usize,
+    /// Only sample BSDFs, without next event estimation. Punctual lights can
+    /// only be found by sampling them, so they leave nothing lit.
+    pub bsdf_only: bool,
+}
+
+impl Default for PathTracer {
+    fn default() -> Self {
+        Self {
+            max_depth: 50,
+            min_depth: 3,
+            bsdf_only: false,
+        }
+    }
+}
+
+impl PathTracer {
+    /// An estimate of the radiance arriving along `ray`, and how many times
+    /// the path bounced.
+    ///
+    /// Unless `bsdf_only` is set, light is sampled at every bounce off a
+    /// non-specular surface and combined with BSDF sampling by multiple
+    /// importance sampling.
+    fn path(&self, mut ray: Ray, scene: &Scene, rng: &mut Rng) -> (Vec3, usize) {
+        let mut result = Vec3::zeros();
+        let mut throughput = Vec3::ones();
+        // the density with which the previous bounce sampled `ray`, or `None`
+        // if light sampling couldn't have produced it
+        let mut bsdf_pdf: Option<f64> = None;
+        let mut depth = 0;
+        loop {
+            let (medium_emitted, vertex) = trace(ray, scene, rng);
+            result += throughput * medium_emitted;
+            let (rec, weight) = match vertex {
+                Vertex::Hit(rec, weight) => (rec, weight),
+                Vertex::Absorbed => break,
+                Vertex::Escaped => {
+                    let radiance = scene.environment.radiance(ray.direction());
+                    result += throughput
+                        * match bsdf_pdf {
+                            Some(pdf) if scene.environment.sampled() => {
+                                let light_pdf = environment_pdf(scene, ray.direction());
+                                radiance * utils::power_heuristic(pdf, light_pdf)
+                            }
+                            _ => radiance,
+                        };
+                    break;
+                }
+            };
+            throughput *= weight;
+
+            let emitted = rec.material.emitted(&rec);
+            result += throughput
+                * match bsdf_pdf {
+                    Some(pdf) if emitted != Vec3::zeros() => {
+                        let light_pdf = light_pdf(scene, ray.origin(), ray.direction(), ray.time());
+                        emitted * utils::power_heuristic(pdf, light_pdf)
+                    }
+                    _ => emitted,
+                };
+
+            if depth >= self.max_depth {
+                break;
+            }
+            let (attenuation, scattered) = match rec.material.scatter(&ray, &rec, rng) {
+                Some(scatter) => scatter,
+                None => break,
+            };
+            let pdf = rec.material.pdf(&ray, &rec, &scattered);
+            let sampled_lights = !self.bsdf_only && pdf > 0.0 && light_count(scene) > 0;
+            if sampled_lights {
+                result += throughput * sample_light(&ray, &rec, scene, rng);
+            }
+            throughput *= attenuation;
+
+            if depth + 1 >= self.min_depth {
+                let survival = throughput.x().max(throughput.y()).max(throughput.z());
+                if survival < 1.0 {
+                    if utils::rand(rng) >= survival {
+                        break;
+                    }
+                    throughput /= survival;
+                }
+            }
+            bsdf_pdf = if sampled_lights { Some(pdf) } else { None };
+            ray = scattered;
+            depth += 1;
+        }
+        (result, depth)
+    }
+}
+
+impl Integrator for PathTracer {
+    fn radiance(&self, ray: Ray, scene: &Scene, rng: &mut Rng) -> Vec3 {
+        self.path(ray, scene, rng).0
+    }
+}
+
+/// The fraction of directions around each visible point, weighted by cosine,
+/// in which nothing is hit within `distance`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AmbientOcclusion {
+    pub distance: f64,
+}
+
+impl Integrator for AmbientOcclusion {
+    fn radiance(&self, ray: Ray, scene: &Scene, rng: &mut Rng) -> Vec3 {
+        let rec = match scene.world.hit(ray, 0.001, f64::MAX) {
+            Some(rec) => rec,
+            None => return Vec3::zeros(),
+        };
+        let normal = material::facing_normal(&ray, &rec);
+        // cosine weighted about the normal
+        let direction = normal + utils::random_unit_vector(rng);
+        if direction.norm2() < 1e-12 {
+            return Vec3::ones();
+        }
+        let probe = ray.spawn(rec.point, direction.unitize());
+        if scene.world.occluded(probe, 0.001, self.distance) {
+            Vec3::zeros()
+        } else {
+            Vec3::ones()
+        }
+    }
+}
+
+/// What the first surface along a ray reports about itself, for finding
+/// broken geometry and materials. Rays that hit nothing are black, and media
+/// are passed through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inspect {
+    /// The shading normal, from `[-1, 1]` to `[0, 1]` in each channel.
+    Normals,
+    /// The distance to the surface in every channel, which wants EXR output
+    /// or a lower exposure.
+    Depth,
+    /// The material's albedo.
+    Albedo,
+    /// The texture coordinates as red and green, wrapped to `[0, 1)` so that
+    /// tiling shows.
+    Uv,
+    /// Each triangle's vertex weights as red, green and blue, and magenta off
+    /// triangles.
+    Barycentrics,
+}
+
+impl Integrator for Inspect {
+    fn radiance(&self, ray: Ray, scene: &Scene, _rng: &mut Rng) -> Vec3 {
+        let rec = match scene.world.hit(ray, 0.001, f64::MAX) {
+            Some(rec) => rec,
+            None => return Vec3::zeros(),
+        };
+        match self {
+            Inspect::Normals => 0.5 * (rec.normal + 1.0),
+            Inspect::Depth => {
+                let depth = (rec.point - ray.origin()).norm();
+                vec3![depth, depth, depth]
+            }
+            Inspect::Albedo => rec.material.albedo(&rec),
+            Inspect::Uv => vec3![rec.u.rem_euclid(1.0), rec.v.rem_euclid(1.0), 0],
+            Inspect::Barycentrics => rec.barycentrics.map_or(vec3![1, 0, 1], Vec3::from),
+        }
+    }
+}
+
+/// How many times each path bounces before it ends, from blue for none
+/// through green to red for the path tracer's `max_depth`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounces(pub PathTracer);
+
+impl Integrator for Bounces {
+    fn radiance(&self, ray: Ray, scene: &Scene, rng: &mut Rng) -> Vec3 {
+        let (_, bounces) = self.0.path(ray, scene, rng);
+        let x = if self.0.max_depth > 0 {
+            bounces as f64 / self.0.max_depth as f64
+        } else {
+            0.0
+        };
+        if x < 0.5 {
+            vec3![0, 2.0 * x, 1.0 - 2.0 * x]
+        } else {
+            vec3![2.0 * x - 1.0, 2.0 - 2.0 * x, 0]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AmbientOcclusion, Bounces, Inspect, Integrator, PathTracer};
+    use crate::{
+        environment,
+        output::Image,
+        utils::{seeded_rng, Rng},
+        Bvh, ConstantMedium, DiffuseLight, DirectionalLight, HenyeyGreenstein, Isotropic,
+        Lambertian, Plane, PointLight, Ray, Scene, Sphere, SpotLight, TriangleMesh, Vec3,
+    };
+    use std::sync::Arc;
+
+    const BSDF_ONLY: PathTracer = PathTracer {
+        max_depth: 50,
+        min_depth: 3,
+        bsdf_only: true,
+    };
+
+    /// A grey floor lit by a small spherical light above it.
+    fn lit_floor() -> Scene {
+        let light = Arc::new(Sphere::new(
+            vec3![0, 2, 0],
+            0.5,
+            DiffuseLight::new(vec3![4, 4, 4]),
+        ));
+        let floor = TriangleMesh::new(
+            vec![
+                vec3![-100, 0, -100],
+                vec3![100, 0, -100],
+                vec3![100, 0, 100],
+                vec3![-100, 0, 100],
+            ],
+            vec![[0, 1, 2], [0, 2, 3]],
+            None,
+            None,
+            Arc::new(Lambertian::new(vec3![0.5, 0.5, 0.5])),
+        );
+        let mut scene = scene_of(vec![Arc::new(floor), Arc::clone(&light) as _]);
+        scene.lights = vec![light];
+        scene
+    }
+
+    fn estimate(nsamples: u32, mut sample: impl FnMut(&mut Rng) -> Vec3) -> f64 {
+        let rng = &mut seeded_rng(0);
+        (0..nsamples).map(|_| sample(rng).x()).sum::<f64>() / f64::from(nsamples)
+    }
+
+    #[test]
+    fn test_mis_matches_bsdf_sampling() {
+        let scene = lit_floor();
+        // the floor sees the whole light, so the reflected radiance is
+        // albedo * emission * sin^2(theta_max) * cos(angle to the light)
+        let radiance = |point: Vec3| {
+            let to_light = vec3![0, 2, 0] - point;
+            0.5 * 4.0 * 0.25 / to_light.norm2() * to_light.unitize().y()
+        };
+        let rays = [
+            Ray::new(vec3![0, 1, 0.3], vec3![0, -1, -0.3]),
+            Ray::new(vec3![0, 1, 0], vec3![1.5, -1, 0.5]),
+        ];
+        for ray in &rays {
+            let expected = radiance(ray.point(1.0));
+            let mis = estimate(20_000, |rng| {
+                PathTracer::default().radiance(*ray, &scene, rng)
+            });
+            let bsdf = estimate(150_000, |rng| BSDF_ONLY.radiance(*ray, &scene, rng));
+            assert!(
+                (mis - expected).abs() < 0.02 * expected,
+                "{} {}",
+                mis,
+                expected
+            );
+            assert!(
+                (bsdf - expected).abs() < 0.06 * expected,
+                "{} {}",
+                bsdf,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_samples_environment() {
+        // a floor under a dim sky with one bright patch, which light sampling
+        // should find as well as BSDF sampling does
+        let mut pixels = vec![vec3![0.2, 0.2, 0.2]; 16 * 8];
+        pixels[2 * 16 + 6] = vec3![20, 20, 20];
+        let sky = environment::Equirectangular::new(Image::new(16, 8, pixels), 0.0, 1.0);
+        let mut scene = lit_floor();
+        scene.lights.clear();
+        scene.environment = Arc::new(sky);
+
+        let ray = Ray::new(vec3![0, 1, 0.3], vec3![0, -1, -0.3]);
+        let mis = estimate(20_000, |rng| {
+            PathTracer::default().radiance(ray, &scene, rng)
+        });
+        let bsdf = estimate(100_000, |rng| BSDF_ONLY.radiance(ray, &scene, rng));
+        assert!((mis - bsdf).abs() < 0.03 * bsdf, "{} {}", mis, bsdf);
+    }
+
+    #[test]
+    fn test_punctual_lights() {
+        let mut scene = lit_floor();
+        scene.lights.clear();
+        scene.world = Bvh::new(vec![
+            // a floor, and a ball shadowing it around x = 4 from the
+            // directional light
+            Arc::new(Plane::new(
+                Vec3::zeros(),
+                vec3![0, 1, 0],
+                Lambertian::new(vec3![0.5, 0.5, 0.5]),
+            )) as Arc<dyn crate::Hittable + Send + Sync>,
+            Arc::new(Sphere::new(
+                vec3![5, 1, 0],
+                0.5,
+                Lambertian::new(Vec3::zeros()),
+            )),
+        ]);
+        scene.delta_lights = vec![
+            Arc::new(PointLight::new(vec3![0, 2, 0], vec3![8, 8, 8])),
+            Arc::new(SpotLight::new(
+                vec3![0, 2, 0],
+                vec3![0, -1, 0],
+                vec3![8, 8, 8],
+                30.0,
+                40.0,
+            )),
+            Arc::new(DirectionalLight::new(vec3![-1, -1, 0], vec3![2, 2, 2])),
+        ];
+
+        // the floor is lit by both point lights within the spot's inner cone,
+        // and the directional light everywhere but in the ball's shadow
+        let radiance = |x: f64, spot: f64, directional: f64| {
+            let cosine = 2.0 / (4.0 + x * x).sqrt();
+            0.5 / std::f64::consts::PI
+                * ((1.0 + spot) * 8.0 * cosine / (4.0 + x * x) + directional * 2.0 * 0.5_f64.sqrt())
+        };
+        for &(x, spot, directional) in &[(0.5, 1.0, 1.0), (3.0, 0.0, 1.0), (4.0, 0.0, 0.0)] {
+            let ray = Ray::new(vec3![x, 1, 1], vec3![0, -1, -1]);
+            let expected = radiance(x, spot, directional);
+            let mis = estimate(20_000, |rng| {
+                PathTracer::default().radiance(ray, &scene, rng)
+            });
+            assert!(
+                (mis - expected).abs() < 0.03 * expected,
+                "{} {} {}",
+                x,
+                mis,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_russian_roulette() {
+        // inside a ball lit by a point light at its centre, light reflects
+        // off the walls over and over, so that the walls' radiance is
+        // albedo * intensity / (pi * radius^2 * (1 - albedo))
+        let albedo = 0.8;
+        let mut scene = scene_of(vec![Arc::new(Sphere::new(
+            Vec3::zeros(),
+            2.0,
+            Lambertian::new(vec3![albedo, albedo, albedo]),
+        ))]);
+        scene.delta_lights = vec![Arc::new(PointLight::new(Vec3::zeros(), vec3![4, 4, 4]))];
+        let direct = albedo * 4.0 / (std::f64::consts::PI * 4.0);
+        let ray = Ray::new(Vec3::zeros(), vec3![0.3, -1, 0.2]);
+        let radiance =
+            |tracer: PathTracer| estimate(20_000, |rng| tracer.radiance(ray, &scene, rng));
+
+        let single = radiance(PathTracer {
+            max_depth: 1,
+            ..PathTracer::default()
+        });
+        assert!((single - direct).abs() < 1e-9, "{}", single);
+        let expected = direct / (1.0 - albedo);
+        for &min_depth in &[0, 3] {
+            let all = radiance(PathTracer {
+                max_depth: 1000,
+                min_depth,
+                bsdf_only: false,
+            });
+            assert!(
+                (all - expected).abs() < 0.02 * expected,
+                "{} {}",
+                all,
+                expected
+            );
+        }
+        // where stopping after a few bounces loses the rest
+        let truncated = radiance(PathTracer {
+            max_depth: 3,
+            ..PathTracer::default()
+        });
+        let three_bounces = direct * (1.0 + albedo + albedo * albedo);
+        assert!((truncated - three_bounces).abs() < 0.02 * three_bounces);
+    }
+
+    /// A unit ball of medium, with a density of 0.7, in a white environment.
+    fn smoke_ball(phase: impl crate::Material + Send + Sync + 'static) -> Scene {
+        let smoke = ConstantMedium::new(
+            Sphere::new(Vec3::zeros(), 1.0, Lambertian::new(Vec3::ones())),
+            0.7,
+            phase,
+        );
+        let mut scene = scene_of(vec![Arc::new(smoke)]);
+        scene.environment = Arc::new(environment::Constant::new(Vec3::ones()));
+        scene
+    }
+
+    #[test]
+    fn test_media() {
+        let rays = [
+            (Ray::new(vec3![0, 0, -5], vec3![0, 0, 2]), 2.0),
+            (Ray::new(vec3![0, 0.6, -5], vec3![0, 0, 1]), 1.6),
+            // starting inside
+            (Ray::new(Vec3::zeros(), vec3![1, 0, 0]), 1.0),
+        ];
+        // black smoke only absorbs, so it lets through exp(-density * length)
+        let black = smoke_ball(Isotropic::new(Vec3::zeros()));
+        // while white smoke loses nothing, scattering as much in as out
+        let white = smoke_ball(HenyeyGreenstein::new(Vec3::ones(), 0.5));
+        for &(ray, length) in &rays {
+            for (scene, expected) in &[(&black, (-0.7_f64 * length).exp()), (&white, 1.0)] {
+                let mis = estimate(20_000, |rng| {
+                    PathTracer::default().radiance(ray, scene, rng)
+                });
+                let bsdf = estimate(20_000, |rng| BSDF_ONLY.radiance(ray, scene, rng));
+                assert!((mis - expected).abs() < 0.02, "{} {}", mis, expected);
+                assert!((bsdf - expected).abs() < 0.02, "{} {}", bsdf, expected);
+            }
+        }
+    }
+
+    /// `shapes` under a black sky.
+    fn scene_of(shapes: Vec<Arc<dyn crate::Hittable + Send + Sync>>) -> Scene {
+        Scene {
+            world: Bvh::new(shapes),
+            camera: None,
+            render: Default::default(),
+            environment: Arc::new(environment::Constant::new(Vec3::zeros())),
+            lights: vec![],
+            delta_lights: vec![],
+        }
+    }
+
+    #[test]
+    fn test_inspect() {
+        let triangle = TriangleMesh::new(
+            vec![vec3![0, 0, 0], vec3![4, 0, 0], vec3![0, 4, 0]],
+            vec![[0, 1, 2]],
+            None,
+            Some(vec![[0.0, 0.0], [3.0, 0.0], [0.0, 1.0]]),
+            Arc::new(Lambertian::new(vec3![0.2, 0.4, 0.6])),
+        );
+        let sphere = Sphere::new(vec3![10, 0, 0], 1.0, Lambertian::new(Vec3::ones()));
+        let scene = scene_of(vec![Arc::new(triangle), Arc::new(sphere)]);
+        let rng = &mut seeded_rng(0);
+        let ray = Ray::new(vec3![1, 2, 5], vec3![0, 0, -1]);
+        let mut look = |inspect: Inspect, ray| inspect.radiance(ray, &scene, rng);
+
+        assert_eq!(look(Inspect::Normals, ray), vec3![0.5, 0.5, 1]);
+        assert_eq!(look(Inspect::Depth, ray), vec3![5, 5, 5]);
+        assert_eq!(look(Inspect::Albedo, ray), vec3![0.2, 0.4, 0.6]);
+        // u runs to 3 along the triangle's first side, so wraps once
+        let uv = look(Inspect::Uv, ray);
+        assert!((uv - vec3![0.75, 0.5, 0]).norm() < 1e-12, "{:?}", uv);
+        let barycentrics = look(Inspect::Barycentrics, ray);
+        assert!((barycentrics - vec3![0.25, 0.25, 0.5]).norm() < 1e-12);
+
+        let sphere_ray = Ray::new(vec3![10, 0, 5], vec3![0, 0, -1]);
+        assert_eq!(look(Inspect::Barycentrics, sphere_ray), vec3![1, 0, 1]);
+        let miss = Ray::new(vec3![-5, 0, 5], vec3![0, 0, -1]);
+        assert_eq!(look(Inspect::Normals, miss), Vec3::zeros());
+    }
+
+    #[test]
+    fn test_ambient_occlusion() {
+        // under a ceiling half a unit up, directions within 60 degrees of
+        // straight up hit it within a unit, which is 3/4 of them by cosine
+        let scene = scene_of(vec![
+            Arc::new(Plane::new(
+                Vec3::zeros(),
+                vec3![0, 1, 0],
+                Lambertian::new(Vec3::ones()),
+            )),
+            Arc::new(Plane::new(
+                vec3![0, 0.5, 0],
+                vec3![0, -1, 0],
+                Lambertian::new(Vec3::ones()),
+            )),
+        ]);
+        let ray = Ray::new(vec3![0, 0.4, 0], vec3![0.3, -1, 0]);
+        let near = AmbientOcclusion { distance: 1.0 };
+        let open = estimate(20_000, |rng| near.radiance(ray, &scene, rng));
+        assert!((open - 0.25).abs() < 0.01, "{}", open);
+        let far = AmbientOcclusion { distance: 0.4 };
+        assert_eq!(estimate(1_000, |rng| far.radiance(ray, &scene, rng)), 1.0);
+    }
+
+    #[test]
+    fn test_bounces() {
+        let scene = scene_of(vec![Arc::new(Sphere::new(
+            Vec3::zeros(),
+            1.0,
+            Lambertian::new(Vec3::ones()),
+        ))]);
+        let tracer = PathTracer {
+            max_depth: 2,
+            min_depth: 2,
+            bsdf_only: false,
+        };
+        let rng = &mut seeded_rng(0);
+        let bounces = |origin: Vec3, direction: Vec3, rng: &mut Rng| {
+            Bounces(tracer).radiance(Ray::new(origin, direction), &scene, rng)
+        };
+        // paths that miss don't bounce, paths off the outside of a convex
+        // ball bounce once, and paths inside a white ball bounce until
+        // they're stopped
+        assert_eq!(bounces(vec3![0, 2, 2], vec3![0, 0, 1], rng), vec3![0, 0, 1]);
+        assert_eq!(
+            bounces(vec3![0, 0, 2], vec3![0, 0, -1], rng),
+            vec3![0, 1, 0]
+        );
+        assert_eq!(bounces(Vec3::zeros(), vec3![0, 0, 1], rng), vec3![1, 0, 0]);
+    }
+}
@@ -5,14 +5,68 @@ macro_rules! vec3 {
     }
 }
 
+mod aabb;
+pub use aabb::Aabb;
+
+mod bvh;
+pub use bvh::Bvh;
+
 mod camera;
 pub use camera::Camera;
 
 mod material;
-pub use material::{Dielectric, Lambertian, Material, Metal};
+pub use material::{Dielectric, DiffuseLight, Dispersion, Lambertian, Material, Metal};
+
+mod microfacet;
+pub use microfacet::{Conductor, Element, Ggx, RoughDielectric};
+
+mod principled;
+pub use principled::{Principled, PrincipledParameters};
+
+mod mesh;
+pub use mesh::{Triangle, TriangleMesh};
+
+pub mod obj;
+
+pub mod output;
+
+pub mod scene;
+pub use scene::Scene;
+
+pub mod tonemap;
+
+pub mod noise;
+
+mod texture;
+pub use texture::{Checker, Cloud, Gradient, ImageTexture, Marble, Texture, Wood, Wrap};
 
 mod shape;
-pub use shape::{Hittable, HittableList, Sphere};
+pub use shape::{Hittable, HittableList, MovingSphere, Sphere};
+
+mod planar;
+pub use planar::{BoxShape, Disk, Plane, Quad};
+
+pub mod transform;
+pub use transform::Instance;
+
+mod medium;
+pub use medium::{
+    Coefficients, ConstantMedium, GridMedium, HenyeyGreenstein, Isotropic, Medium, MediumEvent,
+    TransferFunction,
+};
+
+pub mod voxel;
+
+pub mod environment;
+pub use environment::Environment;
+
+pub mod sky;
+
+pub mod integrator;
+pub use integrator::{Integrator, PathTracer};
+
+mod light;
+pub use light::{DirectionalLight, Illumination, Light, PointLight, SpotLight};
 
 mod colorvec3;
 pub use colorvec3::ColorVec3;
@@ -24,31 +78,163 @@ pub struct HitRecord<'mat> {
     pub t: f64,
     pub point: crate::Vec3,
     pub normal: crate::Vec3,
+    pub u: f64,
+    pub v: f64,
+    /// The weights of a triangle's three vertices at `point`, or `None` off
+    /// triangles.
+    pub barycentrics: Option<[f64; 3]>,
     pub material: &'mat dyn crate::Material,
 }
 
+/// The stretch of a ray that lies within a participating medium.
+pub struct MediumHit<'a> {
+    /// Where the ray enters the medium, or starts within it.
+    pub t_enter: f64,
+    pub t_exit: f64,
+    /// The ray in the medium's own space, which distances along the ray carry
+    /// over to, and in which densities are measured.
+    pub ray: crate::Ray,
+    pub medium: &'a dyn crate::Medium,
+}
+
 pub mod utils {
-    pub fn rand() -> f64 {
-        rand::random()
+    use crate::Vec3;
+    use rand::{Rng as _, SeedableRng};
+
+    /// The random number generator threaded through rendering, which
+    /// produces the same stream on every platform.
+    pub type Rng = rand_pcg::Pcg64Mcg;
+
+    /// SplitMix64's finalizer, which scrambles nearby inputs into unrelated
+    /// outputs.
+    fn mix(mut z: u64) -> u64 {
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A generator for anything that isn't a pixel sample, e.g. building a
+    /// scene.
+    pub fn seeded_rng(seed: u64) -> Rng {
+        Rng::seed_from_u64(mix(seed))
+    }
+
+    /// The generator for one sample of one pixel, so that the image doesn't
+    /// depend on the order in which samples are rendered.
+    pub fn sample_rng(seed: u64, pixel: u64, sample: u64) -> Rng {
+        let hash = mix(mix(mix(seed) ^ pixel) ^ sample);
+        Rng::new((u128::from(hash) << 64) | u128::from(mix(hash)))
+    }
+
+    pub fn rand(rng: &mut Rng) -> f64 {
+        rng.gen()
+    }
+
+    pub fn randvec(rng: &mut Rng) -> crate::Vec3 {
+        [rand(rng), rand(rng), rand(rng)].into()
+    }
+
+    /// A uniformly distributed direction.
+    pub fn random_unit_vector(rng: &mut Rng) -> Vec3 {
+        let z = 1.0 - 2.0 * rand(rng);
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * std::f64::consts::PI * rand(rng);
+        vec3![r * phi.cos(), r * phi.sin(), z]
     }
 
-    pub fn randvec() -> crate::Vec3 {
-        [rand(), rand(), rand()].into()
+    /// Two unit vectors that form a right handed orthonormal basis with the
+    /// unit vector `n`.
+    pub fn orthonormal_basis(n: Vec3) -> (Vec3, Vec3) {
+        // Duff et al., "Building an Orthonormal Basis, Revisited"
+        let sign = 1.0f64.copysign(n.z());
+        let a = -1.0 / (sign + n.z());
+        let b = n.x() * n.y() * a;
+        (
+            vec3![1.0 + sign * n.x() * n.x() * a, sign * b, -sign * n.x()],
+            vec3![b, sign + n.y() * n.y() * a, -n.y()],
+        )
+    }
+
+    /// The power heuristic with an exponent of two, weighting a sample drawn
+    /// with density `pdf` against another strategy's `other_pdf`.
+    pub fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+        let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+        if a + b > 0.0 {
+            a / (a + b)
+        } else {
+            0.0
+        }
+    }
+
+    #[test]
+    fn test_sample_rng() {
+        let first = rand(&mut sample_rng(1, 2, 3));
+        assert_eq!(first, rand(&mut sample_rng(1, 2, 3)));
+        assert_ne!(first, rand(&mut sample_rng(1, 2, 4)));
+        assert_ne!(first, rand(&mut sample_rng(1, 3, 3)));
+        assert_ne!(first, rand(&mut sample_rng(2, 2, 3)));
+    }
+
+    #[test]
+    fn test_orthonormal_basis() {
+        for n in &[vec3![0, 0, 1], vec3![0, 0, -1], vec3![1, 2, 3].unitize()] {
+            let (s, t) = orthonormal_basis(*n);
+            assert!(s.dot(t).abs() < 1e-12);
+            assert!(s.dot(*n).abs() < 1e-12);
+            assert!((s.norm() - 1.0).abs() < 1e-12);
+            assert!((s.cross(t) - *n).norm() < 1e-12);
+        }
     }
 }
 
 mod ray {
     use crate::Vec3;
 
+    /// A ray, at an instant within the frame, and possibly of a single
+    /// wavelength.
+    ///
+    /// Times run from 0 to 1 over the frame: moving objects are where they
+    /// start at time 0 and where they end at time 1. Rays carry all
+    /// wavelengths until something that disperses light picks one for them.
     #[derive(Debug, Copy, Clone)]
     pub struct Ray {
         origin: Vec3,
         direction: Vec3,
+        time: f64,
+        wavelength: Option<f64>,
     }
 
     impl Ray {
+        /// A ray at time 0.
         pub fn new(origin: Vec3, direction: Vec3) -> Self {
-            Self { origin, direction }
+            Self::with_time(origin, direction, 0.0)
+        }
+
+        pub fn with_time(origin: Vec3, direction: Vec3, time: f64) -> Self {
+            Self {
+                origin,
+                direction,
+                time,
+                wavelength: None,
+            }
+        }
+
+        /// A ray from `origin` along `direction` that continues this one's
+        /// path, at the same time and wavelength.
+        pub fn spawn(&self, origin: Vec3, direction: Vec3) -> Self {
+            Self {
+                origin,
+                direction,
+                ..*self
+            }
+        }
+
+        /// This ray, but of a single wavelength in nanometres.
+        pub fn with_wavelength(self, wavelength: f64) -> Self {
+            Self {
+                wavelength: Some(wavelength),
+                ..self
+            }
         }
 
         pub fn origin(&self) -> Vec3 {
@@ -59,6 +245,14 @@ mod ray {
             self.direction
         }
 
+        pub fn time(&self) -> f64 {
+            self.time
+        }
+
+        pub fn wavelength(&self) -> Option<f64> {
+            self.wavelength
+        }
+
         pub fn point(&self, t: f64) -> Vec3 {
             self.origin() + t * self.direction()
         }
@@ -0,0 +1,202 @@
+//! Punctual lights, infinitely small or infinitely far away, which no ray
+//! can hit and which are only found by sampling them with shadow rays.
+
+use crate::Vec3;
+
+/// The light arriving at a point from a punctual light.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Illumination {
+    /// The unit vector from the point towards the light.
+    pub direction: Vec3,
+    /// How far away the light is, or infinity.
+    pub distance: f64,
+    /// The irradiance on a surface facing the light.
+    pub irradiance: Vec3,
+}
+
+pub trait Light {
+    /// The light arriving at `point` if it were unoccluded, or `None` if no
+    /// light arrives there.
+    fn illuminate(&self, point: Vec3) -> Option<Illumination>;
+}
+
+/// Light shining equally in every direction from a single point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PointLight {
+    position: Vec3,
+    intensity: Vec3,
+}
+
+impl PointLight {
+    /// `intensity` is the power per solid angle, the irradiance a unit
+    /// distance away.
+    pub fn new(position: Vec3, intensity: Vec3) -> Self {
+        Self {
+            position,
+            intensity,
+        }
+    }
+}
+
+/// The light from `position` with `intensity` arriving at `point`.
+fn illuminate_from(position: Vec3, intensity: Vec3, point: Vec3) -> Option<Illumination> {
+    let to_light = position - point;
+    let distance2 = to_light.norm2();
+    if distance2 == 0.0 {
+        return None;
+    }
+    let distance = distance2.sqrt();
+    Some(Illumination {
+        direction: to_light / distance,
+        distance,
+        irradiance: intensity / distance2,
+    })
+}
+
+impl Light for PointLight {
+    fn illuminate(&self, point: Vec3) -> Option<Illumination> {
+        illuminate_from(self.position, self.intensity, point)
+    }
+}
+
+/// A point light that only shines within a cone, at full intensity within
+/// `inner_angle` of its axis and fading smoothly to nothing at
+/// `outer_angle`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpotLight {
+    position: Vec3,
+    direction: Vec3,
+    intensity: Vec3,
+    cos_inner: f64,
+    cos_outer: f64,
+}
+
+impl SpotLight {
+    /// The angles are in degrees from the axis, and `direction` needn't be
+    /// a unit vector. Panics unless `0 <= inner_angle <= outer_angle <=
+    /// 180`.
+    pub fn new(
+        position: Vec3,
+        direction: Vec3,
+        intensity: Vec3,
+        inner_angle: f64,
+        outer_angle: f64,
+    ) -> Self {
+        assert!(
+            0.0 <= inner_angle && inner_angle <= outer_angle && outer_angle <= 180.0,
+            "spot lights need 0 <= inner angle <= outer angle <= 180 degrees"
+        );
+        Self {
+            position,
+            direction: direction.unitize(),
+            intensity,
+            cos_inner: inner_angle.to_radians().cos(),
+            cos_outer: outer_angle.to_radians().cos(),
+        }
+    }
+
+    /// The fraction of the intensity shone at `cos_theta` from the axis.
+    fn falloff(&self, cos_theta: f64) -> f64 {
+        if cos_theta >= self.cos_inner {
+            1.0
+        } else if cos_theta <= self.cos_outer {
+            0.0
+        } else {
+            let x = (cos_theta - self.cos_outer) / (self.cos_inner - self.cos_outer);
+            x * x * (3.0 - 2.0 * x)
+        }
+    }
+}
+
+impl Light for SpotLight {
+    fn illuminate(&self, point: Vec3) -> Option<Illumination> {
+        let illumination = illuminate_from(self.position, self.intensity, point)?;
+        let falloff = self.falloff(-illumination.direction.dot(self.direction));
+        if falloff <= 0.0 {
+            return None;
+        }
+        Some(Illumination {
+            irradiance: falloff * illumination.irradiance,
+            ..illumination
+        })
+    }
+}
+
+/// Parallel light from infinitely far away, such as sunlight.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DirectionalLight {
+    direction: Vec3,
+    irradiance: Vec3,
+}
+
+impl DirectionalLight {
+    /// Light travelling along `direction`, which needn't be a unit vector,
+    /// with `irradiance` on surfaces facing it.
+    pub fn new(direction: Vec3, irradiance: Vec3) -> Self {
+        Self {
+            direction: direction.unitize(),
+            irradiance,
+        }
+    }
+}
+
+impl Light for DirectionalLight {
+    fn illuminate(&self, _point: Vec3) -> Option<Illumination> {
+        Some(Illumination {
+            direction: -self.direction,
+            distance: f64::INFINITY,
+            irradiance: self.irradiance,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DirectionalLight, Light, PointLight, SpotLight};
+    use crate::Vec3;
+
+    #[test]
+    fn test_point_light() {
+        let light = PointLight::new(vec3![0, 4, 0], vec3![8, 8, 8]);
+        let illumination = light.illuminate(vec3![0, 0, 3]).unwrap();
+        assert_eq!(illumination.direction, vec3![0, 0.8, -0.6]);
+        assert_eq!(illumination.distance, 5.0);
+        assert_eq!(illumination.irradiance, vec3![0.32, 0.32, 0.32]);
+        assert!(light.illuminate(vec3![0, 4, 0]).is_none());
+    }
+
+    #[test]
+    fn test_spot_light() {
+        let light = SpotLight::new(vec3![0, 2, 0], vec3![0, -3, 0], Vec3::ones(), 30.0, 60.0);
+        let irradiance = |x: f64| {
+            light
+                .illuminate(vec3![x, 0, 0])
+                .map_or(0.0, |illumination| illumination.irradiance.x())
+        };
+        // full intensity within the inner cone, nothing outside the outer one
+        assert_eq!(irradiance(0.0), 0.25);
+        assert_eq!(irradiance(1.0), 0.2);
+        assert_eq!(irradiance(4.0), 0.0);
+        // and a smooth fade in between
+        let (mut previous, mut x) = (irradiance(1.0), 1.0);
+        while x < 3.5 {
+            x += 0.1;
+            let current = irradiance(x);
+            assert!(current <= previous);
+            previous = current;
+        }
+        // a hard edge when the angles coincide
+        let hard = SpotLight::new(Vec3::zeros(), vec3![0, 0, 1], Vec3::ones(), 45.0, 45.0);
+        assert!(hard.illuminate(vec3![0.9, 0, 1]).is_some());
+        assert!(hard.illuminate(vec3![1.1, 0, 1]).is_none());
+    }
+
+    #[test]
+    fn test_directional_light() {
+        let light = DirectionalLight::new(vec3![0, -2, 0], vec3![3, 2, 1]);
+        let illumination = light.illuminate(vec3![100, 5, -7]).unwrap();
+        assert_eq!(illumination.direction, vec3![0, 1, 0]);
+        assert_eq!(illumination.distance, f64::INFINITY);
+        assert_eq!(illumination.irradiance, vec3![3, 2, 1]);
+    }
+}
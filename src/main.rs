@@ -1,72 +1,151 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Result};
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
 use raytracer::{
-    utils::{rand, randvec},
-    vec3, Camera, ColorVec3, Dielectric, Hittable, HittableList, Lambertian, Metal, Ray, Sphere,
-    Vec3,
-};
-use std::{
-    fs::File,
-    io::Write,
-    ops::{Add, Div},
+    integrator::{AmbientOcclusion, Bounces, Inspect},
+    output::{Format, FrameBuffer, Pixel},
+    scene::{self, CameraSettings},
+    sky::{self, Sky},
+    tonemap::{Dither, Operator, ToneMap, Transfer},
+    utils::{self, rand},
+    Camera, Integrator, PathTracer, Scene, Vec3,
 };
+use std::{convert::TryFrom, str::FromStr, sync::Arc};
 use structopt::StructOpt;
 
-fn random_scene(ball_density: i32) -> impl Hittable {
-    HittableList::new(
-        vec![
-            Sphere::new(
-                vec3![0, -1000, 0],
-                1000.0,
-                Lambertian::new(vec3![0.5, 0.5, 0.5]),
-            ),
-            Sphere::new(vec3![-4, 1, 0], 1.0, Lambertian::new(vec3![0.4, 0.2, 0.1])),
-            Sphere::new(vec3![0, 1, 0], 1.0, Dielectric::new(1.5)),
-            Sphere::new(vec3![4, 1, 0], 1.0, Metal::new(vec3![0.7, 0.6, 0.5], 0.0)),
-        ]
-        .into_iter()
-        .chain(
-            itertools::iproduct!(-ball_density..ball_density, -ball_density..ball_density)
-                .filter_map(|(a, b)| {
-                    let center = vec3![a as f64 + 0.9 * rand(), 0.2, b as f64 + 0.9 * rand()];
-                    if (center - vec3![4, 0.2, 0]).norm() <= 0.9 {
-                        return None;
-                    }
-
-                    Some(match rand() {
-                        chosen if chosen < 0.8 => {
-                            Sphere::new(center, 0.2, Lambertian::new(randvec() * randvec()))
-                        }
-                        chosen if chosen < 0.95 => Sphere::new(
-                            center,
-                            0.2,
-                            Metal::new((randvec() + 1.0) * 0.5, 0.5 * rand()),
-                        ),
-                        _ => Sphere::new(center, 0.2, Dielectric::new(1.5)),
-                    })
-                }),
-        )
-        .collect::<Vec<_>>(),
-    )
+/// Settings shared by every pixel of a render.
+struct Render<'a> {
+    scene: &'a Scene,
+    camera: &'a Camera,
+    width: u16,
+    height: u16,
+    nsamples: u32,
+    seed: u64,
+    integrator: &'a (dyn Integrator + Sync),
+    /// Whether to fill in the auxiliary values of each pixel, which costs an
+    /// extra intersection per sample.
+    aovs: bool,
 }
 
-fn color(ray: Ray, world: &impl Hittable, depth: usize) -> Vec3 {
-    if let Some(rec) = world.hit(ray, 0.001, f64::MAX) {
-        if let Some((attenuation, scattered)) = rec.material.scatter(&ray, &rec) {
-            if depth < 50 {
-                attenuation * color(scattered, world, depth + 1)
-            } else {
-                Vec3::zeros()
+impl Render<'_> {
+    /// The average of every sample of the pixel at `(x, y)`, counting rows
+    /// from the top, leaving out any sample whose radiance isn't finite.
+    ///
+    /// Each sample draws from its own generator, so the result doesn't depend
+    /// on which thread renders the pixel or in what order.
+    fn pixel(&self, x: u16, y: u16) -> Pixel {
+        let (width, height) = (f64::from(self.width), f64::from(self.height));
+        let index = u64::from(y) * u64::from(self.width) + u64::from(x);
+        let fy = f64::from(self.height - y);
+        let mut pixel = Pixel {
+            color: Vec3::zeros(),
+            albedo: Vec3::zeros(),
+            normal: Vec3::zeros(),
+            depth: f64::INFINITY,
+            object_id: 0,
+            samples: 0,
+        };
+        for sample in 0..self.nsamples {
+            let rng = &mut utils::sample_rng(self.seed, index, u64::from(sample));
+            let u = (f64::from(x) + rand(rng)) / width;
+            let v = (fy + rand(rng)) / height;
+            let ray = self.camera.ray(u, v, rng);
+            if self.aovs {
+                if let Some((id, rec)) = self.scene.world.hit_index(ray, 0.001, f64::MAX) {
+                    pixel.albedo += rec.material.albedo(&rec);
+                    pixel.normal += rec.normal;
+                    // depth and ID can't be meaningfully averaged, so keep the
+                    // nearest hit's
+                    let depth = (rec.point - ray.origin()).norm();
+                    if depth < pixel.depth {
+                        pixel.depth = depth;
+                        // IDs past `u32::MAX` all share the last one
+                        pixel.object_id = u32::try_from(id + 1).unwrap_or(u32::MAX);
+                    }
+                }
+            }
+            let radiance = self.integrator.radiance(ray, self.scene, rng);
+            if radiance.into_array().iter().all(|value| value.is_finite()) {
+                pixel.color += radiance;
+                pixel.samples += 1;
             }
-        } else {
-            Vec3::zeros()
         }
-    } else {
-        Vec3::ones().lerp(
-            vec3![0.5, 0.7, 1.0],
-            0.5 * (ray.direction().unitize().y() + 1.0),
-        )
+        if pixel.samples > 0 {
+            pixel.color /= f64::from(pixel.samples);
+        }
+        let nsamples = f64::from(self.nsamples);
+        pixel.albedo /= nsamples;
+        pixel.normal /= nsamples;
+        pixel
+    }
+
+    /// Every pixel in row-major order, rendered in parallel.
+    fn pixels(&self, pb: &ProgressBar) -> Vec<Pixel> {
+        (0..self.height)
+            .into_par_iter()
+            .flat_map_iter(|y| {
+                (0..self.width).map(move |x| {
+                    let col = self.pixel(x, y);
+                    pb.inc(u64::from(self.nsamples));
+                    col
+                })
+            })
+            .collect()
+    }
+}
+
+/// Prefer the command line value if it was given explicitly, then the scene's.
+fn choose<T>(given: bool, cli: T, scene: Option<T>) -> T {
+    match scene {
+        Some(value) if !given => value,
+        _ => cli,
+    }
+}
+
+fn vec3_array(values: Vec<f64>) -> Result<[f64; 3]> {
+    match values[..] {
+        [x, y, z] => Ok([x, y, z]),
+        _ => anyhow::bail!("Expected 3 comma separated values, got {}", values.len()),
+    }
+}
+
+/// What `--integrator` renders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Choice {
+    Path,
+    AmbientOcclusion,
+    Inspect(Inspect),
+    Bounces,
+}
+
+impl Choice {
+    const NAMES: &'static [&'static str] = &[
+        "path",
+        "ao",
+        "normals",
+        "depth",
+        "albedo",
+        "uv",
+        "barycentrics",
+        "bounces",
+    ];
+}
+
+impl FromStr for Choice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "path" => Choice::Path,
+            "ao" => Choice::AmbientOcclusion,
+            "normals" => Choice::Inspect(Inspect::Normals),
+            "depth" => Choice::Inspect(Inspect::Depth),
+            "albedo" => Choice::Inspect(Inspect::Albedo),
+            "uv" => Choice::Inspect(Inspect::Uv),
+            "barycentrics" => Choice::Inspect(Inspect::Barycentrics),
+            "bounces" => Choice::Bounces,
+            _ => anyhow::bail!("Unknown integrator `{}`", s),
+        })
     }
 }
 
@@ -86,12 +165,118 @@ struct Opt {
     #[structopt(short, long, default_value = "100", help = "Number of samples")]
     nsamples: u32,
 
-    #[structopt(short, long, default_value = "2.0", help = "Gamma")]
-    gamma: f64,
+    #[structopt(
+        short,
+        long,
+        help = "Encode 8-bit output with this gamma instead of the sRGB curve"
+    )]
+    gamma: Option<f64>,
+
+    #[structopt(
+        short,
+        long,
+        default_value = "0",
+        allow_hyphen_values = true,
+        help = "Exposure in stops for 8-bit output"
+    )]
+    exposure: f64,
+
+    #[structopt(
+        long,
+        default_value = "clamp",
+        possible_values = Operator::NAMES,
+        help = "Tone mapping operator for 8-bit output"
+    )]
+    tonemap: Operator,
+
+    #[structopt(
+        long,
+        help = "Luminance that extended Reinhard maps to white, defaulting to the brightest pixel"
+    )]
+    white: Option<f64>,
+
+    #[structopt(
+        long,
+        default_value = "none",
+        possible_values = Dither::NAMES,
+        help = "Dithering applied before quantizing to 8 bits"
+    )]
+    dither: Dither,
 
     #[structopt(short, long, default_value = "11", help = "Density of balls")]
     ball_density: u16,
 
+    #[structopt(
+        long,
+        help = "Scene description file, rendering the built-in scene if not given"
+    )]
+    scene: Option<std::path::PathBuf>,
+
+    #[structopt(
+        long,
+        default_value = "random",
+        possible_values = &["random", "cornell"],
+        help = "Built-in scene to render"
+    )]
+    builtin: String,
+
+    #[structopt(
+        long,
+        help = "Only sample BSDFs, without explicit light sampling, which leaves punctual lights dark"
+    )]
+    bsdf_only: bool,
+
+    #[structopt(
+        long,
+        value_delimiter = ",",
+        allow_hyphen_values = true,
+        help = "Light the scene with the sun and sky at latitude,longitude,day of the year,hour \
+                and optionally the time zone's hours ahead of UTC, e.g. 51.5,-0.1,172,9.5,1"
+    )]
+    sky: Option<Vec<f64>>,
+
+    #[structopt(
+        long,
+        default_value = "path",
+        possible_values = Choice::NAMES,
+        help = "What to render: the path traced image, or ambient occlusion, shading normals, \
+                distance, albedo, texture coordinates, triangle barycentrics or path lengths \
+                to debug the scene"
+    )]
+    integrator: Choice,
+
+    #[structopt(
+        long,
+        default_value = "1",
+        help = "How far away geometry occludes for --integrator ao, in scene units"
+    )]
+    ao_distance: f64,
+
+    #[structopt(long, default_value = "50", help = "Most bounces a path takes")]
+    max_depth: usize,
+
+    #[structopt(
+        long,
+        default_value = "3",
+        help = "Bounces before Russian roulette can end a path"
+    )]
+    min_depth: usize,
+
+    #[structopt(
+        long,
+        default_value = "3",
+        help = "Haziness of the --sky, from 1.7 for clear air to 10 for haze"
+    )]
+    turbidity: f64,
+
+    #[structopt(
+        long,
+        default_value = "0.3,0.3,0.3",
+        value_delimiter = ",",
+        help = "Albedo of the ground below the --sky's horizon"
+    )]
+    ground_albedo: Vec<f64>,
+
     #[structopt(
         short,
         long,
@@ -102,7 +287,6 @@ struct Opt {
     look_from: Vec<f64>,
 
     #[structopt(
-        short,
         long,
         default_value = "0,0,0",
         value_delimiter = ",",
@@ -116,34 +300,143 @@ struct Opt {
     #[structopt(required = true, help = "Output filename")]
     filename: std::path::PathBuf,
 
+    #[structopt(
+        long,
+        possible_values = Format::NAMES,
+        help = "Output image format, inferred from the filename if not given"
+    )]
+    format: Option<Format>,
+
+    #[structopt(
+        long,
+        help = "Leave out the albedo, normal, depth, object ID and sample count EXR layers"
+    )]
+    no_aovs: bool,
+
     #[structopt(short, long, default_value = "10.0", help = "Distance to focus")]
     dist_to_focus: f64,
+
+    #[structopt(
+        long,
+        default_value = "0,0",
+        value_delimiter = ",",
+        help = "When the shutter opens and closes, from 0 at the start of the frame to 1 at its end"
+    )]
+    shutter: Vec<f64>,
+
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Random seed; the same seed always renders the same image"
+    )]
+    seed: u64,
 }
 
 fn main() -> Result<()> {
+    let matches = Opt::clap().get_matches();
+    // structopt names arguments after their long flags, e.g. `image-dims`
+    let given = |name| matches.occurrences_of(name) > 0;
     let Opt {
         image_dims,
         nsamples,
         gamma,
+        exposure,
+        tonemap,
+        white,
+        dither,
         ball_density,
+        scene,
+        builtin,
+        bsdf_only,
+        sky,
+        turbidity,
+        ground_albedo,
+        integrator,
+        ao_distance,
+        max_depth,
+        min_depth,
         look_from,
         look_at,
         aperture,
         filename,
+        format,
+        no_aovs,
         dist_to_focus,
-    } = Opt::from_args();
-    let (width, height) = (image_dims[0], image_dims[1]);
-
-    let camera = Camera::new(
-        look_from.into(),
-        look_at.into(),
-        vec3![0, 1, 0],
-        20.0,
-        f64::from(width) / f64::from(height),
+        shutter,
+        seed,
+    } = Opt::from_clap(&matches);
+    let format = format
+        .or_else(|| Format::from_path(&filename))
+        .ok_or_else(|| {
+            anyhow!(
+                "Unable to determine the image format of {}, expected one of: {}",
+                filename.display(),
+                Format::NAMES.join(", ")
+            )
+        })?;
+    let mut scene = match scene {
+        Some(path) => Scene::load(path, seed)?,
+        None if builtin == "cornell" => scene::cornell_box(),
+        None => scene::random_scene(i32::from(ball_density), &mut utils::seeded_rng(seed)),
+    };
+    if let Some(location) = sky {
+        let sun = match location[..] {
+            [latitude, longitude, day, hour] => {
+                sky::solar_direction(latitude, longitude, day, hour, 0.0)
+            }
+            [latitude, longitude, day, hour, utc_offset] => {
+                sky::solar_direction(latitude, longitude, day, hour, utc_offset)
+            }
+            _ => anyhow::bail!("Expected --sky to be latitude,longitude,day,hour[,utc_offset]"),
+        };
+        if !(1.7..=10.0).contains(&turbidity) {
+            anyhow::bail!("Expected a --turbidity from 1.7 to 10, got {}", turbidity);
+        }
+        let ground_albedo = vec3_array(ground_albedo)?;
+        scene.environment = Arc::new(Sky::new(sun, turbidity, ground_albedo.into()));
+    }
+    let render = &scene.render;
+    let width = choose(given("image-dims"), image_dims[0], render.width);
+    let height = choose(given("image-dims"), image_dims[1], render.height);
+    let nsamples = choose(given("nsamples"), nsamples, render.samples);
+    let tonemap = ToneMap {
+        exposure: choose(given("exposure"), exposure, render.exposure),
+        operator: choose(given("tonemap"), tonemap, render.tonemap),
+        white,
+        transfer: gamma
+            .or(render.gamma)
+            .map_or(Transfer::Srgb, Transfer::Gamma),
+        dither,
+    };
+
+    let mut camera_settings = scene.camera.clone().unwrap_or(CameraSettings {
+        look_from: vec3_array(look_from.clone())?,
+        look_at: vec3_array(look_at.clone())?,
+        vup: [0.0, 1.0, 0.0],
+        fov: 20.0,
         aperture,
-        dist_to_focus,
-    );
-    let world = random_scene(i32::from(ball_density));
+        focus_distance: Some(dist_to_focus),
+        shutter: [0.0, 0.0],
+    });
+    if given("look-from") {
+        camera_settings.look_from = vec3_array(look_from)?;
+    }
+    if given("look-at") {
+        camera_settings.look_at = vec3_array(look_at)?;
+    }
+    if given("aperture") {
+        camera_settings.aperture = aperture;
+    }
+    if given("dist-to-focus") {
+        camera_settings.focus_distance = Some(dist_to_focus);
+    }
+    if given("shutter") {
+        camera_settings.shutter = match shutter[..] {
+            [open, close] => [open, close],
+            _ => anyhow::bail!("Expected 2 comma separated times, got {}", shutter.len()),
+        };
+    }
+    let camera = camera_settings.camera(f64::from(width) / f64::from(height));
     let pb = ProgressBar::new(u64::from(u32::from(height) * u32::from(width) * nsamples));
     pb.set_style(
         ProgressStyle::default_bar()
@@ -151,40 +444,157 @@ fn main() -> Result<()> {
             .progress_chars("##-"),
     );
 
-    let mut file = File::create(filename).context("Unable to create file")?;
-
-    writeln!(file, "P3").context("Unable to write PPM header")?;
-    writeln!(file, "{} {}", width, height).context("Unable to write width and height to PPM")?;
-    writeln!(file, "255").context("Unable to write max pixel color value to PPM")?;
-
-    let gamma = gamma.recip();
-
-    let mut rows = (0..height)
-        .into_par_iter()
-        .flat_map(|y| {
-            let fy = f64::from(height - y);
-            let mut res = Vec::with_capacity(width.into());
-            for x in 0..width {
-                let col = (0..nsamples)
-                    .map(|_| {
-                        let u = (f64::from(x) + rand()) / f64::from(width);
-                        let v = (fy + rand()) / f64::from(height);
-                        color(camera.ray(u, v), &world, 0)
-                    })
-                    .fold(Vec3::zeros(), Add::add)
-                    .div(f64::from(nsamples))
-                    .powf(gamma);
-                pb.inc(u64::from(nsamples));
-                res.push((y * width + x, ColorVec3::from(col).into_array()));
+    let tracer = PathTracer {
+        max_depth: choose(given("max-depth"), max_depth, render.max_depth),
+        min_depth: choose(given("min-depth"), min_depth, render.min_depth),
+        bsdf_only,
+    };
+    let integrator: Box<dyn Integrator + Sync> = match integrator {
+        Choice::Path => Box::new(tracer),
+        Choice::AmbientOcclusion => Box::new(AmbientOcclusion {
+            distance: ao_distance,
+        }),
+        Choice::Inspect(inspect) => Box::new(inspect),
+        Choice::Bounces => Box::new(Bounces(tracer)),
+    };
+
+    let render = Render {
+        scene: &scene,
+        camera: &camera,
+        width,
+        height,
+        nsamples,
+        seed,
+        integrator: integrator.as_ref(),
+        aovs: !no_aovs && format == Format::Exr,
+    };
+    let framebuffer = FrameBuffer::new(usize::from(width), usize::from(height), render.pixels(&pb));
+    pb.finish();
+    if render.aovs {
+        framebuffer.save(filename, format, &tonemap)
+    } else {
+        framebuffer.image().save(filename, format, &tonemap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Choice, Render};
+    use indicatif::ProgressBar;
+    use raytracer::{scene, utils::Rng, vec3, Camera, Integrator, PathTracer, Ray, Scene, Vec3};
+
+    #[test]
+    fn test_render_is_deterministic() {
+        let scene = scene::cornell_box();
+        let camera = Camera::new(
+            vec3![0, 3, 4],
+            vec3![0, 0, 0],
+            vec3![0, 1, 0],
+            40.0,
+            1.0,
+            0.1,
+            5.0,
+        );
+        let render = |seed, threads| {
+            let render = Render {
+                scene: &scene,
+                camera: &camera,
+                width: 8,
+                height: 8,
+                nsamples: 4,
+                seed,
+                integrator: &PathTracer::default(),
+                aovs: true,
+            };
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap()
+                .install(|| render.pixels(&ProgressBar::hidden()))
+        };
+        let pixels = render(7, 1);
+        assert_eq!(pixels, render(7, 4));
+        assert_ne!(pixels, render(8, 4));
+    }
+
+    #[test]
+    fn test_pixel_keeps_nearest_hit() {
+        let scene = scene::cornell_box();
+        let camera = Camera::new(
+            vec3![278, 278, -800],
+            vec3![278, 278, 0],
+            vec3![0, 1, 0],
+            40.0,
+            1.0,
+            0.0,
+            800.0,
+        );
+        let render = Render {
+            scene: &scene,
+            camera: &camera,
+            width: 1,
+            height: 2,
+            nsamples: 64,
+            seed: 0,
+            integrator: &PathTracer::default(),
+            aovs: true,
+        };
+        let pixel = render.pixel(0, 1);
+        assert_eq!(pixel.samples, 64);
+        // every sample looks into the box, and the nearest hit sets the depth
+        assert!(
+            pixel.depth.is_finite() && pixel.depth >= 800.0,
+            "{}",
+            pixel.depth
+        );
+        assert_ne!(pixel.object_id, 0);
+    }
+
+    /// White, except for NaN to the right of the view.
+    struct Unstable;
+
+    impl Integrator for Unstable {
+        fn radiance(&self, ray: Ray, _scene: &Scene, _rng: &mut Rng) -> Vec3 {
+            if ray.direction().x() > 0.0 {
+                vec3![f64::NAN, 0, 0]
+            } else {
+                Vec3::ones()
             }
-            res
-        })
-        .collect::<Vec<_>>();
-    rows.sort_unstable_by(|(left, _), (right, _)| left.cmp(right));
-    for (row_index, [r, g, b]) in rows {
-        writeln!(file, "{} {} {}", r, g, b)
-            .with_context(|| format!("Unable to write pixel at row: {}", row_index))?;
+        }
+    }
+
+    #[test]
+    fn test_pixel_skips_non_finite_samples() {
+        let scene = scene::cornell_box();
+        let camera = Camera::new(
+            vec3![278, 278, -800],
+            vec3![278, 278, 0],
+            vec3![0, 1, 0],
+            40.0,
+            1.0,
+            0.0,
+            800.0,
+        );
+        let render = Render {
+            scene: &scene,
+            camera: &camera,
+            width: 1,
+            height: 2,
+            nsamples: 64,
+            seed: 0,
+            integrator: &Unstable,
+            aovs: true,
+        };
+        let pixel = render.pixel(0, 1);
+        assert!(pixel.samples > 0 && pixel.samples < 64, "{}", pixel.samples);
+        assert_eq!(pixel.color, Vec3::ones());
+    }
+
+    #[test]
+    fn test_integrator_names() {
+        for name in Choice::NAMES {
+            assert!(name.parse::<Choice>().is_ok(), "{}", name);
+        }
+        assert!("photons".parse::<Choice>().is_err());
     }
-    pb.finish();
-    Ok(())
 }
@@ -1,8 +1,14 @@
-use crate::{ray::Ray, utils, vec3::Vec3, HitRecord};
+use crate::{
+    ray::Ray,
+    utils::{self, Rng},
+    vec3::Vec3,
+    HitRecord, Texture,
+};
+use std::sync::Arc;
 
-fn random_in_unit_sphere() -> Vec3 {
+fn random_in_unit_sphere(rng: &mut Rng) -> Vec3 {
     loop {
-        let p = 2.0 * utils::randvec() - Vec3::ones();
+        let p = 2.0 * utils::randvec(rng) - Vec3::ones();
         if p.norm2() < 1.0 {
             return p;
         }
@@ -14,38 +20,121 @@ pub(crate) fn schlick(cosine: f64, ref_idx: f64) -> f64 {
     r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
 }
 
+/// The normal on the side of the surface that `r_in` arrives from.
+pub(crate) fn facing_normal(r_in: &Ray, rec: &HitRecord) -> Vec3 {
+    if r_in.direction().dot(rec.normal) > 0.0 {
+        -rec.normal
+    } else {
+        rec.normal
+    }
+}
+
 pub trait Material {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<(Vec3, Ray)>;
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, rng: &mut Rng) -> Option<(Vec3, Ray)>;
+
+    /// Radiance emitted from the surface at `rec`.
+    fn emitted(&self, _rec: &HitRecord) -> Vec3 {
+        Vec3::zeros()
+    }
+
+    /// The BSDF times the cosine of the angle between `scattered` and the
+    /// normal, for light arriving along `scattered` and leaving along `-r_in`.
+    ///
+    /// Phase functions of participating media have no surface to take the
+    /// cosine against, so they return the phase function times the albedo.
+    fn eval(&self, _r_in: &Ray, _rec: &HitRecord, _scattered: &Ray) -> Vec3 {
+        Vec3::zeros()
+    }
+
+    /// The solid angle density with which `scatter` samples `scattered`.
+    ///
+    /// Zero for specular materials that can't be evaluated, whose scattered
+    /// rays light sampling can't reproduce.
+    fn pdf(&self, _r_in: &Ray, _rec: &HitRecord, _scattered: &Ray) -> f64 {
+        0.0
+    }
+
+    /// The surface colour at `rec` for the albedo layer, independent of
+    /// lighting.
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        Vec3::zeros()
+    }
+}
+
+impl<M> Material for Arc<M>
+where
+    M: Material + ?Sized,
+{
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, rng: &mut Rng) -> Option<(Vec3, Ray)> {
+        self.as_ref().scatter(r_in, rec, rng)
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Vec3 {
+        self.as_ref().emitted(rec)
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Vec3 {
+        self.as_ref().eval(r_in, rec, scattered)
+    }
+
+    fn pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        self.as_ref().pdf(r_in, rec, scattered)
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.as_ref().albedo(rec)
+    }
 }
 
 #[derive(Debug, PartialEq)]
-pub struct Lambertian {
-    albedo: Vec3,
+pub struct Lambertian<T = Vec3> {
+    albedo: T,
 }
 
-impl Lambertian {
-    pub fn new(albedo: Vec3) -> Self {
+impl<T> Lambertian<T> {
+    pub fn new(albedo: T) -> Self {
         Self { albedo }
     }
 }
 
-impl Material for Lambertian {
-    fn scatter(&self, _: &Ray, rec: &HitRecord) -> Option<(Vec3, Ray)> {
-        let point = rec.point;
-        let target = point + rec.normal + random_in_unit_sphere();
-        let scattered = Ray::new(point, target - point);
-        Some((self.albedo, scattered))
+impl<T> Material for Lambertian<T>
+where
+    T: Texture,
+{
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, rng: &mut Rng) -> Option<(Vec3, Ray)> {
+        // offsetting the normal by a random unit vector is cosine distributed
+        let normal = facing_normal(r_in, rec);
+        let direction = normal + utils::random_unit_vector(rng);
+        let direction = if direction.norm2() < 1e-12 {
+            normal
+        } else {
+            direction
+        };
+        Some((self.albedo(rec), r_in.spawn(rec.point, direction)))
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Vec3 {
+        self.albedo(rec) * self.pdf(r_in, rec, scattered)
+    }
+
+    fn pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        let cosine = facing_normal(r_in, rec).dot(scattered.direction().unitize());
+        cosine.max(0.0) / std::f64::consts::PI
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.albedo.value(rec.u, rec.v, rec.point)
     }
 }
 
 #[derive(Debug, PartialEq)]
-pub struct Metal {
-    albedo: Vec3,
+pub struct Metal<T = Vec3> {
+    albedo: T,
     fuzz: f64,
 }
 
-impl Metal {
-    pub fn new(albedo: Vec3, fuzz: f64) -> Self {
+impl<T> Metal<T> {
+    pub fn new(albedo: T, fuzz: f64) -> Self {
         Self {
             albedo,
             fuzz: fuzz.min(1.0),
@@ -53,46 +142,167 @@ impl Metal {
     }
 }
 
-impl Material for Metal {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<(Vec3, Ray)> {
-        let reflected = r_in.direction().unitize().reflect(rec.normal);
-        let scattered = Ray::new(rec.point, reflected + self.fuzz * random_in_unit_sphere());
-        if scattered.direction().dot(rec.normal) > 0.0 {
-            Some((self.albedo, scattered))
+impl<T> Material for Metal<T>
+where
+    T: Texture,
+{
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, rng: &mut Rng) -> Option<(Vec3, Ray)> {
+        let normal = facing_normal(r_in, rec);
+        let reflected = r_in.direction().unitize().reflect(normal);
+        let scattered = r_in.spawn(
+            rec.point,
+            reflected + self.fuzz * random_in_unit_sphere(rng),
+        );
+        if scattered.direction().dot(normal) > 0.0 {
+            Some((self.albedo(rec), scattered))
         } else {
             None
         }
     }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.albedo.value(rec.u, rec.v, rec.point)
+    }
+}
+
+/// The shortest and longest wavelengths, in nanometres, that dispersive
+/// materials split light into.
+const WAVELENGTHS: (f64, f64) = (400.0, 700.0);
+
+/// Pick a wavelength for a ray of white light, with the weight for each
+/// channel of the colour it carries, which averages to one.
+///
+/// Blue, green and red each take a third of the spectrum and blend smoothly
+/// into their neighbours, so that white splits into a rainbow.
+fn sample_wavelength(rng: &mut Rng) -> (f64, Vec3) {
+    let (shortest, longest) = WAVELENGTHS;
+    let wavelength = shortest + utils::rand(rng) * (longest - shortest);
+    let blend = |centre: f64| {
+        let x = ((wavelength - centre) / 50.0 + 0.5).clamp(0.0, 1.0);
+        x * x * (3.0 - 2.0 * x)
+    };
+    let third = (longest - shortest) / 3.0;
+    let (to_green, to_red) = (blend(shortest + third), blend(longest - third));
+    let weight = 3.0 * vec3![to_red, to_green - to_red, 1.0 - to_green];
+    (wavelength, weight)
+}
+
+/// How the index of refraction of a dielectric varies with wavelength, in
+/// micrometres.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Dispersion {
+    /// Cauchy's equation, `n = a + b / λ²`.
+    Cauchy { a: f64, b: f64 },
+    /// The Sellmeier equation, `n² = 1 + Σ b λ² / (λ² - c)`, as given in
+    /// glass catalogues.
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+impl Dispersion {
+    /// The wavelength of the sodium d-line, in nanometres, at which glass
+    /// catalogues give indices of refraction.
+    pub const D_LINE: f64 = 587.6;
+
+    /// Cauchy's equation for glass with index of refraction `ref_idx` at the
+    /// d-line and Abbe number `abbe`, the lower the more dispersive.
+    pub fn abbe(ref_idx: f64, abbe: f64) -> Self {
+        // the hydrogen F and C lines that the Abbe number spans
+        let (f, c, d) = (0.4861_f64, 0.6563_f64, Self::D_LINE / 1000.0);
+        let b = (ref_idx - 1.0) / (abbe * (f.powi(-2) - c.powi(-2)));
+        Dispersion::Cauchy {
+            a: ref_idx - b / (d * d),
+            b,
+        }
+    }
+
+    /// The index of refraction at `wavelength`, in nanometres.
+    pub fn ref_idx(&self, wavelength: f64) -> f64 {
+        let l2 = (wavelength / 1000.0).powi(2);
+        match *self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                let sum: f64 = b.iter().zip(&c).map(|(b, c)| b * l2 / (l2 - c)).sum();
+                (1.0 + sum).sqrt()
+            }
+        }
+    }
 }
 
+/// Glass or liquid, which reflects and refracts perfectly smoothly.
+///
+/// Light travelling through it is absorbed by the Beer-Lambert law, at a
+/// rate of `absorption` per unit distance, counted from where a ray last
+/// scattered to where it next hits the surface from inside. Anything else
+/// inside the dielectric cuts that distance short.
 #[derive(Debug, PartialEq)]
 pub struct Dielectric {
     ref_idx: f64,
+    absorption: Vec3,
+    dispersion: Option<Dispersion>,
 }
 
 impl Dielectric {
     pub fn new(ref_idx: f64) -> Self {
-        Self { ref_idx }
+        Self::with_absorption(ref_idx, Vec3::zeros())
+    }
+
+    pub fn with_absorption(ref_idx: f64, absorption: Vec3) -> Self {
+        Self {
+            ref_idx,
+            absorption,
+            dispersion: None,
+        }
+    }
+
+    /// A dielectric that refracts each wavelength differently, splitting
+    /// white light into its colours.
+    pub fn dispersive(dispersion: Dispersion, absorption: Vec3) -> Self {
+        Self {
+            ref_idx: dispersion.ref_idx(Dispersion::D_LINE),
+            absorption,
+            dispersion: Some(dispersion),
+        }
     }
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<(Vec3, Ray)> {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, rng: &mut Rng) -> Option<(Vec3, Ray)> {
         let dir = r_in.direction();
         let dir_length = dir.norm();
         let rec_normal = rec.normal;
         let reflected = dir.reflect(rec_normal);
         let dir_dot_normal = dir.dot(rec_normal);
-        let ref_idx = self.ref_idx;
+
+        // rays of white light take on a single wavelength to disperse
+        let (ref_idx, mut attenuation, wavelength) = match (self.dispersion, r_in.wavelength()) {
+            (None, _) => (self.ref_idx, vec3![1, 1, 1], None),
+            (Some(dispersion), Some(wavelength)) => {
+                (dispersion.ref_idx(wavelength), vec3![1, 1, 1], None)
+            }
+            (Some(dispersion), None) => {
+                let (wavelength, weight) = sample_wavelength(rng);
+                (dispersion.ref_idx(wavelength), weight, Some(wavelength))
+            }
+        };
 
         let (outward_normal, ni_over_nt, factor) = if dir_dot_normal > 0.0 {
+            // arriving from inside, having crossed the dielectric since
+            // the ray's origin
+            let distance = rec.t * dir_length;
+            let [r, g, b] = self.absorption.into_array();
+            attenuation *= vec3![
+                (-r * distance).exp(),
+                (-g * distance).exp(),
+                (-b * distance).exp()
+            ];
             (-rec_normal, ref_idx, ref_idx)
         } else {
             (rec_normal, 1.0 / ref_idx, -1.0)
         };
 
         let direction = if let Some(refracted) = dir.refract(outward_normal, ni_over_nt) {
-            if utils::rand() < schlick(factor * dir_dot_normal / dir_length, ref_idx) {
+            if utils::rand(rng) < schlick(factor * dir_dot_normal / dir_length, ref_idx) {
                 reflected
             } else {
                 refracted
@@ -100,6 +310,99 @@ impl Material for Dielectric {
         } else {
             reflected
         };
-        Some((vec3![1, 1, 1], Ray::new(rec.point, direction)))
+        let scattered = r_in.spawn(rec.point, direction);
+        let scattered = match wavelength {
+            Some(wavelength) => scattered.with_wavelength(wavelength),
+            None => scattered,
+        };
+        Some((attenuation, scattered))
+    }
+
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        Vec3::ones()
+    }
+}
+
+/// A surface that emits light uniformly from both of its sides and reflects
+/// nothing.
+#[derive(Debug, PartialEq)]
+pub struct DiffuseLight {
+    emit: Vec3,
+}
+
+impl DiffuseLight {
+    pub fn new(emit: Vec3) -> Self {
+        Self { emit }
+    }
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, _: &Ray, _: &HitRecord, _: &mut Rng) -> Option<(Vec3, Ray)> {
+        None
+    }
+
+    fn emitted(&self, _: &HitRecord) -> Vec3 {
+        self.emit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sample_wavelength, Dielectric, Dispersion};
+    use crate::{utils::seeded_rng, HitRecord, Material, Ray, Vec3};
+
+    #[test]
+    fn test_dispersion() {
+        // Schott's N-BK7
+        let bk7 = Dispersion::Sellmeier {
+            b: [1.03961212, 0.231792344, 1.01046945],
+            c: [0.00600069867, 0.0200179144, 103.560653],
+        };
+        assert!((bk7.ref_idx(Dispersion::D_LINE) - 1.5168).abs() < 1e-4);
+        assert!(bk7.ref_idx(450.0) > bk7.ref_idx(650.0));
+
+        let cauchy = Dispersion::abbe(1.5168, 64.17);
+        assert!((cauchy.ref_idx(Dispersion::D_LINE) - 1.5168).abs() < 1e-12);
+        let abbe = (cauchy.ref_idx(587.6) - 1.0) / (cauchy.ref_idx(486.1) - cauchy.ref_idx(656.3));
+        assert!((abbe - 64.17).abs() < 1e-9);
+
+        // white light stays white on average
+        let rng = &mut seeded_rng(0);
+        let n = 100_000;
+        let mean =
+            (0..n).fold(Vec3::zeros(), |total, _| total + sample_wavelength(rng).1) / n as f64;
+        assert!((mean - Vec3::ones()).norm() < 0.01, "{:?}", mean);
+    }
+
+    #[test]
+    fn test_absorption_and_wavelength() {
+        let rng = &mut seeded_rng(0);
+        let glass = Dielectric::dispersive(Dispersion::abbe(1.5, 30.0), vec3![0.5, 0, 0]);
+        let rec = |t: f64, normal: Vec3| HitRecord {
+            t,
+            point: vec3![0, 0, t],
+            normal,
+            u: 0.0,
+            v: 0.0,
+            barycentrics: None,
+            material: &glass,
+        };
+
+        // entering picks a wavelength, and the weight of its colour
+        let r_in = Ray::new(vec3![0, 0, -1], vec3![0, 0, 1]);
+        let (weight, scattered) = glass
+            .scatter(&r_in, &rec(1.0, vec3![0, 0, -1]), rng)
+            .unwrap();
+        let wavelength = scattered.wavelength().unwrap();
+        assert!((400.0..700.0).contains(&wavelength));
+        assert!(weight.x() >= 0.0 && weight.y() >= 0.0 && weight.z() >= 0.0);
+
+        // leaving keeps it, but absorbs some of the red along the way
+        let r_in = Ray::new(Vec3::zeros(), vec3![0, 0, 2]).with_wavelength(wavelength);
+        let (weight, scattered) = glass
+            .scatter(&r_in, &rec(1.0, vec3![0, 0, 1]), rng)
+            .unwrap();
+        assert_eq!(scattered.wavelength(), Some(wavelength));
+        assert_eq!(weight, vec3![(-1.0_f64).exp(), 1, 1]);
     }
 }
@@ -0,0 +1,540 @@
+//! Participating media, which scatter light throughout their volume rather
+//! than at a surface, and the phase functions they scatter by.
+
+use crate::{
+    utils::{self, Rng},
+    voxel::VoxelGrid,
+    Aabb, HitRecord, Hittable, Material, MediumHit, Ray, Texture, Vec3,
+};
+use std::f64::consts::PI;
+
+/// How light travels through a participating medium, along rays given in
+/// the medium's own space.
+pub trait Medium {
+    /// Follow `ray` from `t_enter` towards `t_exit`, returning the radiance
+    /// that the medium emits back along it on the way, and what stops it.
+    fn sample(&self, ray: &Ray, t_enter: f64, t_exit: f64, rng: &mut Rng) -> (Vec3, MediumEvent);
+
+    /// The fraction of light that makes it from `t_enter` to `t_exit`
+    /// along `ray` without being scattered or absorbed, or an unbiased
+    /// estimate of it.
+    fn transmittance(&self, ray: &Ray, t_enter: f64, t_exit: f64, rng: &mut Rng) -> f64;
+
+    /// The phase function that light scatters by within the medium.
+    fn phase(&self) -> &dyn Material;
+}
+
+/// What, if anything, stops a ray travelling through a medium.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MediumEvent {
+    /// The ray makes it through.
+    Pass,
+    /// The ray scatters at `t`, by the phase function times `weight`.
+    Scatter {
+        t: f64,
+        weight: Vec3,
+    },
+    Absorb,
+}
+
+/// A medium of the same density throughout a closed, convex boundary, such
+/// as fog or smoke.
+///
+/// The boundary's own material is ignored. A ray is only ever inside the
+/// medium between its first two crossings of the boundary. Media mustn't
+/// overlap: where they do, only the one the ray entered first counts.
+pub struct ConstantMedium {
+    boundary: Box<dyn Hittable + Send + Sync>,
+    density: f64,
+    phase: Box<dyn Material + Send + Sync>,
+}
+
+impl ConstantMedium {
+    pub fn new(
+        boundary: impl Hittable + Send + Sync + 'static,
+        density: f64,
+        phase: impl Material + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            boundary: Box::new(boundary),
+            density,
+            phase: Box::new(phase),
+        }
+    }
+}
+
+impl Hittable for ConstantMedium {
+    fn hit(&self, _ray: Ray, _t_min: f64, _t_max: f64) -> Option<HitRecord<'_>> {
+        None
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.boundary.bounding_box()
+    }
+
+    fn medium_hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<MediumHit<'_>> {
+        // crossings behind the origin count, so that rays starting inside
+        // the medium still find where they entered it
+        let enter = self.boundary.hit(ray, f64::MIN, f64::MAX)?.t;
+        let exit = self.boundary.hit(ray, enter + 1e-4, f64::MAX)?.t;
+        let (t_enter, t_exit) = (enter.max(t_min), exit.min(t_max));
+        if t_enter >= t_exit {
+            return None;
+        }
+        Some(MediumHit {
+            t_enter,
+            t_exit,
+            ray,
+            medium: self,
+        })
+    }
+}
+
+impl Medium for ConstantMedium {
+    fn sample(&self, ray: &Ray, t_enter: f64, t_exit: f64, rng: &mut Rng) -> (Vec3, MediumEvent) {
+        // free flight distances are exponentially distributed
+        let speed = ray.direction().norm();
+        let t = t_enter - (1.0 - utils::rand(rng)).ln() / (self.density * speed);
+        let event = if t < t_exit {
+            MediumEvent::Scatter {
+                t,
+                weight: Vec3::ones(),
+            }
+        } else {
+            MediumEvent::Pass
+        };
+        (Vec3::zeros(), event)
+    }
+
+    fn transmittance(&self, ray: &Ray, t_enter: f64, t_exit: f64, _rng: &mut Rng) -> f64 {
+        let speed = ray.direction().norm();
+        (-self.density * speed * (t_exit - t_enter)).exp()
+    }
+
+    fn phase(&self) -> &dyn Material {
+        self.phase.as_ref()
+    }
+}
+
+/// A transfer function, which colours densities by interpolating linearly
+/// between stops.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferFunction {
+    stops: Vec<(f64, Vec3)>,
+}
+
+impl TransferFunction {
+    /// Stops of a density and its colour, in any order. Densities beyond the
+    /// first or last stop take its colour.
+    ///
+    /// Panics if there are no stops.
+    pub fn new(mut stops: Vec<(f64, Vec3)>) -> Self {
+        assert!(!stops.is_empty(), "transfer functions need a stop");
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self { stops }
+    }
+
+    /// The same colour at every density.
+    pub fn constant(color: Vec3) -> Self {
+        Self::new(vec![(0.0, color)])
+    }
+
+    pub fn color(&self, density: f64) -> Vec3 {
+        let after = self.stops.partition_point(|&(stop, _)| stop <= density);
+        match (self.stops.get(after.wrapping_sub(1)), self.stops.get(after)) {
+            (Some(&(start, from)), Some(&(end, to))) => {
+                from.lerp(to, (density - start) / (end - start))
+            }
+            (Some(&(_, color)), None) | (None, Some(&(_, color))) => color,
+            (None, None) => unreachable!("transfer functions have a stop"),
+        }
+    }
+}
+
+/// How much a grid medium absorbs, scatters and emits per unit of density
+/// and distance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coefficients {
+    pub absorption: f64,
+    pub scattering: f64,
+    pub emission: f64,
+}
+
+/// A medium whose density varies throughout a voxel grid, which it fills.
+///
+/// The grid's first channel is the density, and its second, if it has one,
+/// the strength of emission, which is otherwise the density. The transfer
+/// function colours both the light scattered at a density and the light
+/// emitted there. Like `ConstantMedium`, it mustn't overlap other media.
+pub struct GridMedium {
+    grid: VoxelGrid,
+    coefficients: Coefficients,
+    transfer: TransferFunction,
+    phase: Box<dyn Material + Send + Sync>,
+    /// The greatest extinction anywhere in the grid, which trilinear
+    /// interpolation never exceeds.
+    majorant: f64,
+}
+
+impl GridMedium {
+    pub fn new(
+        grid: VoxelGrid,
+        coefficients: Coefficients,
+        transfer: TransferFunction,
+        phase: impl Material + Send + Sync + 'static,
+    ) -> Self {
+        let majorant = (coefficients.absorption + coefficients.scattering) * grid.max(0).max(0.0);
+        Self {
+            grid,
+            coefficients,
+            transfer,
+            phase: Box::new(phase),
+            majorant,
+        }
+    }
+
+    /// The rate of tentative collisions along a ray through the medium, at
+    /// least the majorant but also high enough for a few per crossing, so
+    /// that thin, glowing media still gather their emission.
+    fn rate(&self, ray: &Ray, t_enter: f64, t_exit: f64) -> f64 {
+        let length = ray.direction().norm() * (t_exit - t_enter);
+        self.majorant.max(4.0 / length)
+    }
+
+    /// Radiance emitted per unit of distance at `point`, where the density
+    /// is `density`.
+    fn emission(&self, point: Vec3, density: f64) -> Vec3 {
+        if self.coefficients.emission == 0.0 {
+            return Vec3::zeros();
+        }
+        let strength = if self.grid.channels() > 1 {
+            self.grid.value(point, 1)
+        } else {
+            density
+        };
+        self.coefficients.emission * strength.max(0.0) * self.transfer.color(density)
+    }
+}
+
+impl Hittable for GridMedium {
+    fn hit(&self, _ray: Ray, _t_min: f64, _t_max: f64) -> Option<HitRecord<'_>> {
+        None
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.grid.bounds())
+    }
+
+    fn medium_hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<MediumHit<'_>> {
+        let (t_enter, t_exit) = self.grid.bounds().interval(ray, t_min, t_max)?;
+        if t_enter >= t_exit {
+            return None;
+        }
+        Some(MediumHit {
+            t_enter,
+            t_exit,
+            ray,
+            medium: self,
+        })
+    }
+}
+
+impl Medium for GridMedium {
+    /// Delta tracking: tentative collisions are drawn as if the medium were
+    /// as dense as its majorant everywhere, and each is real with the chance
+    /// of the actual density over the majorant.
+    fn sample(&self, ray: &Ray, t_enter: f64, t_exit: f64, rng: &mut Rng) -> (Vec3, MediumEvent) {
+        let rate = self.rate(ray, t_enter, t_exit);
+        let speed = ray.direction().norm();
+        let Coefficients {
+            absorption,
+            scattering,
+            ..
+        } = self.coefficients;
+        let mut emitted = Vec3::zeros();
+        let mut t = t_enter;
+        loop {
+            t -= (1.0 - utils::rand(rng)).ln() / (rate * speed);
+            if t >= t_exit {
+                return (emitted, MediumEvent::Pass);
+            }
+            let point = ray.point(t);
+            let density = self.grid.value(point, 0).max(0.0);
+            // every tentative collision up to the first real one samples the
+            // emission along the ray
+            emitted += self.emission(point, density) / rate;
+            let chance = utils::rand(rng) * rate;
+            if chance < absorption * density {
+                return (emitted, MediumEvent::Absorb);
+            }
+            if chance < (absorption + scattering) * density {
+                let weight = self.transfer.color(density);
+                return (emitted, MediumEvent::Scatter { t, weight });
+            }
+        }
+    }
+
+    /// Ratio tracking: the tentative collisions of delta tracking each scale
+    /// the transmittance by the chance that they weren't real.
+    fn transmittance(&self, ray: &Ray, t_enter: f64, t_exit: f64, rng: &mut Rng) -> f64 {
+        let rate = self.rate(ray, t_enter, t_exit);
+        let speed = ray.direction().norm();
+        let extinction = self.coefficients.absorption + self.coefficients.scattering;
+        let mut transmittance = 1.0;
+        let mut t = t_enter;
+        loop {
+            t -= (1.0 - utils::rand(rng)).ln() / (rate * speed);
+            if t >= t_exit || transmittance == 0.0 {
+                return transmittance;
+            }
+            let density = self.grid.value(ray.point(t), 0).max(0.0);
+            transmittance *= (1.0 - extinction * density / rate).max(0.0);
+        }
+    }
+
+    fn phase(&self) -> &dyn Material {
+        self.phase.as_ref()
+    }
+}
+
+/// A phase function that scatters equally in every direction.
+#[derive(Debug, PartialEq)]
+pub struct Isotropic<T = Vec3> {
+    albedo: T,
+}
+
+impl<T> Isotropic<T> {
+    pub fn new(albedo: T) -> Self {
+        Self { albedo }
+    }
+}
+
+impl<T> Material for Isotropic<T>
+where
+    T: Texture,
+{
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, rng: &mut Rng) -> Option<(Vec3, Ray)> {
+        let direction = utils::random_unit_vector(rng);
+        Some((self.albedo(rec), r_in.spawn(rec.point, direction)))
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Vec3 {
+        self.albedo(rec) * self.pdf(r_in, rec, scattered)
+    }
+
+    fn pdf(&self, _r_in: &Ray, _rec: &HitRecord, _scattered: &Ray) -> f64 {
+        1.0 / (4.0 * PI)
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.albedo.value(rec.u, rec.v, rec.point)
+    }
+}
+
+/// The Henyey-Greenstein phase function, which favours scattering forwards
+/// for positive asymmetry `g` and backwards for negative `g`, and is
+/// isotropic at zero.
+#[derive(Debug, PartialEq)]
+pub struct HenyeyGreenstein<T = Vec3> {
+    albedo: T,
+    g: f64,
+}
+
+impl<T> HenyeyGreenstein<T> {
+    /// `g` is the average cosine of the scattering angle, clamped to within
+    /// `(-1, 1)`.
+    pub fn new(albedo: T, g: f64) -> Self {
+        Self {
+            albedo,
+            g: g.clamp(-0.999, 0.999),
+        }
+    }
+
+    /// The density of scattering by an angle with cosine `cos_theta`.
+    fn phase(&self, cos_theta: f64) -> f64 {
+        let g = self.g;
+        let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+        (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+    }
+}
+
+impl<T> Material for HenyeyGreenstein<T>
+where
+    T: Texture,
+{
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, rng: &mut Rng) -> Option<(Vec3, Ray)> {
+        // invert the cumulative distribution of the scattering angle
+        let g = self.g;
+        let xi = utils::rand(rng);
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * xi
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * xi);
+            ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * utils::rand(rng);
+
+        let w = r_in.direction().unitize();
+        let (u, v) = utils::orthonormal_basis(w);
+        let direction = sin_theta * phi.cos() * u + sin_theta * phi.sin() * v + cos_theta * w;
+        Some((self.albedo(rec), r_in.spawn(rec.point, direction)))
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Vec3 {
+        self.albedo(rec) * self.pdf(r_in, rec, scattered)
+    }
+
+    fn pdf(&self, r_in: &Ray, _rec: &HitRecord, scattered: &Ray) -> f64 {
+        self.phase(
+            r_in.direction()
+                .unitize()
+                .dot(scattered.direction().unitize()),
+        )
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.albedo.value(rec.u, rec.v, rec.point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Coefficients, ConstantMedium, GridMedium, HenyeyGreenstein, Isotropic, Medium, MediumEvent,
+        TransferFunction,
+    };
+    use crate::{
+        utils::seeded_rng, voxel::VoxelGrid, Aabb, HitRecord, Hittable, Material, Ray, Sphere, Vec3,
+    };
+
+    #[test]
+    fn test_constant_medium_segments() {
+        let fog = ConstantMedium::new(
+            Sphere::new(Vec3::zeros(), 1.0, Isotropic::new(Vec3::ones())),
+            0.5,
+            Isotropic::new(Vec3::ones()),
+        );
+        let segment = |origin, t_max| {
+            let ray = Ray::new(origin, vec3![0, 0, 1]);
+            assert!(fog.hit(ray, 0.001, t_max).is_none());
+            fog.medium_hit(ray, 0.001, t_max)
+                .map(|rec| (rec.t_enter, rec.t_exit))
+        };
+        // passing through, starting inside and stopping short of the exit
+        assert_eq!(segment(vec3![0, 0, -3], f64::MAX), Some((2.0, 4.0)));
+        assert_eq!(segment(vec3![0, 0, 0.5], f64::MAX), Some((0.001, 0.5)));
+        assert_eq!(segment(vec3![0, 0, -3], 2.5), Some((2.0, 2.5)));
+        // beyond the medium and missing it altogether
+        assert_eq!(segment(vec3![0, 0, 2], f64::MAX), None);
+        assert_eq!(segment(vec3![0, 0, -3], 1.5), None);
+        assert_eq!(segment(vec3![0, 2, -3], f64::MAX), None);
+    }
+
+    #[test]
+    fn test_henyey_greenstein() {
+        let rng = &mut seeded_rng(0);
+        let material = Isotropic::new(Vec3::ones());
+        let rec = HitRecord {
+            t: 1.0,
+            point: Vec3::zeros(),
+            normal: vec3![0, 0, 1],
+            u: 0.0,
+            v: 0.0,
+            barycentrics: None,
+            material: &material,
+        };
+        let r_in = Ray::new(vec3![0, 0, -1], vec3![0, 0, 2]);
+        for &g in &[-0.6, 0.0, 0.3, 0.9] {
+            let phase = HenyeyGreenstein::new(Vec3::ones(), g);
+            // sampled directions average out to a cosine of g, and dividing
+            // the phase function by its density integrates it to one
+            let n = 100_000;
+            let (mut cosine, mut integral) = (0.0, 0.0);
+            for _ in 0..n {
+                let (_, scattered) = phase.scatter(&r_in, &rec, rng).unwrap();
+                let direction = scattered.direction().unitize();
+                cosine += direction.z();
+                let uniform = crate::utils::random_unit_vector(rng);
+                let uniform = Ray::new(Vec3::zeros(), uniform);
+                integral += phase.pdf(&r_in, &rec, &uniform) * 4.0 * std::f64::consts::PI;
+            }
+            let n = f64::from(n);
+            assert!((cosine / n - g).abs() < 0.01, "{} {}", cosine / n, g);
+            assert!((integral / n - 1.0).abs() < 0.05, "{} {}", integral / n, g);
+            let reverse = Ray::new(Vec3::zeros(), vec3![0, 0, -1]);
+            assert_eq!(
+                phase.eval(&r_in, &rec, &reverse).x(),
+                phase.pdf(&r_in, &rec, &reverse)
+            );
+        }
+    }
+
+    #[test]
+    fn test_transfer_function() {
+        let transfer = TransferFunction::new(vec![
+            (3.0, vec3![1, 0, 0]),
+            (0.0, Vec3::zeros()),
+            (1.0, Vec3::ones()),
+        ]);
+        assert_eq!(transfer.color(0.5), vec3![0.5, 0.5, 0.5]);
+        assert_eq!(transfer.color(2.0), vec3![1, 0.5, 0.5]);
+        assert_eq!(transfer.color(-1.0), Vec3::zeros());
+        assert_eq!(transfer.color(5.0), vec3![1, 0, 0]);
+        assert_eq!(
+            TransferFunction::constant(Vec3::ones()).color(7.0),
+            Vec3::ones()
+        );
+    }
+
+    #[test]
+    fn test_grid_medium_tracking() {
+        // the density rises from 0 to 2 across the unit cube, so a ray along
+        // x has an optical depth of one, half of which absorbs
+        let grid = VoxelGrid::from_fn([3, 2, 2], Aabb::new(Vec3::zeros(), Vec3::ones()), |p| {
+            2.0 * p.x()
+        });
+        let coefficients = Coefficients {
+            absorption: 0.5,
+            scattering: 0.5,
+            emission: 1.0,
+        };
+        let medium = GridMedium::new(
+            grid,
+            coefficients,
+            TransferFunction::constant(Vec3::ones()),
+            Isotropic::new(Vec3::ones()),
+        );
+        let ray = Ray::new(vec3![-1, 0.5, 0.5], vec3![0.5, 0, 0]);
+        let hit = medium.medium_hit(ray, 0.001, f64::MAX).unwrap();
+        assert_eq!((hit.t_enter, hit.t_exit), (2.0, 4.0));
+
+        let rng = &mut seeded_rng(0);
+        let n = 50_000;
+        let (mut transmittance, mut passed, mut absorbed, mut emitted) = (0.0, 0, 0, 0.0);
+        for _ in 0..n {
+            transmittance += medium.transmittance(&ray, 2.0, 4.0, rng);
+            let (light, event) = medium.sample(&ray, 2.0, 4.0, rng);
+            emitted += light.x();
+            match event {
+                MediumEvent::Pass => passed += 1,
+                MediumEvent::Absorb => absorbed += 1,
+                MediumEvent::Scatter { t, weight } => {
+                    assert!((2.0..4.0).contains(&t));
+                    assert_eq!(weight, Vec3::ones());
+                }
+            }
+        }
+        let n = f64::from(n);
+        let expected = (-1.0_f64).exp();
+        assert!((transmittance / n - expected).abs() < 0.01);
+        assert!((f64::from(passed) / n - expected).abs() < 0.01);
+        assert!((f64::from(absorbed) / n - 0.5 * (1.0 - expected)).abs() < 0.01);
+        // the emission of 2x, attenuated by exp(-x^2), integrates to 1 - 1/e
+        assert!(
+            (emitted / n - (1.0 - expected)).abs() < 0.01,
+            "{}",
+            emitted / n
+        );
+    }
+}
@@ -0,0 +1,401 @@
+use crate::{
+    shape::area_to_solid_angle,
+    utils::{self, Rng},
+    Aabb, Bvh, HitRecord, Hittable, Material, Ray, Vec3,
+};
+use std::sync::Arc;
+
+struct MeshData {
+    positions: Vec<Vec3>,
+    normals: Option<Vec<Vec3>>,
+    uvs: Option<Vec<[f64; 2]>>,
+    indices: Vec<[usize; 3]>,
+    material: Arc<dyn Material + Send + Sync>,
+}
+
+/// A single triangle of a [`TriangleMesh`], or a standalone triangle.
+pub struct Triangle {
+    mesh: Arc<MeshData>,
+    face: usize,
+}
+
+impl Triangle {
+    pub fn new(a: Vec3, b: Vec3, c: Vec3, material: impl Material + Send + Sync + 'static) -> Self {
+        Self {
+            mesh: Arc::new(MeshData {
+                positions: vec![a, b, c],
+                normals: None,
+                uvs: None,
+                indices: vec![[0, 1, 2]],
+                material: Arc::new(material),
+            }),
+            face: 0,
+        }
+    }
+
+    fn vertices(&self) -> [Vec3; 3] {
+        let [i0, i1, i2] = self.mesh.indices[self.face];
+        let positions = &self.mesh.positions;
+        [positions[i0], positions[i1], positions[i2]]
+    }
+
+    pub fn area(&self) -> f64 {
+        let [p0, p1, p2] = self.vertices();
+        0.5 * (p1 - p0).cross(p2 - p0).norm()
+    }
+
+    fn geometric_normal(&self) -> Vec3 {
+        let [p0, p1, p2] = self.vertices();
+        (p1 - p0).cross(p2 - p0).unitize()
+    }
+
+    /// A uniformly distributed point on the triangle.
+    fn uniform_point(&self, rng: &mut Rng) -> Vec3 {
+        let [p0, p1, p2] = self.vertices();
+        let su = utils::rand(rng).sqrt();
+        let (b0, b1) = (1.0 - su, utils::rand(rng) * su);
+        b0 * p0 + b1 * p1 + (1.0 - b0 - b1) * p2
+    }
+}
+
+/// Watertight ray/triangle intersection (Woop, Benthin and Wald, 2013).
+///
+/// Returns `t` and the barycentric weights of the three vertices. Rays
+/// through a shared edge or vertex hit at least one of the adjacent
+/// triangles.
+fn intersect(ray: &Ray, [p0, p1, p2]: [Vec3; 3]) -> Option<(f64, [f64; 3])> {
+    let origin = ray.origin();
+    let dir = ray.direction();
+
+    // permute the axes so that the ray direction is dominant in z
+    let kz = (0..3)
+        .max_by(|&i, &j| dir[i].abs().total_cmp(&dir[j].abs()))
+        .unwrap();
+    let (mut kx, mut ky) = ((kz + 1) % 3, (kz + 2) % 3);
+    if dir[kz] < 0.0 {
+        std::mem::swap(&mut kx, &mut ky);
+    }
+
+    // shear the triangle into a space where the ray runs along +z
+    let sx = dir[kx] / dir[kz];
+    let sy = dir[ky] / dir[kz];
+    let sz = 1.0 / dir[kz];
+
+    let (a, b, c) = (p0 - origin, p1 - origin, p2 - origin);
+    let (ax, ay) = (a[kx] - sx * a[kz], a[ky] - sy * a[kz]);
+    let (bx, by) = (b[kx] - sx * b[kz], b[ky] - sy * b[kz]);
+    let (cx, cy) = (c[kx] - sx * c[kz], c[ky] - sy * c[kz]);
+
+    let u = cx * by - cy * bx;
+    let v = ax * cy - ay * cx;
+    let w = bx * ay - by * ax;
+
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+
+    // NaN for rays with NaN directions
+    let det = u + v + w;
+    if det == 0.0 || det.is_nan() {
+        return None;
+    }
+
+    let t = (u * sz * a[kz] + v * sz * b[kz] + w * sz * c[kz]) / det;
+    Some((t, [u / det, v / det, w / det]))
+}
+
+impl Hittable for Triangle {
+    fn hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let vertices = self.vertices();
+        let (t, [b0, b1, b2]) = intersect(&ray, vertices)?;
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+
+        let [p0, p1, p2] = vertices;
+        let geometric_normal = self.geometric_normal();
+        let [i0, i1, i2] = self.mesh.indices[self.face];
+
+        let normal = match &self.mesh.normals {
+            Some(normals) => {
+                let shading_normal =
+                    (b0 * normals[i0] + b1 * normals[i1] + b2 * normals[i2]).unitize();
+                // keep the interpolated normal on the geometric side of the surface
+                if shading_normal.dot(geometric_normal) < 0.0 {
+                    -shading_normal
+                } else {
+                    shading_normal
+                }
+            }
+            None => geometric_normal,
+        };
+
+        let [u, v] = match &self.mesh.uvs {
+            Some(uvs) => {
+                let ([u0, v0], [u1, v1], [u2, v2]) = (uvs[i0], uvs[i1], uvs[i2]);
+                [b0 * u0 + b1 * u1 + b2 * u2, b0 * v0 + b1 * v1 + b2 * v2]
+            }
+            None => [b1, b2],
+        };
+
+        Some(HitRecord {
+            t,
+            point: b0 * p0 + b1 * p1 + b2 * p2,
+            normal,
+            u,
+            v,
+            barycentrics: Some([b0, b1, b2]),
+            material: self.mesh.material.as_ref(),
+        })
+    }
+
+    fn occluded(&self, ray: Ray, t_min: f64, t_max: f64) -> bool {
+        matches!(intersect(&ray, self.vertices()), Some((t, _)) if t > t_min && t < t_max)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let [p0, p1, p2] = self.vertices();
+        Some(Aabb::new(p0, p1).grow(p2))
+    }
+
+    fn sample_point(&self, _origin: Vec3, _time: f64, rng: &mut Rng) -> Option<Vec3> {
+        Some(self.uniform_point(rng))
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, _time: f64) -> f64 {
+        match self.hit(Ray::new(origin, direction), 0.001, f64::MAX) {
+            Some(rec) => area_to_solid_angle(
+                self.area().recip(),
+                origin,
+                rec.point,
+                self.geometric_normal(),
+            ),
+            None => 0.0,
+        }
+    }
+}
+
+/// An indexed triangle mesh whose triangles share a single material.
+pub struct TriangleMesh {
+    triangles: Bvh<Triangle>,
+    /// Cumulative triangle areas, in face order, for sampling by area.
+    cdf: Vec<f64>,
+    mesh: Arc<MeshData>,
+}
+
+impl TriangleMesh {
+    /// Build a mesh from shared vertex buffers.
+    ///
+    /// `normals` and `uvs`, when given, are per-vertex and interpolated across
+    /// each face.
+    ///
+    /// # Panics
+    ///
+    /// If an index is out of bounds or a per-vertex buffer has a different
+    /// length than `positions`.
+    pub fn new(
+        positions: Vec<Vec3>,
+        indices: Vec<[usize; 3]>,
+        normals: Option<Vec<Vec3>>,
+        uvs: Option<Vec<[f64; 2]>>,
+        material: Arc<dyn Material + Send + Sync>,
+    ) -> Self {
+        let nvertices = positions.len();
+        assert!(
+            indices.iter().flatten().all(|&index| index < nvertices),
+            "triangle index out of bounds"
+        );
+        assert!(
+            normals
+                .as_ref()
+                .is_none_or(|normals| normals.len() == nvertices),
+            "expected one normal per vertex"
+        );
+        assert!(
+            uvs.as_ref().is_none_or(|uvs| uvs.len() == nvertices),
+            "expected one uv per vertex"
+        );
+
+        let nfaces = indices.len();
+        let mesh = Arc::new(MeshData {
+            positions,
+            normals,
+            uvs,
+            indices,
+            material,
+        });
+        let triangles = (0..nfaces)
+            .map(|face| Triangle {
+                mesh: Arc::clone(&mesh),
+                face,
+            })
+            .collect::<Vec<_>>();
+        let cdf = triangles
+            .iter()
+            .scan(0.0, |total, triangle| {
+                *total += triangle.area();
+                Some(*total)
+            })
+            .collect();
+        Self {
+            triangles: Bvh::new(triangles),
+            cdf,
+            mesh,
+        }
+    }
+}
+
+impl Hittable for TriangleMesh {
+    fn hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        self.triangles.hit(ray, t_min, t_max)
+    }
+
+    fn occluded(&self, ray: Ray, t_min: f64, t_max: f64) -> bool {
+        self.triangles.occluded(ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.triangles.bounding_box()
+    }
+
+    fn sample_point(&self, _origin: Vec3, _time: f64, rng: &mut Rng) -> Option<Vec3> {
+        let area = *self.cdf.last()?;
+        let target = utils::rand(rng) * area;
+        let face = self
+            .cdf
+            .partition_point(|&total| total <= target)
+            .min(self.cdf.len() - 1);
+        let triangle = Triangle {
+            mesh: Arc::clone(&self.mesh),
+            face,
+        };
+        Some(triangle.uniform_point(rng))
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, _time: f64) -> f64 {
+        let area = self.cdf.last().copied().unwrap_or(0.0);
+        match self
+            .triangles
+            .hit_object(Ray::new(origin, direction), 0.001, f64::MAX)
+        {
+            Some((_, triangle, rec)) if area > 0.0 => {
+                area_to_solid_angle(area.recip(), origin, rec.point, triangle.geometric_normal())
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Triangle, TriangleMesh};
+    use crate::{
+        utils::{rand, seeded_rng},
+        Hittable, Lambertian, Ray,
+    };
+    use std::sync::Arc;
+
+    fn quad() -> TriangleMesh {
+        TriangleMesh::new(
+            vec![
+                vec3![0, 0, 0],
+                vec3![1, 0, 0],
+                vec3![1, 1, 0],
+                vec3![0, 1, 0],
+            ],
+            vec![[0, 1, 2], [0, 2, 3]],
+            Some(vec![
+                vec3![0, 0, 1],
+                vec3![0, 0, 1],
+                vec3![1, 0, 0],
+                vec3![0, 0, 1],
+            ]),
+            Some(vec![[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]]),
+            Arc::new(Lambertian::new(vec3![0.5, 0.5, 0.5])),
+        )
+    }
+
+    #[test]
+    fn test_triangle_hit() {
+        let triangle = Triangle::new(
+            vec3![-1, -1, 0],
+            vec3![1, -1, 0],
+            vec3![0, 1, 0],
+            Lambertian::new(vec3![0.5, 0.5, 0.5]),
+        );
+        let rec = triangle
+            .hit(Ray::new(vec3![0, 0, -2], vec3![0, 0, 1]), 0.0, f64::MAX)
+            .unwrap();
+        assert_eq!(rec.t, 2.0);
+        assert_eq!(rec.point, vec3![0, 0, 0]);
+        assert_eq!(rec.normal, vec3![0, 0, 1]);
+        assert_eq!(triangle.area(), 2.0);
+        assert!(triangle
+            .hit(Ray::new(vec3![2, 0, -2], vec3![0, 0, 1]), 0.0, f64::MAX)
+            .is_none());
+        assert!(triangle
+            .hit(Ray::new(vec3![0, 0, -2], vec3![0, 0, 1]), 0.0, 1.0)
+            .is_none());
+        // degenerate rays miss rather than panic
+        assert!(triangle
+            .hit(
+                Ray::new(vec3![0, 0, -2], vec3![f64::NAN, 0, 1]),
+                0.0,
+                f64::MAX
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn test_mesh_interpolation() {
+        let mesh = quad();
+        let rec = mesh
+            .hit(
+                Ray::new(vec3![0.75, 0.25, 1], vec3![0, 0, -1]),
+                0.0,
+                f64::MAX,
+            )
+            .unwrap();
+        assert!((rec.u - 0.75).abs() < 1e-12);
+        assert!((rec.v - 0.25).abs() < 1e-12);
+        // a quarter of the way towards the vertex whose normal is +x
+        assert!(rec.normal.x() > 0.0 && rec.normal.z() > 0.0);
+        assert!((rec.normal.norm() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_mesh_sampling() {
+        let mesh = quad();
+        let origin = vec3![0.5, 0.5, 2];
+        let rng = &mut seeded_rng(0);
+        let mut hits = 0;
+        for _ in 0..1000 {
+            let point = mesh.sample_point(origin, 0.0, rng).unwrap();
+            assert!(point.z() == 0.0 && (0.0..=1.0).contains(&point.x()));
+            hits += (point.x() > point.y()) as i32;
+        }
+        // both triangles have the same area
+        assert!((400..600).contains(&hits), "{}", hits);
+        // a unit quad seen head on from a distance of 2
+        let pdf = mesh.pdf_value(origin, vec3![0, 0, -1], 0.0);
+        assert!((pdf - 4.0).abs() < 1e-12);
+        assert_eq!(mesh.pdf_value(origin, vec3![0, 0, 1], 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_mesh_is_watertight() {
+        let mesh = quad();
+        // rays along the shared diagonal and through shared vertices
+        for i in 0..=100 {
+            let s = f64::from(i) / 100.0;
+            let ray = Ray::new(vec3![s, s, 1], vec3![0, 0, -1]);
+            assert!(mesh.hit(ray, 0.0, f64::MAX).is_some(), "missed at {}", s);
+        }
+        let rng = &mut seeded_rng(0);
+        for _ in 0..1000 {
+            let s = rand(rng);
+            let ray = Ray::new(vec3![0.5, 0.5, 1], vec3![s - 0.5, s - 0.5, -1]);
+            assert!(mesh.hit(ray, 0.0, f64::MAX).is_some());
+        }
+    }
+}
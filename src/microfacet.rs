@@ -0,0 +1,507 @@
+//! Rough conductors and dielectrics, modelled as surfaces of tiny mirror
+//! facets whose normals follow the GGX (Trowbridge-Reitz) distribution.
+
+use crate::{
+    material::facing_normal,
+    utils::{self, Rng},
+    HitRecord, Material, Ray, Vec3,
+};
+use serde::Deserialize;
+use std::f64::consts::PI;
+
+/// The GGX distribution of microfacet normals, with Smith's height-correlated
+/// masking-shadowing.
+///
+/// Slopes along the surface's tangent scale with `alpha_x`, and along its
+/// bitangent with `alpha_y`. Surfaces have no tangents of their own, so it
+/// runs around the world's y axis, as on something turned on a lathe.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ggx {
+    alpha_x: f64,
+    alpha_y: f64,
+}
+
+impl Ggx {
+    /// Each `alpha` is clamped to at least `1e-3`, as perfectly smooth
+    /// surfaces make the distribution a delta function.
+    pub fn new(alpha_x: f64, alpha_y: f64) -> Self {
+        Self {
+            alpha_x: alpha_x.max(1e-3),
+            alpha_y: alpha_y.max(1e-3),
+        }
+    }
+
+    /// The perceptually linear parameterisation from Disney's principled
+    /// BRDF, with `alpha` the square of `roughness`, stretched along the
+    /// tangent by `anisotropy` from 0 (isotropic) to 1.
+    pub fn from_roughness(roughness: f64, anisotropy: f64) -> Self {
+        let alpha = roughness * roughness;
+        let aspect = (1.0 - 0.9 * anisotropy.clamp(0.0, 1.0)).sqrt();
+        Self::new(alpha / aspect, alpha * aspect)
+    }
+
+    /// The density of microfacet normals `h`, per projected area.
+    fn d(&self, h: Vec3) -> f64 {
+        if h.z() <= 0.0 {
+            return 0.0;
+        }
+        let (x, y) = (h.x() / self.alpha_x, h.y() / self.alpha_y);
+        let denominator = x * x + y * y + h.z() * h.z();
+        1.0 / (PI * self.alpha_x * self.alpha_y * denominator * denominator)
+    }
+
+    /// Smith's auxiliary function, the area of facets hidden from `w` per
+    /// visible area.
+    fn lambda(&self, w: Vec3) -> f64 {
+        let (x, y) = (self.alpha_x * w.x(), self.alpha_y * w.y());
+        let tan2 = (x * x + y * y) / (w.z() * w.z());
+        0.5 * ((1.0 + tan2).sqrt() - 1.0)
+    }
+
+    /// The fraction of facets that are visible from `w`.
+    pub(crate) fn g1(&self, w: Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// The fraction of facets that are visible from both `wo` and `wi`.
+    pub(crate) fn g2(&self, wo: Vec3, wi: Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// Sample a facet normal in proportion to how much of it is visible from
+    /// `wo`, in the upper hemisphere (Heitz, "Sampling the GGX Distribution
+    /// of Visible Normals").
+    pub(crate) fn sample_visible(&self, wo: Vec3, rng: &mut Rng) -> Vec3 {
+        // stretch to a hemisphere of unit roughness
+        let v = vec3![self.alpha_x * wo.x(), self.alpha_y * wo.y(), wo.z()].unitize();
+        let length2 = v.x() * v.x() + v.y() * v.y();
+        let t1 = if length2 > 0.0 {
+            vec3![-v.y(), v.x(), 0] / length2.sqrt()
+        } else {
+            vec3![1, 0, 0]
+        };
+        let t2 = v.cross(t1);
+
+        // a point on the disk, squashed onto the part of it that's visible
+        let r = utils::rand(rng).sqrt();
+        let phi = 2.0 * PI * utils::rand(rng);
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + v.z());
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let n = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * v;
+
+        vec3![self.alpha_x * n.x(), self.alpha_y * n.y(), n.z().max(1e-6)].unitize()
+    }
+
+    /// The density with which `sample_visible` draws `h`.
+    pub(crate) fn pdf_visible(&self, wo: Vec3, h: Vec3) -> f64 {
+        self.g1(wo) * wo.dot(h).max(0.0) * self.d(h) / wo.z()
+    }
+
+    /// Sample a direction reflected from `wo` by a visible facet, or `None`
+    /// if it would go below the surface.
+    pub(crate) fn sample_reflection(&self, wo: Vec3, rng: &mut Rng) -> Option<Vec3> {
+        let wi = (-wo).reflect(self.sample_visible(wo, rng));
+        if wi.z() > 0.0 {
+            Some(wi)
+        } else {
+            None
+        }
+    }
+
+    /// The BRDF times the cosine for reflecting `wo` into `wi`, without the
+    /// Fresnel reflectance, and the density with which `sample_reflection`
+    /// draws `wi`, with the facet normal between them.
+    pub(crate) fn reflection(&self, wo: Vec3, wi: Vec3) -> Option<(Vec3, f64, f64)> {
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return None;
+        }
+        let h = (wo + wi).unitize();
+        let value = self.d(h) * self.g2(wo, wi) / (4.0 * wo.z());
+        Some((h, value, self.pdf_visible(wo, h) / (4.0 * wo.dot(h))))
+    }
+
+    /// Sample a direction that a dielectric surface reflects or refracts
+    /// `wo` into, in proportion to the Fresnel reflectance, where `eta` is
+    /// the index of refraction below the surface relative to above it.
+    pub(crate) fn sample_dielectric(&self, wo: Vec3, eta: f64, rng: &mut Rng) -> Option<Vec3> {
+        let h = self.sample_visible(wo, rng);
+        let cos_o = wo.dot(h);
+        if utils::rand(rng) < fresnel_dielectric(cos_o, eta) {
+            let wi = (-wo).reflect(h);
+            if wi.z() > 0.0 {
+                Some(wi)
+            } else {
+                None
+            }
+        } else {
+            let sin2_t = (1.0 - cos_o * cos_o) / (eta * eta);
+            let cos_t = (1.0 - sin2_t).max(0.0).sqrt();
+            let wi = -wo / eta + (cos_o / eta - cos_t) * h;
+            if wi.z() < 0.0 {
+                Some(wi)
+            } else {
+                None
+            }
+        }
+    }
+
+    /// The BSDF times the cosine of a dielectric surface scattering `wo`
+    /// into `wi`, and the density with which `sample_dielectric` draws `wi`.
+    pub(crate) fn dielectric(&self, wo: Vec3, wi: Vec3, eta: f64) -> (f64, f64) {
+        if wo.z() <= 0.0 || wi.z() == 0.0 {
+            return (0.0, 0.0);
+        }
+        // the facet normal that scatters `wo` into `wi`, which has to face both
+        let refracts = wi.z() < 0.0;
+        let h = if refracts { wo + eta * wi } else { wo + wi };
+        if h.norm2() == 0.0 {
+            return (0.0, 0.0);
+        }
+        let h = h.unitize();
+        let h = if h.z() < 0.0 { -h } else { h };
+        if wo.dot(h) <= 0.0 || (wi.dot(h) < 0.0) != refracts {
+            return (0.0, 0.0);
+        }
+
+        let fresnel = fresnel_dielectric(wo.dot(h), eta);
+        let (d, g) = (self.d(h), self.g2(wo, wi));
+        let pdf_h = self.pdf_visible(wo, h);
+        if refracts {
+            let denominator = (wo.dot(h) + eta * wi.dot(h)).powi(2);
+            let jacobian = eta * eta * wi.dot(h).abs() / denominator;
+            let value = (1.0 - fresnel) * d * g * wo.dot(h) * jacobian / wo.z();
+            (value, (1.0 - fresnel) * pdf_h * jacobian)
+        } else {
+            let value = fresnel * d * g / (4.0 * wo.z());
+            (value, fresnel * pdf_h / (4.0 * wo.dot(h)))
+        }
+    }
+}
+
+/// The tangent, bitangent and normal of a shading frame around `normal`.
+pub(crate) struct Frame {
+    tangent: Vec3,
+    bitangent: Vec3,
+    normal: Vec3,
+}
+
+impl Frame {
+    pub(crate) fn new(normal: Vec3) -> Self {
+        let tangent = vec3![0, 1, 0].cross(normal);
+        let (tangent, bitangent) = if tangent.norm2() > 1e-12 {
+            let tangent = tangent.unitize();
+            (tangent, normal.cross(tangent))
+        } else {
+            utils::orthonormal_basis(normal)
+        };
+        Self {
+            tangent,
+            bitangent,
+            normal,
+        }
+    }
+
+    pub(crate) fn to_local(&self, v: Vec3) -> Vec3 {
+        vec3![
+            v.dot(self.tangent),
+            v.dot(self.bitangent),
+            v.dot(self.normal)
+        ]
+    }
+
+    pub(crate) fn to_world(&self, v: Vec3) -> Vec3 {
+        v.x() * self.tangent + v.y() * self.bitangent + v.z() * self.normal
+    }
+}
+
+/// The Fresnel reflectance of light arriving at an angle with cosine
+/// `cos_i` to the normal, onto a dielectric with relative index of
+/// refraction `eta`.
+pub(crate) fn fresnel_dielectric(cos_i: f64, eta: f64) -> f64 {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let rs = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let rp = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    0.5 * (rs * rs + rp * rp)
+}
+
+/// The Fresnel reflectance of a conductor with complex index of refraction
+/// `eta + i k`, for each channel.
+pub(crate) fn fresnel_conductor(cos_i: f64, eta: Vec3, k: Vec3) -> Vec3 {
+    let cos2 = cos_i.clamp(0.0, 1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let channel = |eta: f64, k: f64| {
+        let t0 = eta * eta - k * k - sin2;
+        let a2_plus_b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+        let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+        let t1 = a2_plus_b2 + cos2;
+        let t2 = 2.0 * cos2.sqrt() * a;
+        let rs = (t1 - t2) / (t1 + t2);
+        let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let rp = rs * (t3 - t4) / (t3 + t4);
+        0.5 * (rs + rp)
+    };
+    vec3![
+        channel(eta.x(), k.x()),
+        channel(eta.y(), k.y()),
+        channel(eta.z(), k.z())
+    ]
+}
+
+/// Metals with measured indices of refraction, for red, green and blue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Element {
+    Gold,
+    Copper,
+    Aluminium,
+    Silver,
+}
+
+impl Element {
+    /// The real and imaginary parts of the index of refraction.
+    pub fn ior(self) -> (Vec3, Vec3) {
+        match self {
+            Element::Gold => (vec3![0.143, 0.374, 1.442], vec3![3.983, 2.385, 1.603]),
+            Element::Copper => (vec3![0.200, 0.924, 1.102], vec3![3.912, 2.452, 2.142]),
+            Element::Aluminium => (vec3![1.657, 0.880, 0.521], vec3![9.224, 6.270, 4.837]),
+            Element::Silver => (vec3![0.155, 0.117, 0.138], vec3![4.828, 3.122, 2.147]),
+        }
+    }
+}
+
+/// A rough metal, reflecting by the Fresnel equations for its complex index
+/// of refraction.
+#[derive(Debug, PartialEq)]
+pub struct Conductor {
+    eta: Vec3,
+    k: Vec3,
+    distribution: Ggx,
+}
+
+impl Conductor {
+    pub fn new(eta: Vec3, k: Vec3, distribution: Ggx) -> Self {
+        Self {
+            eta,
+            k,
+            distribution,
+        }
+    }
+
+    pub fn preset(element: Element, distribution: Ggx) -> Self {
+        let (eta, k) = element.ior();
+        Self::new(eta, k, distribution)
+    }
+
+    /// The directions back along `r_in` and along `scattered`, in the
+    /// shading frame on the side that `r_in` arrives from.
+    fn local(r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> (Vec3, Vec3) {
+        let frame = Frame::new(facing_normal(r_in, rec));
+        (
+            frame.to_local(-r_in.direction().unitize()),
+            frame.to_local(scattered.direction().unitize()),
+        )
+    }
+}
+
+impl Material for Conductor {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, rng: &mut Rng) -> Option<(Vec3, Ray)> {
+        let frame = Frame::new(facing_normal(r_in, rec));
+        let wo = frame.to_local(-r_in.direction().unitize());
+        if wo.z() <= 0.0 {
+            return None;
+        }
+        let ggx = &self.distribution;
+        let wi = ggx.sample_reflection(wo, rng)?;
+        let h = (wo + wi).unitize();
+        let weight = fresnel_conductor(wo.dot(h), self.eta, self.k) * ggx.g2(wo, wi) / ggx.g1(wo);
+        Some((weight, r_in.spawn(rec.point, frame.to_world(wi))))
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Vec3 {
+        let (wo, wi) = Self::local(r_in, rec, scattered);
+        match self.distribution.reflection(wo, wi) {
+            Some((h, value, _)) => fresnel_conductor(wo.dot(h), self.eta, self.k) * value,
+            None => Vec3::zeros(),
+        }
+    }
+
+    fn pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        let (wo, wi) = Self::local(r_in, rec, scattered);
+        self.distribution
+            .reflection(wo, wi)
+            .map_or(0.0, |(_, _, pdf)| pdf)
+    }
+
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        fresnel_conductor(1.0, self.eta, self.k)
+    }
+}
+
+/// Rough glass, which both reflects and refracts through its microfacets.
+///
+/// Like [`Dielectric`](crate::Dielectric), radiance isn't scaled by the
+/// squared ratio of the indices of refraction as it crosses the surface,
+/// which cancels out for light that leaves the way it came in.
+#[derive(Debug, PartialEq)]
+pub struct RoughDielectric {
+    ref_idx: f64,
+    distribution: Ggx,
+}
+
+impl RoughDielectric {
+    pub fn new(ref_idx: f64, distribution: Ggx) -> Self {
+        Self {
+            ref_idx,
+            distribution,
+        }
+    }
+
+    /// The shading frame on the side `r_in` arrives from, with the index of
+    /// refraction of the other side relative to it.
+    fn frame(&self, r_in: &Ray, rec: &HitRecord) -> (Frame, f64) {
+        if r_in.direction().dot(rec.normal) > 0.0 {
+            (Frame::new(-rec.normal), 1.0 / self.ref_idx)
+        } else {
+            (Frame::new(rec.normal), self.ref_idx)
+        }
+    }
+
+    /// The BSDF times the cosine and the density of sampling it, together.
+    fn eval_pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> (f64, f64) {
+        let (frame, eta) = self.frame(r_in, rec);
+        let wo = frame.to_local(-r_in.direction().unitize());
+        let wi = frame.to_local(scattered.direction().unitize());
+        self.distribution.dielectric(wo, wi, eta)
+    }
+}
+
+impl Material for RoughDielectric {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, rng: &mut Rng) -> Option<(Vec3, Ray)> {
+        let (frame, eta) = self.frame(r_in, rec);
+        let wo = frame.to_local(-r_in.direction().unitize());
+        if wo.z() <= 0.0 {
+            return None;
+        }
+        // choosing between reflection and refraction by the Fresnel
+        // reflectance leaves only the masking-shadowing in the weight
+        let ggx = &self.distribution;
+        let wi = ggx.sample_dielectric(wo, eta, rng)?;
+        Some((
+            Vec3::ones() * (ggx.g2(wo, wi) / ggx.g1(wo)),
+            r_in.spawn(rec.point, frame.to_world(wi)),
+        ))
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Vec3 {
+        Vec3::ones() * self.eval_pdf(r_in, rec, scattered).0
+    }
+
+    fn pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        self.eval_pdf(r_in, rec, scattered).1
+    }
+
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        Vec3::ones()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fresnel_conductor, fresnel_dielectric, Conductor, Element, Ggx, RoughDielectric};
+    use crate::{
+        utils::{self, seeded_rng},
+        HitRecord, Material, Ray, Vec3,
+    };
+    use std::f64::consts::PI;
+
+    fn record(material: &dyn Material) -> HitRecord<'_> {
+        HitRecord {
+            t: 1.0,
+            point: Vec3::zeros(),
+            normal: vec3![0, 0, 1],
+            u: 0.0,
+            v: 0.0,
+            barycentrics: None,
+            material,
+        }
+    }
+
+    #[test]
+    fn test_fresnel() {
+        // at normal incidence both reduce to ((n - 1)^2 + k^2) / ((n + 1)^2 + k^2)
+        assert!((fresnel_dielectric(1.0, 1.5) - 0.04).abs() < 1e-12);
+        let (eta, k) = Element::Gold.ior();
+        let r = fresnel_conductor(1.0, eta, k);
+        let expected =
+            ((eta.x() - 1.0).powi(2) + k.x().powi(2)) / ((eta.x() + 1.0).powi(2) + k.x().powi(2));
+        assert!((r.x() - expected).abs() < 1e-12);
+        // everything reflects at grazing angles and beyond the critical angle
+        assert!((fresnel_conductor(0.0, eta, k) - Vec3::ones()).norm() < 1e-9);
+        assert_eq!(fresnel_dielectric(0.5, 1.0 / 1.5), 1.0);
+    }
+
+    #[test]
+    fn test_ggx_normalized() {
+        // projected facet areas add up to the macrosurface's, from any side
+        let rng = &mut seeded_rng(0);
+        let ggx = Ggx::new(0.3, 0.6);
+        let wo = vec3![0.5, -0.3, 0.7].unitize();
+        let (n, mut projected, mut visible) = (200_000, 0.0, 0.0);
+        for _ in 0..n {
+            let h = utils::random_unit_vector(rng);
+            projected += ggx.d(h) * h.z().max(0.0) * 4.0 * PI;
+            visible += ggx.pdf_visible(wo, h) * 4.0 * PI;
+        }
+        assert!((projected / n as f64 - 1.0).abs() < 0.05);
+        assert!((visible / n as f64 - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_sampling_matches_eval() {
+        let rng = &mut seeded_rng(1);
+        let r_in = Ray::new(vec3![-1, 0.3, 1], vec3![1, -0.3, -1]);
+        let ggx = Ggx::from_roughness(0.5, 0.8);
+        let gold = Conductor::preset(Element::Gold, ggx);
+        let glass = RoughDielectric::new(1.5, ggx);
+        for material in [&gold as &dyn Material, &glass] {
+            let rec = record(material);
+            let (mut total, mut count) = (Vec3::zeros(), 0);
+            for _ in 0..10_000 {
+                if let Some((weight, scattered)) = material.scatter(&r_in, &rec, rng) {
+                    let pdf = material.pdf(&r_in, &rec, &scattered);
+                    let expected = material.eval(&r_in, &rec, &scattered) / pdf;
+                    assert!((weight - expected).norm() < 1e-6 * (1.0 + expected.norm()));
+                    total += weight;
+                    count += 1;
+                }
+            }
+            // single scattering loses a little energy, but never gains any
+            let mean = total / 10_000.0;
+            assert!(count > 8_000);
+            assert!(mean.x() <= 1.0 && mean.y() <= 1.0 && mean.z() <= 1.0);
+        }
+    }
+
+    #[test]
+    fn test_rough_dielectric_furnace() {
+        // nearly everything is either reflected or transmitted when smooth
+        let rng = &mut seeded_rng(2);
+        let glass = RoughDielectric::new(1.5, Ggx::from_roughness(0.1, 0.0));
+        let rec = record(&glass);
+        let n = 10_000;
+        for direction in [vec3![0.3, 0, -1], vec3![0.3, 0, 1]] {
+            let r_in = Ray::new(Vec3::zeros(), direction);
+            let total = (0..n)
+                .filter_map(|_| glass.scatter(&r_in, &rec, rng))
+                .fold(Vec3::zeros(), |total, (weight, _)| total + weight);
+            assert!((total.x() / n as f64 - 1.0).abs() < 0.01);
+        }
+    }
+}
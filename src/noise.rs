@@ -0,0 +1,287 @@
+//! Seeded gradient noise for procedural textures.
+
+use crate::{utils::Rng, Vec3};
+use rand::seq::SliceRandom;
+use std::sync::Arc;
+
+/// A smooth, repeatable random function of position.
+pub trait Noise {
+    /// Noise at `point`, roughly within `[-1, 1]` and zero on average.
+    fn noise(&self, point: Vec3) -> f64;
+
+    /// Fractal Brownian motion: `octaves` layers of noise, each at twice the
+    /// frequency and half the amplitude of the last, normalized by the total
+    /// amplitude so that it stays within the range of a single octave.
+    fn fbm(&self, point: Vec3, octaves: u32) -> f64 {
+        octaves_of(self, point, octaves, |noise| noise)
+    }
+
+    /// Like [`Noise::fbm`] but summing absolute values, which creases the
+    /// result wherever an octave crosses zero. Lies within `[0, 1]`.
+    fn turbulence(&self, point: Vec3, octaves: u32) -> f64 {
+        octaves_of(self, point, octaves, f64::abs)
+    }
+}
+
+fn octaves_of<N>(noise: &N, point: Vec3, octaves: u32, f: impl Fn(f64) -> f64) -> f64
+where
+    N: Noise + ?Sized,
+{
+    let (mut sum, mut total) = (0.0, 0.0);
+    let (mut frequency, mut amplitude) = (1.0, 1.0);
+    for _ in 0..octaves {
+        sum += amplitude * f(noise.noise(frequency * point));
+        total += amplitude;
+        frequency *= 2.0;
+        amplitude *= 0.5;
+    }
+    if total > 0.0 {
+        sum / total
+    } else {
+        0.0
+    }
+}
+
+impl<T> Noise for Arc<T>
+where
+    T: Noise + ?Sized,
+{
+    fn noise(&self, point: Vec3) -> f64 {
+        self.as_ref().noise(point)
+    }
+}
+
+/// A random permutation of `0..256`, repeated twice so that hashes of
+/// neighbouring lattice points can be looked up without wrapping.
+fn permutation(rng: &mut Rng) -> Vec<u8> {
+    let mut permutation = (0..=u8::MAX).collect::<Vec<_>>();
+    permutation.shuffle(rng);
+    permutation.extend_from_within(..);
+    permutation
+}
+
+/// Hash a lattice point into `0..256`.
+fn hash(permutation: &[u8], i: i64, j: i64, k: i64) -> usize {
+    let index = |hash: usize, i: i64| usize::from(permutation[hash + (i & 255) as usize]);
+    index(index(index(0, i), j), k)
+}
+
+/// How Perlin noise blends between lattice points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Smoothing {
+    /// Plain trilinear interpolation, which shows the lattice as creases.
+    Trilinear,
+    /// Trilinear interpolation with Hermite cubic weights `3t² - 2t³`,
+    /// which is smooth across lattice cells.
+    Hermite,
+}
+
+impl Smoothing {
+    fn weight(self, t: f64) -> f64 {
+        match self {
+            Smoothing::Trilinear => t,
+            Smoothing::Hermite => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
+/// Perlin gradient noise, with random unit gradients on the integer lattice.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Perlin {
+    permutation: Vec<u8>,
+    gradients: Vec<Vec3>,
+    smoothing: Smoothing,
+}
+
+impl Perlin {
+    pub fn new(smoothing: Smoothing, rng: &mut Rng) -> Self {
+        let gradients = (0..256)
+            .map(|_| crate::utils::random_unit_vector(rng))
+            .collect();
+        Self {
+            permutation: permutation(rng),
+            gradients,
+            smoothing,
+        }
+    }
+}
+
+impl Noise for Perlin {
+    fn noise(&self, point: Vec3) -> f64 {
+        let cell = point.into_array().map(f64::floor);
+        let [x, y, z] = [
+            point.x() - cell[0],
+            point.y() - cell[1],
+            point.z() - cell[2],
+        ];
+        let [i, j, k] = cell.map(|c| c as i64);
+        let [wx, wy, wz] = [x, y, z].map(|t| self.smoothing.weight(t));
+
+        let mut sum = 0.0;
+        for corner in 0..8 {
+            let [di, dj, dk] = [corner & 1, corner >> 1 & 1, corner >> 2 & 1];
+            let gradient = self.gradients[hash(&self.permutation, i + di, j + dj, k + dk)];
+            let offset = vec3![x - di as f64, y - dj as f64, z - dk as f64];
+            let weight = |w: f64, d| if d == 1 { w } else { 1.0 - w };
+            sum += weight(wx, di) * weight(wy, dj) * weight(wz, dk) * gradient.dot(offset);
+        }
+        sum
+    }
+}
+
+/// The midpoints of the edges of a cube, used as simplex gradients.
+const EDGES: [[f64; 3]; 12] = [
+    [1.0, 1.0, 0.0],
+    [-1.0, 1.0, 0.0],
+    [1.0, -1.0, 0.0],
+    [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0],
+    [-1.0, 0.0, 1.0],
+    [1.0, 0.0, -1.0],
+    [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0],
+    [0.0, -1.0, 1.0],
+    [0.0, 1.0, -1.0],
+    [0.0, -1.0, -1.0],
+];
+
+/// Simplex noise, which sums contributions from the four corners of the
+/// tetrahedron containing a point instead of the eight of a cube, and has no
+/// axis-aligned artifacts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Simplex {
+    permutation: Vec<u8>,
+}
+
+impl Simplex {
+    pub fn new(rng: &mut Rng) -> Self {
+        Self {
+            permutation: permutation(rng),
+        }
+    }
+}
+
+impl Noise for Simplex {
+    fn noise(&self, point: Vec3) -> f64 {
+        // skew space so that the tetrahedra become cube halves
+        const SKEW: f64 = 1.0 / 3.0;
+        const UNSKEW: f64 = 1.0 / 6.0;
+        let skew = (point.x() + point.y() + point.z()) * SKEW;
+        let cell = point.into_array().map(|c| (c + skew).floor());
+        let unskew = (cell[0] + cell[1] + cell[2]) * UNSKEW;
+        let origin = Vec3::from(cell) - unskew;
+        let first = point - origin;
+        let [i, j, k] = cell.map(|c| c as i64);
+
+        // walk from the origin to the far corner along the largest offsets
+        let [x, y, z] = first.into_array();
+        let (second, third) = match (x >= y, y >= z, x >= z) {
+            (true, true, _) => ([1, 0, 0], [1, 1, 0]),
+            (true, false, true) => ([1, 0, 0], [1, 0, 1]),
+            (true, false, false) => ([0, 0, 1], [1, 0, 1]),
+            (false, false, _) => ([0, 0, 1], [0, 1, 1]),
+            (false, true, false) => ([0, 1, 0], [0, 1, 1]),
+            (false, true, true) => ([0, 1, 0], [1, 1, 0]),
+        };
+        let corners = [[0, 0, 0], second, third, [1, 1, 1]];
+
+        corners
+            .iter()
+            .enumerate()
+            .map(|(n, &[di, dj, dk])| {
+                let offset = first - vec3![di, dj, dk] + n as f64 * UNSKEW;
+                let falloff = 0.6 - offset.norm2();
+                if falloff <= 0.0 {
+                    return 0.0;
+                }
+                let corner = hash(
+                    &self.permutation,
+                    i + i64::from(di),
+                    j + i64::from(dj),
+                    k + i64::from(dk),
+                );
+                let gradient = EDGES[corner % 12];
+                falloff.powi(4) * offset.dot(gradient)
+            })
+            .sum::<f64>()
+            // scale the peaks to about one
+            * 32.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Noise, Perlin, Simplex, Smoothing};
+    use crate::utils::{randvec, seeded_rng};
+    use crate::Vec3;
+
+    fn noises(seed: u64) -> Vec<Box<dyn Noise>> {
+        let rng = &mut seeded_rng(seed);
+        vec![
+            Box::new(Perlin::new(Smoothing::Trilinear, rng)),
+            Box::new(Perlin::new(Smoothing::Hermite, rng)),
+            Box::new(Simplex::new(rng)),
+        ]
+    }
+
+    #[test]
+    fn test_noise_is_bounded_and_centred() {
+        let rng = &mut seeded_rng(1);
+        for noise in noises(0) {
+            let values = (0..10_000)
+                .map(|_| noise.noise(20.0 * randvec(rng) - 10.0))
+                .collect::<Vec<_>>();
+            assert!(values.iter().all(|value| value.abs() <= 1.0));
+            let mean = values.iter().sum::<f64>() / values.len() as f64;
+            assert!(mean.abs() < 0.05, "{}", mean);
+            let max = values
+                .iter()
+                .fold(0.0_f64, |max, value| max.max(value.abs()));
+            assert!(max > 0.4, "{}", max);
+        }
+    }
+
+    #[test]
+    fn test_noise_is_continuous_and_seeded() {
+        let rng = &mut seeded_rng(1);
+        for (noise, other) in noises(0).into_iter().zip(noises(1)) {
+            for _ in 0..100 {
+                let point = 20.0 * randvec(rng) - 10.0;
+                let step = 1e-6 * (randvec(rng) - 0.5);
+                assert!((noise.noise(point) - noise.noise(point + step)).abs() < 1e-4);
+            }
+            let differs = (0..100)
+                .map(|_| 10.0 * randvec(rng))
+                .any(|point| noise.noise(point) != other.noise(point));
+            assert!(differs);
+        }
+        let [a, b] = [noises(3), noises(3)];
+        let point = vec3![0.3, 1.7, -2.2];
+        assert_eq!(a[2].noise(point), b[2].noise(point));
+    }
+
+    #[test]
+    fn test_perlin_vanishes_on_lattice() {
+        let perlin = Perlin::new(Smoothing::Hermite, &mut seeded_rng(0));
+        assert_eq!(perlin.noise(vec3![3, -2, 7]), 0.0);
+        assert_eq!(perlin.noise(Vec3::zeros()), 0.0);
+    }
+
+    #[test]
+    fn test_octaves() {
+        let perlin = Perlin::new(Smoothing::Hermite, &mut seeded_rng(0));
+        let point = vec3![0.3, 1.7, -2.2];
+        assert_eq!(perlin.fbm(point, 1), perlin.noise(point));
+        assert_eq!(perlin.turbulence(point, 1), perlin.noise(point).abs());
+        let rng = &mut seeded_rng(1);
+        for _ in 0..1000 {
+            let point = 10.0 * randvec(rng);
+            assert!(perlin.fbm(point, 6).abs() <= 1.0);
+            assert!((0.0..=1.0).contains(&perlin.turbulence(point, 6)));
+        }
+        // the same through a shared pointer
+        let shared: std::sync::Arc<dyn Noise> = std::sync::Arc::new(perlin.clone());
+        assert_eq!(shared.fbm(point, 5), perlin.fbm(point, 5));
+    }
+}
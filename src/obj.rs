@@ -0,0 +1,402 @@
+//! Wavefront OBJ and MTL loading.
+
+use crate::{Bvh, Dielectric, DiffuseLight, Lambertian, Material, Metal, TriangleMesh, Vec3};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+    str::{FromStr, SplitWhitespace},
+    sync::Arc,
+};
+
+/// The subset of an MTL material that maps onto the tracer's materials.
+#[derive(Debug, Clone, PartialEq)]
+struct MtlMaterial {
+    kd: Vec3,
+    ks: Vec3,
+    ke: Vec3,
+    ns: f64,
+    ni: Option<f64>,
+    d: f64,
+}
+
+impl Default for MtlMaterial {
+    fn default() -> Self {
+        Self {
+            kd: vec3![0.8, 0.8, 0.8],
+            ks: Vec3::zeros(),
+            ke: Vec3::zeros(),
+            ns: 0.0,
+            ni: None,
+            d: 1.0,
+        }
+    }
+}
+
+fn max_component(v: Vec3) -> f64 {
+    v.x().max(v.y()).max(v.z())
+}
+
+impl MtlMaterial {
+    fn into_material(self) -> Arc<dyn Material + Send + Sync> {
+        if max_component(self.ke) > 0.0 {
+            Arc::new(DiffuseLight::new(self.ke))
+        } else if self.d < 1.0 {
+            Arc::new(Dielectric::new(self.ni.unwrap_or(1.5)))
+        } else if max_component(self.ks) > max_component(self.kd) {
+            // Blender writes the specular exponent as 1000 * (1 - roughness)^2
+            let roughness = 1.0 - (self.ns.max(0.0) / 1000.0).min(1.0).sqrt();
+            Arc::new(Metal::new(self.ks, roughness))
+        } else {
+            Arc::new(Lambertian::new(self.kd))
+        }
+    }
+}
+
+fn parse_next<T>(tokens: &mut SplitWhitespace) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let token = tokens.next().ok_or_else(|| anyhow!("Missing value"))?;
+    token
+        .parse()
+        .with_context(|| format!("Invalid value: {:?}", token))
+}
+
+fn parse_vec3(tokens: &mut SplitWhitespace) -> Result<Vec3> {
+    Ok(vec3![
+        parse_next::<f64>(tokens)?,
+        parse_next::<f64>(tokens)?,
+        parse_next::<f64>(tokens)?
+    ])
+}
+
+fn parse_mtl(reader: impl BufRead, path: &Path) -> Result<HashMap<String, MtlMaterial>> {
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlMaterial)> = None;
+
+    for (line_index, line) in reader.lines().enumerate() {
+        let line = line.with_context(|| format!("Unable to read {}", path.display()))?;
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) if !keyword.starts_with('#') => keyword,
+            _ => continue,
+        };
+
+        let result = (|| -> Result<()> {
+            if keyword == "newmtl" {
+                let name = tokens
+                    .next()
+                    .ok_or_else(|| anyhow!("Missing material name"))?;
+                if let Some((name, material)) = current.replace((name.into(), Default::default())) {
+                    materials.insert(name, material);
+                }
+                return Ok(());
+            }
+
+            let (_, material) = match current.as_mut() {
+                Some(current) => current,
+                None if ["Kd", "Ks", "Ke", "Ns", "Ni", "d", "Tr"].contains(&keyword) => {
+                    bail!("{} before newmtl", keyword)
+                }
+                None => return Ok(()),
+            };
+            match keyword {
+                "Kd" => material.kd = parse_vec3(&mut tokens)?,
+                "Ks" => material.ks = parse_vec3(&mut tokens)?,
+                "Ke" => material.ke = parse_vec3(&mut tokens)?,
+                "Ns" => material.ns = parse_next(&mut tokens)?,
+                "Ni" => material.ni = Some(parse_next(&mut tokens)?),
+                "d" => material.d = parse_next(&mut tokens)?,
+                "Tr" => material.d = 1.0 - parse_next::<f64>(&mut tokens)?,
+                _ => {}
+            }
+            Ok(())
+        })();
+        result.with_context(|| format!("Unable to parse {}:{}", path.display(), line_index + 1))?;
+    }
+
+    if let Some((name, material)) = current {
+        materials.insert(name, material);
+    }
+    Ok(materials)
+}
+
+/// Resolve a 1-based, possibly negative (relative) OBJ index.
+fn resolve_index(token: &str, count: usize) -> Result<usize> {
+    let index: i64 = token
+        .parse()
+        .with_context(|| format!("Invalid index: {:?}", token))?;
+    let resolved = match index {
+        0 => bail!("Index 0 is invalid, OBJ indices start at 1"),
+        index if index > 0 => index - 1,
+        index => count as i64 + index,
+    };
+    if resolved < 0 || resolved >= count as i64 {
+        bail!("Index {} is out of range for {} elements", index, count);
+    }
+    Ok(resolved as usize)
+}
+
+type Corner = (usize, Option<usize>, Option<usize>);
+
+fn parse_corner(token: &str, nv: usize, nvt: usize, nvn: usize) -> Result<Corner> {
+    let mut parts = token.split('/');
+    let v = resolve_index(parts.next().unwrap_or(""), nv)?;
+    let vt = match parts.next() {
+        Some("") | None => None,
+        Some(part) => Some(resolve_index(part, nvt)?),
+    };
+    let vn = match parts.next() {
+        Some("") | None => None,
+        Some(part) => Some(resolve_index(part, nvn)?),
+    };
+    Ok((v, vt, vn))
+}
+
+/// Faces sharing a group and material, which become a single mesh.
+#[derive(Default)]
+struct MeshBuilder {
+    corners: HashMap<Corner, usize>,
+    vertices: Vec<Corner>,
+    indices: Vec<[usize; 3]>,
+}
+
+impl MeshBuilder {
+    fn vertex(&mut self, corner: Corner) -> usize {
+        let vertices = &mut self.vertices;
+        *self.corners.entry(corner).or_insert_with(|| {
+            vertices.push(corner);
+            vertices.len() - 1
+        })
+    }
+
+    fn build(
+        self,
+        positions: &[Vec3],
+        uvs: &[[f64; 2]],
+        normals: &[Vec3],
+        material: Arc<dyn Material + Send + Sync>,
+    ) -> TriangleMesh {
+        // attributes are only used when every vertex of the mesh has them
+        let mesh_uvs = self
+            .vertices
+            .iter()
+            .map(|&(_, vt, _)| vt.map(|vt| uvs[vt]))
+            .collect::<Option<Vec<_>>>();
+        let mesh_normals = self
+            .vertices
+            .iter()
+            .map(|&(_, _, vn)| vn.map(|vn| normals[vn]))
+            .collect::<Option<Vec<_>>>();
+        TriangleMesh::new(
+            self.vertices
+                .iter()
+                .map(|&(v, _, _)| positions[v])
+                .collect(),
+            self.indices,
+            mesh_normals,
+            mesh_uvs,
+            material,
+        )
+    }
+}
+
+/// The meshes of an OBJ file.
+pub struct Model {
+    pub meshes: Bvh<Arc<TriangleMesh>>,
+    /// The meshes with emissive materials, which are also in `meshes`, to
+    /// sample as lights.
+    pub lights: Vec<Arc<TriangleMesh>>,
+}
+
+fn parse_obj(reader: impl BufRead, path: &Path) -> Result<Model> {
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut positions = Vec::new();
+    let mut uvs = Vec::new();
+    let mut normals = Vec::new();
+    let mut library = HashMap::new();
+    let mut group = String::new();
+    let mut material_name = String::new();
+    let mut builders = BTreeMap::<(String, String), MeshBuilder>::new();
+
+    for (line_index, line) in reader.lines().enumerate() {
+        let line = line.with_context(|| format!("Unable to read {}", path.display()))?;
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) if !keyword.starts_with('#') => keyword,
+            _ => continue,
+        };
+
+        let result = (|| -> Result<()> {
+            match keyword {
+                "v" => positions.push(parse_vec3(&mut tokens)?),
+                "vn" => normals.push(parse_vec3(&mut tokens)?),
+                "vt" => {
+                    let u = parse_next(&mut tokens)?;
+                    let v = tokens.next().map_or(Ok(0.0), str::parse)?;
+                    uvs.push([u, v]);
+                }
+                "f" => {
+                    let corners = tokens
+                        .map(|token| parse_corner(token, positions.len(), uvs.len(), normals.len()))
+                        .collect::<Result<Vec<_>>>()?;
+                    if corners.len() < 3 {
+                        bail!("Face has {} vertices, expected at least 3", corners.len());
+                    }
+                    let builder = builders
+                        .entry((group.clone(), material_name.clone()))
+                        .or_default();
+                    let first = builder.vertex(corners[0]);
+                    // triangulate the polygon as a fan around its first vertex
+                    for pair in corners[1..].windows(2) {
+                        let face = [first, builder.vertex(pair[0]), builder.vertex(pair[1])];
+                        builder.indices.push(face);
+                    }
+                }
+                "g" | "o" => group = tokens.collect::<Vec<_>>().join(" "),
+                "usemtl" => {
+                    material_name = tokens
+                        .next()
+                        .ok_or_else(|| anyhow!("Missing material name"))?
+                        .into();
+                }
+                "mtllib" => {
+                    for name in tokens {
+                        let mtl_path = base_dir.join(name);
+                        let file = File::open(&mtl_path).with_context(|| {
+                            format!("Unable to open material library {}", mtl_path.display())
+                        })?;
+                        library.extend(parse_mtl(BufReader::new(file), &mtl_path)?);
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        })();
+        result.with_context(|| format!("Unable to parse {}:{}", path.display(), line_index + 1))?;
+    }
+
+    let mut materials = HashMap::new();
+    let mut meshes = Vec::with_capacity(builders.len());
+    let mut lights = Vec::new();
+    for ((_, name), builder) in builders {
+        let (material, emissive) = materials
+            .entry(name)
+            .or_insert_with_key(|name| {
+                let mtl = library.get(name).cloned().unwrap_or_default();
+                let emissive = max_component(mtl.ke) > 0.0;
+                (mtl.into_material(), emissive)
+            })
+            .clone();
+        let mesh = Arc::new(builder.build(&positions, &uvs, &normals, material));
+        if emissive {
+            lights.push(Arc::clone(&mesh));
+        }
+        meshes.push(mesh);
+    }
+    Ok(Model {
+        meshes: Bvh::new(meshes),
+        lights,
+    })
+}
+
+/// Load an OBJ file, along with any MTL libraries it references.
+///
+/// Each group and material combination becomes a [`TriangleMesh`] sharing one
+/// material, and polygons are triangulated as fans.
+pub fn load_obj(path: impl AsRef<Path>) -> Result<Model> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("Unable to open {}", path.display()))?;
+    parse_obj(BufReader::new(file), path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_obj, parse_mtl, parse_obj, resolve_index, MtlMaterial};
+    use crate::{Hittable, Ray};
+    use std::path::Path;
+
+    #[test]
+    fn test_resolve_index() {
+        assert_eq!(resolve_index("1", 3).unwrap(), 0);
+        assert_eq!(resolve_index("-1", 3).unwrap(), 2);
+        assert!(resolve_index("0", 3).is_err());
+        assert!(resolve_index("4", 3).is_err());
+        assert!(resolve_index("-4", 3).is_err());
+    }
+
+    #[test]
+    fn test_parse_mtl() {
+        let mtl = "# a comment\nnewmtl red\nKd 1 0 0\nnewmtl glass\nNi 1.45\nd 0.1\n";
+        let materials = parse_mtl(mtl.as_bytes(), Path::new("test.mtl")).unwrap();
+        assert_eq!(materials["red"].kd, vec3![1, 0, 0]);
+        assert_eq!(
+            materials["glass"],
+            MtlMaterial {
+                ni: Some(1.45),
+                d: 0.1,
+                ..Default::default()
+            }
+        );
+
+        let error =
+            parse_mtl("newmtl x\nKd 1 zero 0\n".as_bytes(), Path::new("test.mtl")).unwrap_err();
+        assert_eq!(error.to_string(), "Unable to parse test.mtl:2");
+    }
+
+    #[test]
+    fn test_parse_obj() {
+        // a unit quad written as one polygon using relative indices
+        let obj =
+            "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvn 0 0 1\ng quad\nf -4//1 -3//1 -2//1 -1//1\n";
+        let mesh = parse_obj(obj.as_bytes(), Path::new("quad.obj"))
+            .unwrap()
+            .meshes;
+        for &(x, y) in &[(0.25, 0.75), (0.75, 0.25)] {
+            let rec = mesh
+                .hit(Ray::new(vec3![x, y, 1], vec3![0, 0, -1]), 0.0, f64::MAX)
+                .unwrap();
+            assert_eq!(rec.t, 1.0);
+            assert_eq!(rec.normal, vec3![0, 0, 1]);
+        }
+
+        let error = parse_obj("v 0 0 0\nf 1 2 3\n".as_bytes(), Path::new("bad.obj"))
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "Unable to parse bad.obj:2");
+    }
+
+    #[test]
+    fn test_load_obj_with_mtllib() {
+        let dir = std::env::temp_dir().join(format!("raytracer-obj-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("scene.mtl"),
+            "newmtl gold\nKd 0 0 0\nKs 1 0.8 0.3\nNs 900\nnewmtl lamp\nKe 5 5 5\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("scene.obj"),
+            "mtllib scene.mtl\nv -1 -1 0\nv 1 -1 0\nv 0 1 0\nusemtl gold\nf 1 2 3\n\
+             v -1 -1 2\nv 1 -1 2\nv 0 1 2\nusemtl lamp\nf 4 5 6\n",
+        )
+        .unwrap();
+
+        let model = load_obj(dir.join("scene.obj")).unwrap();
+        assert!(model
+            .meshes
+            .hit(Ray::new(vec3![0, 0, 1], vec3![0, 0, -1]), 0.0, f64::MAX)
+            .is_some());
+        // only the lamp is sampled as a light
+        assert_eq!(model.lights.len(), 1);
+        assert!(model.lights[0]
+            .hit(Ray::new(vec3![0, 0, 3], vec3![0, 0, -1]), 0.0, f64::MAX)
+            .is_some());
+        assert!(load_obj(dir.join("missing.obj")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,818 @@
+//! Writing rendered images and their auxiliary layers to disk, and reading
+//! high dynamic range images back.
+
+use crate::{tonemap::ToneMap, Vec3};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    fs::File,
+    io::{BufWriter, Cursor, Write},
+    path::Path,
+    str::FromStr,
+};
+
+/// An image file format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Binary (P6) PPM.
+    Ppm,
+    Png,
+    /// Radiance RGBE, storing unclamped linear radiance.
+    Hdr,
+    /// Portable float map, storing unclamped linear radiance.
+    Pfm,
+    /// OpenEXR, storing linear radiance along with any auxiliary layers.
+    Exr,
+}
+
+impl Format {
+    pub const NAMES: &'static [&'static str] = &["ppm", "png", "hdr", "pfm", "exr"];
+
+    /// The format implied by the extension of `path`, if any.
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        path.as_ref().extension()?.to_str()?.parse().ok()
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "ppm" => Format::Ppm,
+            "png" => Format::Png,
+            "hdr" => Format::Hdr,
+            "pfm" => Format::Pfm,
+            "exr" => Format::Exr,
+            _ => bail!("Unknown image format `{}`", s),
+        })
+    }
+}
+
+/// Linear radiance for each pixel, in row-major order from the top left.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, pixels: Vec<Vec3>) -> Self {
+        assert_eq!(
+            pixels.len(),
+            width * height,
+            "expected one pixel per width * height"
+        );
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[Vec3] {
+        &self.pixels
+    }
+
+    /// Read a Radiance RGBE (`.hdr`) or portable float map (`.pfm`) image,
+    /// by its extension.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Unable to read image {}", path.display()))?;
+        match Format::from_path(path) {
+            Some(Format::Hdr) => Self::read_hdr(&bytes),
+            Some(Format::Pfm) => Self::read_pfm(&bytes),
+            _ => bail!("Expected a .hdr or .pfm image"),
+        }
+        .with_context(|| format!("Invalid image {}", path.display()))
+    }
+
+    /// Parse a Radiance RGBE image, flat or run length encoded, with rows
+    /// from the top down.
+    pub fn read_hdr(bytes: &[u8]) -> Result<Self> {
+        let mut lines = bytes.split(|&byte| byte == b'\n');
+        let mut header_len = 0;
+        let mut next_line = || {
+            let line = lines.next()?;
+            header_len += line.len() + 1;
+            Some(line)
+        };
+        if !next_line().is_some_and(|line| line.starts_with(b"#?")) {
+            bail!("Expected a header starting with `#?`");
+        }
+        // variables up to a blank line, then the resolution
+        loop {
+            match next_line() {
+                Some(b"") => break,
+                Some(line) if line.starts_with(b"FORMAT=") && line != b"FORMAT=32-bit_rle_rgbe" => {
+                    bail!(
+                        "Expected RGBE pixels, got {}",
+                        String::from_utf8_lossy(&line[7..])
+                    )
+                }
+                Some(_) => {}
+                None => bail!("Unexpected end of header"),
+            }
+        }
+        let resolution = next_line().map(String::from_utf8_lossy).unwrap_or_default();
+        let (height, width) = match resolution.split_whitespace().collect::<Vec<_>>()[..] {
+            ["-Y", height, "+X", width] => (height.parse::<usize>()?, width.parse::<usize>()?),
+            _ => bail!(
+                "Expected a resolution of `-Y height +X width`, got `{}`",
+                resolution
+            ),
+        };
+
+        if width == 0 || height == 0 {
+            return Ok(Self::new(width, height, Vec::new()));
+        }
+        let mut data = bytes.get(header_len..).unwrap_or_default();
+        // scanlines that can't be run length encoded take four bytes a
+        // pixel, so the file bounds their width, and the pixels grow as
+        // they're decoded rather than as the header claims
+        if !(8..0x8000).contains(&width) && data.len() / 4 < width {
+            bail!("Unexpected end of pixels");
+        }
+        let mut pixels = Vec::new();
+        let mut scanline = vec![[0u8; 4]; width];
+        for _ in 0..height {
+            data = decode_scanline(data, &mut scanline)?;
+            pixels.extend(scanline.iter().map(|&rgbe| from_rgbe(rgbe)));
+        }
+        Ok(Self::new(width, height, pixels))
+    }
+
+    /// Parse a colour or greyscale portable float map, of either endianness.
+    pub fn read_pfm(bytes: &[u8]) -> Result<Self> {
+        // the magic number, width, height and scale, each followed by a
+        // single whitespace character
+        let mut tokens = Vec::new();
+        let mut start = 0;
+        for (i, byte) in bytes.iter().enumerate() {
+            if tokens.len() == 4 {
+                break;
+            }
+            if byte.is_ascii_whitespace() {
+                if i > start {
+                    tokens.push(String::from_utf8_lossy(&bytes[start..i]));
+                }
+                start = i + 1;
+            }
+        }
+        let (channels, width, height, scale) = match &tokens[..] {
+            [magic, width, height, scale] => (
+                match magic.as_ref() {
+                    "PF" => 3,
+                    "Pf" => 1,
+                    _ => bail!("Expected a header starting with `PF` or `Pf`"),
+                },
+                width.parse::<usize>()?,
+                height.parse::<usize>()?,
+                scale.parse::<f64>()?,
+            ),
+            _ => bail!("Unexpected end of header"),
+        };
+
+        let data = &bytes[start..];
+        let expected = width
+            .checked_mul(height)
+            .and_then(|n| n.checked_mul(channels))
+            .with_context(|| format!("Image size {}x{} is too large", width, height))?;
+        if expected.checked_mul(4) != Some(data.len()) {
+            bail!(
+                "Expected {} values for {}x{} pixels, found {} bytes",
+                expected,
+                width,
+                height,
+                data.len()
+            );
+        }
+        let values = data
+            .chunks_exact(4)
+            .map(|chunk| {
+                let bytes = [chunk[0], chunk[1], chunk[2], chunk[3]];
+                // a negative scale means little endian
+                f64::from(if scale < 0.0 {
+                    f32::from_le_bytes(bytes)
+                } else {
+                    f32::from_be_bytes(bytes)
+                })
+            })
+            .collect::<Vec<_>>();
+        // rows go from the bottom up
+        let pixels = values
+            .chunks(width.max(1) * channels)
+            .rev()
+            .flat_map(|row| row.chunks(channels))
+            .map(|pixel| match *pixel {
+                [grey] => Vec3::from([grey; 3]),
+                [r, g, b] => vec3![r, g, b],
+                _ => unreachable!("chunks have one value per channel"),
+            })
+            .collect();
+        Ok(Self::new(width, height, pixels))
+    }
+
+    /// Write the image to `path` in `format`. `tonemap` only applies to 8-bit
+    /// formats.
+    pub fn save(&self, path: impl AsRef<Path>, format: Format, tonemap: &ToneMap) -> Result<()> {
+        save(path.as_ref(), |writer| self.write(writer, format, tonemap))
+    }
+
+    pub fn write(&self, writer: impl Write, format: Format, tonemap: &ToneMap) -> Result<()> {
+        match format {
+            Format::Ppm => self.write_ppm(writer, tonemap),
+            Format::Png => self.write_png(writer, tonemap),
+            Format::Hdr => self.write_hdr(writer),
+            Format::Pfm => self.write_pfm(writer),
+            Format::Exr => write_exr(
+                writer,
+                self.width,
+                self.height,
+                &[Layer {
+                    name: String::new(),
+                    data: LayerData::Color(self.pixels.clone()),
+                    half: false,
+                }],
+            ),
+        }
+    }
+
+    fn write_ppm(&self, mut writer: impl Write, tonemap: &ToneMap) -> Result<()> {
+        write!(writer, "P6\n{} {}\n255\n", self.width, self.height)?;
+        writer.write_all(&tonemap.apply(self))?;
+        Ok(())
+    }
+
+    fn write_png(&self, writer: impl Write, tonemap: &ToneMap) -> Result<()> {
+        let mut encoder = png::Encoder::new(writer, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&tonemap.apply(self))?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    fn write_hdr(&self, mut writer: impl Write) -> Result<()> {
+        write!(
+            writer,
+            "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+            self.height, self.width
+        )?;
+        let mut scanline = Vec::with_capacity(4 * self.width);
+        for row in self.pixels.chunks(self.width.max(1)) {
+            let rgbe = row.iter().map(|&pixel| rgbe(pixel)).collect::<Vec<_>>();
+            scanline.clear();
+            encode_scanline(&rgbe, &mut scanline);
+            writer.write_all(&scanline)?;
+        }
+        Ok(())
+    }
+
+    fn write_pfm(&self, mut writer: impl Write) -> Result<()> {
+        // a negative scale means little endian
+        write!(writer, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        // rows go from the bottom up
+        for row in self.pixels.chunks(self.width.max(1)).rev() {
+            for pixel in row {
+                for channel in pixel.into_array().iter() {
+                    writer.write_all(&(*channel as f32).to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hand a buffered writer for `path` to `write`.
+fn save(path: &Path, write: impl FnOnce(&mut BufWriter<File>) -> Result<()>) -> Result<()> {
+    let file =
+        File::create(path).with_context(|| format!("Unable to create file {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    write(&mut writer).with_context(|| format!("Unable to write image to {}", path.display()))?;
+    writer.flush().context("Unable to flush image")
+}
+
+/// What the integrator produces for one pixel: its colour along with
+/// auxiliary values for compositing and denoising.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pixel {
+    /// Linear radiance.
+    pub color: Vec3,
+    /// Average albedo of the first surfaces hit.
+    pub albedo: Vec3,
+    /// Average shading normal of the first surfaces hit.
+    pub normal: Vec3,
+    /// Distance from the camera to the first surface hit, or infinity.
+    pub depth: f64,
+    /// One more than the index of the first object hit in the scene, or zero
+    /// for the background.
+    pub object_id: u32,
+    /// The number of samples that contributed to `color`, leaving out any
+    /// whose radiance wasn't finite.
+    pub samples: u32,
+}
+
+/// Every pixel of a render, in row-major order from the top left.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameBuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<Pixel>,
+}
+
+impl FrameBuffer {
+    pub fn new(width: usize, height: usize, pixels: Vec<Pixel>) -> Self {
+        assert_eq!(
+            pixels.len(),
+            width * height,
+            "expected one pixel per width * height"
+        );
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn pixels(&self) -> &[Pixel] {
+        &self.pixels
+    }
+
+    /// The colour of each pixel.
+    pub fn image(&self) -> Image {
+        Image::new(
+            self.width,
+            self.height,
+            self.pixels.iter().map(|pixel| pixel.color).collect(),
+        )
+    }
+
+    /// The colour as the unnamed layer, followed by the albedo, normal,
+    /// depth (`Z`), object ID and sample count layers.
+    pub fn layers(&self) -> Vec<Layer> {
+        let colors = |f: fn(&Pixel) -> Vec3| self.pixels.iter().map(f).collect();
+        vec![
+            Layer {
+                name: String::new(),
+                data: LayerData::Color(colors(|pixel| pixel.color)),
+                half: false,
+            },
+            Layer {
+                name: "albedo".into(),
+                data: LayerData::Color(colors(|pixel| pixel.albedo)),
+                half: true,
+            },
+            Layer {
+                name: "normal".into(),
+                data: LayerData::Vector(colors(|pixel| pixel.normal)),
+                half: true,
+            },
+            Layer {
+                name: "Z".into(),
+                data: LayerData::Scalar(self.pixels.iter().map(|pixel| pixel.depth).collect()),
+                half: false,
+            },
+            Layer {
+                name: "object_id".into(),
+                data: LayerData::Id(self.pixels.iter().map(|pixel| pixel.object_id).collect()),
+                half: false,
+            },
+            Layer {
+                name: "sample_count".into(),
+                data: LayerData::Id(self.pixels.iter().map(|pixel| pixel.samples).collect()),
+                half: false,
+            },
+        ]
+    }
+
+    /// Like [`Image::save`], except that EXR files get every layer.
+    pub fn save(&self, path: impl AsRef<Path>, format: Format, tonemap: &ToneMap) -> Result<()> {
+        save(path.as_ref(), |writer| match format {
+            Format::Exr => write_exr(writer, self.width, self.height, &self.layers()),
+            _ => self.image().write(writer, format, tonemap),
+        })
+    }
+}
+
+/// The samples of an EXR layer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LayerData {
+    /// `R`, `G` and `B` channels.
+    Color(Vec<Vec3>),
+    /// `X`, `Y` and `Z` channels.
+    Vector(Vec<Vec3>),
+    /// A single channel named after the layer.
+    Scalar(Vec<f64>),
+    /// A single unsigned integer channel named after the layer.
+    Id(Vec<u32>),
+}
+
+/// A named group of channels in an EXR image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    /// Prefixed to the name of each channel, unless empty.
+    pub name: String,
+    pub data: LayerData,
+    /// Whether to store floating point channels as half rather than single
+    /// precision.
+    pub half: bool,
+}
+
+impl Layer {
+    fn channels(&self) -> Vec<exr::prelude::AnyChannel<exr::prelude::FlatSamples>> {
+        use exr::prelude::{f16, AnyChannel, FlatSamples, Text};
+
+        let channel_name = |channel: &str| {
+            Text::from(
+                match (self.name.as_str(), channel) {
+                    (name, "") => name.to_owned(),
+                    ("", channel) => channel.to_owned(),
+                    (name, channel) => format!("{}.{}", name, channel),
+                }
+                .as_str(),
+            )
+        };
+        let floats = |values: Vec<f64>| {
+            if self.half {
+                FlatSamples::F16(values.into_iter().map(f16::from_f64).collect())
+            } else {
+                FlatSamples::F32(values.into_iter().map(|value| value as f32).collect())
+            }
+        };
+        let vectors = |values: &[Vec3], names: [&str; 3]| {
+            names
+                .iter()
+                .enumerate()
+                .map(|(axis, name)| {
+                    AnyChannel::new(
+                        channel_name(name),
+                        floats(values.iter().map(|value| value[axis]).collect()),
+                    )
+                })
+                .collect()
+        };
+        match &self.data {
+            LayerData::Color(values) => vectors(values, ["R", "G", "B"]),
+            LayerData::Vector(values) => vectors(values, ["X", "Y", "Z"]),
+            LayerData::Scalar(values) => {
+                vec![AnyChannel::new(channel_name(""), floats(values.clone()))]
+            }
+            LayerData::Id(values) => vec![AnyChannel::new(
+                channel_name(""),
+                FlatSamples::U32(values.clone()),
+            )],
+        }
+    }
+}
+
+/// Write `layers`, each with `width * height` samples, as the channels of a
+/// single part EXR image.
+pub fn write_exr(
+    mut writer: impl Write,
+    width: usize,
+    height: usize,
+    layers: &[Layer],
+) -> Result<()> {
+    use exr::prelude::{AnyChannels, Encoding, LayerAttributes, WritableImage};
+
+    let channels = layers.iter().flat_map(Layer::channels).collect();
+    let image = exr::prelude::Image::from_layer(exr::prelude::Layer::new(
+        (width, height),
+        LayerAttributes::default(),
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(channels),
+    ));
+    // the encoder needs to seek back to write offsets
+    let mut buffer = Cursor::new(Vec::new());
+    image.write().to_buffered(&mut buffer)?;
+    writer.write_all(buffer.get_ref())?;
+    Ok(())
+}
+
+/// Radiance's shared exponent encoding: three 8-bit mantissas and a biased
+/// exponent taken from the brightest channel.
+fn rgbe(pixel: Vec3) -> [u8; 4] {
+    let [r, g, b] = pixel.into_array().map(|channel| channel.max(0.0));
+    let max = r.max(g).max(b);
+    if max < 1e-32 || !max.is_finite() {
+        return [0; 4];
+    }
+    // max = mantissa * 2^exponent with mantissa in [0.5, 1)
+    let mut exponent = max.log2().floor() as i32 + 1;
+    if max / 2f64.powi(exponent) >= 1.0 {
+        exponent += 1;
+    }
+    let scale = 256.0 / 2f64.powi(exponent);
+    [
+        (r * scale) as u8,
+        (g * scale) as u8,
+        (b * scale) as u8,
+        (exponent + 128).clamp(0, 255) as u8,
+    ]
+}
+
+fn from_rgbe([r, g, b, e]: [u8; 4]) -> Vec3 {
+    if e == 0 {
+        return Vec3::zeros();
+    }
+    // the middle of the range that truncating to a byte maps to
+    let scale = 2f64.powi(i32::from(e) - 128 - 8);
+    vec3![
+        (f64::from(r) + 0.5) * scale,
+        (f64::from(g) + 0.5) * scale,
+        (f64::from(b) + 0.5) * scale
+    ]
+}
+
+/// Decode one scanline from the start of `data` into `pixels`, returning
+/// the rest of the data.
+fn decode_scanline<'a>(data: &'a [u8], pixels: &mut [[u8; 4]]) -> Result<&'a [u8]> {
+    let width = pixels.len();
+    let too_short = || anyhow!("Unexpected end of pixels");
+    let run_length_encoded =
+        (8..0x8000).contains(&width) && data.len() >= 4 && data[..2] == [2, 2] && data[2] < 0x80;
+    if !run_length_encoded {
+        let flat = data.get(..4 * width).ok_or_else(too_short)?;
+        for (pixel, bytes) in pixels.iter_mut().zip(flat.chunks_exact(4)) {
+            pixel.copy_from_slice(bytes);
+        }
+        return Ok(&data[4 * width..]);
+    }
+    if usize::from(data[2]) << 8 | usize::from(data[3]) != width {
+        bail!("Expected scanlines of width {}", width);
+    }
+
+    let mut data = &data[4..];
+    for component in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let (&count, rest) = data.split_first().ok_or_else(too_short)?;
+            if count > 128 {
+                let count = usize::from(count - 128);
+                let &byte = rest.first().ok_or_else(too_short)?;
+                if x + count > width {
+                    bail!("Expected runs to stay within the scanline");
+                }
+                for pixel in &mut pixels[x..x + count] {
+                    pixel[component] = byte;
+                }
+                data = &rest[1..];
+                x += count;
+            } else {
+                let count = usize::from(count);
+                let literal = rest.get(..count).ok_or_else(too_short)?;
+                if count == 0 || x + count > width {
+                    bail!("Expected runs to stay within the scanline");
+                }
+                for (pixel, &byte) in pixels[x..x + count].iter_mut().zip(literal) {
+                    pixel[component] = byte;
+                }
+                data = &rest[count..];
+                x += count;
+            }
+        }
+    }
+    Ok(data)
+}
+
+/// Encode a scanline with Radiance's run length encoding, which stores each
+/// component separately. Widths the encoding can't represent are written
+/// flat.
+fn encode_scanline(pixels: &[[u8; 4]], out: &mut Vec<u8>) {
+    let width = pixels.len();
+    if !(8..0x8000).contains(&width) {
+        out.extend(pixels.iter().flatten());
+        return;
+    }
+    out.extend_from_slice(&[2, 2, (width >> 8) as u8, width as u8]);
+    for component in 0..4 {
+        let bytes = pixels
+            .iter()
+            .map(|pixel| pixel[component])
+            .collect::<Vec<_>>();
+        encode_runs(&bytes, out);
+    }
+}
+
+const MIN_RUN: usize = 4;
+const MAX_COUNT: usize = 127;
+
+fn encode_runs(bytes: &[u8], out: &mut Vec<u8>) {
+    let mut start = 0;
+    while start < bytes.len() {
+        // find the next run long enough to be worth encoding
+        let mut run_start = start;
+        let mut run_len = 0;
+        while run_start < bytes.len() {
+            run_len = bytes[run_start..]
+                .iter()
+                .take(MAX_COUNT)
+                .take_while(|&&byte| byte == bytes[run_start])
+                .count();
+            if run_len >= MIN_RUN {
+                break;
+            }
+            run_start += run_len;
+        }
+        // literals before the run
+        for literal in bytes[start..run_start].chunks(MAX_COUNT) {
+            out.push(literal.len() as u8);
+            out.extend_from_slice(literal);
+        }
+        if run_start < bytes.len() {
+            out.extend_from_slice(&[128 + run_len as u8, bytes[run_start]]);
+        }
+        start = run_start + run_len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_runs, rgbe, Format, FrameBuffer, Image, Pixel};
+    use crate::{tonemap::ToneMap, Vec3};
+    use exr::prelude::{FlatSamples, ReadChannels, ReadLayers};
+    use std::io::Cursor;
+
+    fn decode_runs(mut encoded: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while let Some((&count, rest)) = encoded.split_first() {
+            if count > 128 {
+                bytes.extend(std::iter::repeat_n(rest[0], usize::from(count - 128)));
+                encoded = &rest[1..];
+            } else {
+                bytes.extend_from_slice(&rest[..usize::from(count)]);
+                encoded = &rest[usize::from(count)..];
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(Format::from_path("out.PNG"), Some(Format::Png));
+        assert_eq!(Format::from_path("a/b.hdr"), Some(Format::Hdr));
+        assert_eq!(Format::from_path("out.ppm"), Some(Format::Ppm));
+        assert_eq!(Format::from_path("out"), None);
+        assert_eq!(Format::from_path("out.jpg"), None);
+    }
+
+    #[test]
+    fn test_rgbe() {
+        assert_eq!(rgbe(Vec3::zeros()), [0; 4]);
+        assert_eq!(rgbe(vec3![1, 0.5, 0]), [128, 64, 0, 129]);
+        let [r, g, b, e] = rgbe(vec3![1000, 3, 0.25]);
+        let scale = 2f64.powi(i32::from(e) - 128 - 8);
+        assert!((f64::from(r) * scale - 1000.0).abs() < 1000.0 / 128.0);
+        assert!((f64::from(g) * scale - 3.0).abs() < scale);
+        assert_eq!(b, 0);
+    }
+
+    #[test]
+    fn test_run_length_encoding() {
+        let bytes = [1, 2, 3, 3, 3, 3, 3, 4, 4, 5]
+            .iter()
+            .copied()
+            .chain(std::iter::repeat_n(7, 300))
+            .chain((0..200).map(|i| i as u8))
+            .collect::<Vec<u8>>();
+        let mut encoded = Vec::new();
+        encode_runs(&bytes, &mut encoded);
+        assert!(encoded.len() < bytes.len());
+        assert_eq!(decode_runs(&encoded), bytes);
+    }
+
+    #[test]
+    fn test_float_formats_are_unclamped() {
+        let image = Image::new(2, 1, vec![vec3![2, 0.5, 0], vec3![-1, 100, 1]]);
+        let mut pfm = Vec::new();
+        image
+            .write(&mut pfm, Format::Pfm, &ToneMap::default())
+            .unwrap();
+        let header = b"PF\n2 1\n-1.0\n";
+        assert_eq!(&pfm[..header.len()], header);
+        let floats = pfm[header.len()..]
+            .chunks(4)
+            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect::<Vec<_>>();
+        assert_eq!(floats, [2.0, 0.5, 0.0, -1.0, 100.0, 1.0]);
+    }
+
+    #[test]
+    fn test_read_float_formats() {
+        // wide enough to be run length encoded, with runs and literals
+        let pixels = (0..40)
+            .map(|i| vec3![f64::from(i / 10), 0.5, 1000.0 + f64::from(i)])
+            .collect::<Vec<_>>();
+        let image = Image::new(20, 2, pixels);
+        let round_trip = |format| {
+            let mut bytes = Vec::new();
+            image
+                .write(&mut bytes, format, &ToneMap::default())
+                .unwrap();
+            match format {
+                Format::Hdr => Image::read_hdr(&bytes),
+                _ => Image::read_pfm(&bytes),
+            }
+            .unwrap()
+        };
+
+        assert_eq!(round_trip(Format::Pfm), image);
+        let hdr = round_trip(Format::Hdr);
+        assert_eq!((hdr.width(), hdr.height()), (20, 2));
+        for (read, written) in hdr.pixels().iter().zip(image.pixels()) {
+            // within the precision of the shared exponent
+            assert!((*read - *written).norm() < written.norm() / 128.0);
+        }
+
+        let error = Image::read_pfm(b"PF\n2 1\n-1.0\n\0\0\0\0").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Expected 6 values for 2x1 pixels, found 4 bytes"
+        );
+        assert!(Image::read_hdr(b"#?RADIANCE\n\n-Y 1 +X 1\n").is_err());
+        // headers claiming more pixels than the file could hold
+        assert!(Image::read_hdr(b"#?RADIANCE\n\n-Y 200000 +X 200000\n\x02\x02").is_err());
+        let huge = format!("#?RADIANCE\n\n-Y {} +X {}\n", usize::MAX, usize::MAX);
+        assert!(Image::read_hdr(huge.as_bytes()).is_err());
+        let huge = format!("PF\n{} {}\n-1.0\n", usize::MAX / 2, 2);
+        assert!(Image::read_pfm(huge.as_bytes()).is_err());
+        let huge = format!("PF\n{} {}\n-1.0\n", usize::MAX / 8, 2);
+        assert!(Image::read_pfm(huge.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_ldr_formats() {
+        let image = Image::new(1, 2, vec![vec3![0.25, 1, 2], vec3![0, 0, 0]]);
+        let mut ppm = Vec::new();
+        image
+            .write(&mut ppm, Format::Ppm, &ToneMap::default())
+            .unwrap();
+        assert_eq!(ppm, b"P6\n1 2\n255\n\x89\xff\xff\0\0\0");
+
+        let mut png = Vec::new();
+        image
+            .write(&mut png, Format::Png, &ToneMap::default())
+            .unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    }
+
+    #[test]
+    fn test_exr_layers() {
+        let pixel = |color, object_id| Pixel {
+            color,
+            albedo: vec3![0.5, 0.25, 1],
+            normal: vec3![0, 1, 0],
+            depth: 2.5,
+            object_id,
+            samples: 16,
+        };
+        let framebuffer = FrameBuffer::new(
+            2,
+            1,
+            vec![pixel(vec3![1000, 1, 0], 3), pixel(vec3![0, 0, 0], 0)],
+        );
+        let mut exr = Vec::new();
+        let layers = framebuffer.layers();
+        super::write_exr(&mut exr, 2, 1, &layers).unwrap();
+
+        let image = exr::prelude::read()
+            .no_deep_data()
+            .largest_resolution_level()
+            .all_channels()
+            .first_valid_layer()
+            .all_attributes()
+            .from_buffered(Cursor::new(exr))
+            .unwrap();
+        let channels = &image.layer_data.channel_data.list;
+        let channel = |name: &str| {
+            &channels
+                .iter()
+                .find(|channel| channel.name == *name)
+                .unwrap_or_else(|| panic!("missing channel {}", name))
+                .sample_data
+        };
+        assert_eq!(channels.len(), 12);
+        assert_eq!(channel("R"), &FlatSamples::F32(vec![1000.0, 0.0]));
+        match channel("albedo.G") {
+            FlatSamples::F16(values) => assert_eq!(values[0].to_f32(), 0.25),
+            other => panic!("expected half floats, got {:?}", other),
+        }
+        assert!(channel("normal.Y")
+            .values_as_f32()
+            .all(|value| value == 1.0));
+        assert_eq!(channel("Z"), &FlatSamples::F32(vec![2.5, 2.5]));
+        assert_eq!(channel("object_id"), &FlatSamples::U32(vec![3, 0]));
+        assert_eq!(channel("sample_count"), &FlatSamples::U32(vec![16, 16]));
+    }
+}
@@ -0,0 +1,471 @@
+use crate::{
+    shape::area_to_solid_angle,
+    utils::{self, Rng},
+    Aabb, HitRecord, Hittable, Material, Ray, Vec3,
+};
+use std::f64::consts::PI;
+
+/// Where `ray` crosses the plane through `point` with unit `normal`, if that
+/// is within `(t_min, t_max)`.
+fn hit_plane(ray: &Ray, point: Vec3, normal: Vec3, t_min: f64, t_max: f64) -> Option<f64> {
+    let denom = normal.dot(ray.direction());
+    if denom == 0.0 {
+        return None;
+    }
+    let t = normal.dot(point - ray.origin()) / denom;
+    if t > t_min && t < t_max {
+        Some(t)
+    } else {
+        None
+    }
+}
+
+/// An infinite plane.
+///
+/// `(u, v)` are distances from `point` along two perpendicular axes in the
+/// plane, so that textures tile with a period of one.
+pub struct Plane {
+    point: Vec3,
+    normal: Vec3,
+    axes: (Vec3, Vec3),
+    material: Box<dyn Material + Send + Sync>,
+}
+
+impl Plane {
+    pub fn new(point: Vec3, normal: Vec3, material: impl Material + Send + Sync + 'static) -> Self {
+        let normal = normal.unitize();
+        Self {
+            point,
+            normal,
+            axes: utils::orthonormal_basis(normal),
+            material: Box::new(material),
+        }
+    }
+}
+
+impl Hittable for Plane {
+    fn hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let t = hit_plane(&ray, self.point, self.normal, t_min, t_max)?;
+        let point = ray.point(t);
+        let offset = point - self.point;
+        Some(HitRecord {
+            t,
+            point,
+            normal: self.normal,
+            u: offset.dot(self.axes.0),
+            v: offset.dot(self.axes.1),
+            barycentrics: None,
+            material: self.material.as_ref(),
+        })
+    }
+
+    fn occluded(&self, ray: Ray, t_min: f64, t_max: f64) -> bool {
+        hit_plane(&ray, self.point, self.normal, t_min, t_max).is_some()
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
+}
+
+/// A parallelogram with a corner at `origin` and sides `u` and `v`, facing
+/// along `u × v`.
+///
+/// `(u, v)` run from 0 to 1 along the two sides.
+pub struct Quad {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    normal: Vec3,
+    area: f64,
+    material: Box<dyn Material + Send + Sync>,
+}
+
+impl Quad {
+    pub fn new(
+        origin: Vec3,
+        u: Vec3,
+        v: Vec3,
+        material: impl Material + Send + Sync + 'static,
+    ) -> Self {
+        let n = u.cross(v);
+        Self {
+            origin,
+            u,
+            v,
+            normal: n.unitize(),
+            area: n.norm(),
+            material: Box::new(material),
+        }
+    }
+}
+
+impl Hittable for Quad {
+    fn hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let t = hit_plane(&ray, self.origin, self.normal, t_min, t_max)?;
+        let point = ray.point(t);
+        // coordinates of the point in the (u, v) frame
+        let n = self.u.cross(self.v);
+        let w = n / n.norm2();
+        let offset = point - self.origin;
+        let alpha = w.dot(offset.cross(self.v));
+        let beta = w.dot(self.u.cross(offset));
+        if !((0.0..=1.0).contains(&alpha) && (0.0..=1.0).contains(&beta)) {
+            return None;
+        }
+        Some(HitRecord {
+            t,
+            point,
+            normal: self.normal,
+            u: alpha,
+            v: beta,
+            barycentrics: None,
+            material: self.material.as_ref(),
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let Self { origin, u, v, .. } = *self;
+        Some(
+            Aabb::new(origin, origin + u + v)
+                .grow(origin + u)
+                .grow(origin + v),
+        )
+    }
+
+    fn sample_point(&self, _origin: Vec3, _time: f64, rng: &mut Rng) -> Option<Vec3> {
+        Some(self.origin + utils::rand(rng) * self.u + utils::rand(rng) * self.v)
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, _time: f64) -> f64 {
+        match self.hit(Ray::new(origin, direction), 0.001, f64::MAX) {
+            Some(rec) => area_to_solid_angle(self.area.recip(), origin, rec.point, self.normal),
+            None => 0.0,
+        }
+    }
+}
+
+/// A flat disk.
+///
+/// `u` is the angle around the center as a fraction of a turn and `v` the
+/// distance from the center as a fraction of the radius.
+pub struct Disk {
+    center: Vec3,
+    normal: Vec3,
+    radius: f64,
+    axes: (Vec3, Vec3),
+    material: Box<dyn Material + Send + Sync>,
+}
+
+impl Disk {
+    pub fn new(
+        center: Vec3,
+        normal: Vec3,
+        radius: f64,
+        material: impl Material + Send + Sync + 'static,
+    ) -> Self {
+        let normal = normal.unitize();
+        Self {
+            center,
+            normal,
+            radius,
+            axes: utils::orthonormal_basis(normal),
+            material: Box::new(material),
+        }
+    }
+}
+
+impl Hittable for Disk {
+    fn hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let t = hit_plane(&ray, self.center, self.normal, t_min, t_max)?;
+        let point = ray.point(t);
+        let offset = point - self.center;
+        let distance2 = offset.norm2();
+        if distance2 > self.radius.powi(2) {
+            return None;
+        }
+        let angle = offset.dot(self.axes.1).atan2(offset.dot(self.axes.0));
+        Some(HitRecord {
+            t,
+            point,
+            normal: self.normal,
+            u: angle.rem_euclid(2.0 * PI) / (2.0 * PI),
+            v: distance2.sqrt() / self.radius,
+            barycentrics: None,
+            material: self.material.as_ref(),
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // the disk reaches furthest along the axes it is least aligned with
+        let extent = self
+            .normal
+            .into_array()
+            .map(|n| self.radius * (1.0 - n * n).max(0.0).sqrt());
+        let extent = Vec3::from(extent);
+        Some(Aabb::new(self.center - extent, self.center + extent))
+    }
+
+    fn sample_point(&self, _origin: Vec3, _time: f64, rng: &mut Rng) -> Option<Vec3> {
+        let r = self.radius * utils::rand(rng).sqrt();
+        let (sin, cos) = (2.0 * PI * utils::rand(rng)).sin_cos();
+        Some(self.center + r * cos * self.axes.0 + r * sin * self.axes.1)
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, _time: f64) -> f64 {
+        match self.hit(Ray::new(origin, direction), 0.001, f64::MAX) {
+            Some(rec) => {
+                let area = PI * self.radius.powi(2);
+                area_to_solid_angle(area.recip(), origin, rec.point, self.normal)
+            }
+            None => 0.0,
+        }
+    }
+}
+
+/// An axis-aligned box.
+///
+/// On each face `(u, v)` run from 0 to 1 along the next two axes in `x, y,
+/// z` order, e.g. along `y` and `z` on the faces perpendicular to `x`.
+pub struct BoxShape {
+    min: Vec3,
+    max: Vec3,
+    material: Box<dyn Material + Send + Sync>,
+}
+
+impl BoxShape {
+    /// The box with opposite corners `a` and `b`.
+    pub fn new(a: Vec3, b: Vec3, material: impl Material + Send + Sync + 'static) -> Self {
+        Self {
+            min: a.inf(b),
+            max: a.sup(b),
+            material: Box::new(material),
+        }
+    }
+
+    /// The area of each face perpendicular to `x`, `y` and `z`.
+    fn face_areas(&self) -> [f64; 3] {
+        let extent = self.max - self.min;
+        [
+            extent.y() * extent.z(),
+            extent.z() * extent.x(),
+            extent.x() * extent.y(),
+        ]
+    }
+}
+
+impl Hittable for BoxShape {
+    fn hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let origin = ray.origin();
+        let dir = ray.direction();
+
+        // the latest entry into and earliest exit from the three slabs, and
+        // the axes they happen along
+        let (mut near, mut far) = ((f64::NEG_INFINITY, 0), (f64::INFINITY, 0));
+        for axis in 0..3 {
+            let inv = dir[axis].recip();
+            let t0 = (self.min[axis] - origin[axis]) * inv;
+            let t1 = (self.max[axis] - origin[axis]) * inv;
+            let (t0, t1) = if inv < 0.0 { (t1, t0) } else { (t0, t1) };
+            if t0 > near.0 {
+                near = (t0, axis);
+            }
+            if t1 < far.0 {
+                far = (t1, axis);
+            }
+        }
+        if near.0 > far.0 {
+            return None;
+        }
+
+        // outward normals point against the ray on entry and with it on exit
+        let (t, axis, sign) = if near.0 > t_min && near.0 < t_max {
+            (near.0, near.1, -dir[near.1].signum())
+        } else if far.0 > t_min && far.0 < t_max {
+            (far.0, far.1, dir[far.1].signum())
+        } else {
+            return None;
+        };
+
+        let point = ray.point(t);
+        let mut normal = [0.0; 3];
+        normal[axis] = sign;
+        let extent = self.max - self.min;
+        let coordinate = |axis: usize| (point[axis] - self.min[axis]) / extent[axis];
+        Some(HitRecord {
+            t,
+            point,
+            normal: normal.into(),
+            u: coordinate((axis + 1) % 3),
+            v: coordinate((axis + 2) % 3),
+            barycentrics: None,
+            material: self.material.as_ref(),
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::new(self.min, self.max))
+    }
+
+    fn sample_point(&self, _origin: Vec3, _time: f64, rng: &mut Rng) -> Option<Vec3> {
+        // pick a pair of faces by their area, then one of the two
+        let areas = self.face_areas();
+        let pick = utils::rand(rng) * areas.iter().sum::<f64>();
+        let axis = if pick < areas[0] {
+            0
+        } else if pick < areas[0] + areas[1] {
+            1
+        } else {
+            2
+        };
+        let mut point = [0.0; 3];
+        for (i, value) in point.iter_mut().enumerate() {
+            *value = self.min[i] + utils::rand(rng) * (self.max[i] - self.min[i]);
+        }
+        point[axis] = if utils::rand(rng) < 0.5 {
+            self.min[axis]
+        } else {
+            self.max[axis]
+        };
+        Some(point.into())
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, _time: f64) -> f64 {
+        let area = 2.0 * self.face_areas().iter().sum::<f64>();
+        match self.hit(Ray::new(origin, direction), 0.001, f64::MAX) {
+            Some(rec) => area_to_solid_angle(area.recip(), origin, rec.point, rec.normal),
+            None => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BoxShape, Disk, Plane, Quad};
+    use crate::{
+        utils::{random_unit_vector, seeded_rng},
+        Hittable, Lambertian, Ray, Vec3,
+    };
+
+    fn grey() -> Lambertian {
+        Lambertian::new(vec3![0.5, 0.5, 0.5])
+    }
+
+    #[test]
+    fn test_plane() {
+        let plane = Plane::new(vec3![0, 1, 0], vec3![0, 2, 0], grey());
+        let rec = plane
+            .hit(Ray::new(vec3![3, 5, -2], vec3![0, -2, 0]), 0.0, f64::MAX)
+            .unwrap();
+        assert_eq!(rec.t, 2.0);
+        assert_eq!(rec.point, vec3![3, 1, -2]);
+        assert_eq!(rec.normal, vec3![0, 1, 0]);
+        assert!((rec.u.powi(2) + rec.v.powi(2) - 13.0).abs() < 1e-9);
+        // parallel rays and hits behind the origin miss
+        let ray = Ray::new(vec3![0, 5, 0], vec3![1, 0, 0]);
+        assert!(plane.hit(ray, 0.0, f64::MAX).is_none());
+        let ray = Ray::new(vec3![0, 5, 0], vec3![0, 1, 0]);
+        assert!(plane.hit(ray, 0.0, f64::MAX).is_none());
+        assert!(plane.bounding_box().is_none());
+    }
+
+    #[test]
+    fn test_quad() {
+        let quad = Quad::new(vec3![0, 0, 0], vec3![2, 0, 0], vec3![0, 4, 0], grey());
+        let rec = quad
+            .hit(Ray::new(vec3![1.5, 1, 3], vec3![0, 0, -1]), 0.0, f64::MAX)
+            .unwrap();
+        assert_eq!(rec.normal, vec3![0, 0, 1]);
+        assert_eq!((rec.u, rec.v), (0.75, 0.25));
+        let ray = Ray::new(vec3![2.5, 1, 3], vec3![0, 0, -1]);
+        assert!(quad.hit(ray, 0.0, f64::MAX).is_none());
+        let bounds = quad.bounding_box().unwrap();
+        assert_eq!(
+            (bounds.min(), bounds.max()),
+            (Vec3::zeros(), vec3![2, 4, 0])
+        );
+    }
+
+    #[test]
+    fn test_disk() {
+        let disk = Disk::new(vec3![1, 1, 1], vec3![1, 0, 0], 2.0, grey());
+        let rec = disk
+            .hit(Ray::new(vec3![-3, 1, 2], vec3![1, 0, 0]), 0.0, f64::MAX)
+            .unwrap();
+        assert_eq!(rec.t, 4.0);
+        assert_eq!(rec.normal, vec3![1, 0, 0]);
+        assert_eq!(rec.v, 0.5);
+        let ray = Ray::new(vec3![-3, 1, 3.1], vec3![1, 0, 0]);
+        assert!(disk.hit(ray, 0.0, f64::MAX).is_none());
+        let bounds = disk.bounding_box().unwrap();
+        assert_eq!(bounds.min(), vec3![1, -1, -1]);
+        assert_eq!(bounds.max(), vec3![1, 3, 3]);
+    }
+
+    #[test]
+    fn test_box() {
+        let cube = BoxShape::new(vec3![1, 1, 1], vec3![-1, -1, -1], grey());
+        let rec = cube
+            .hit(Ray::new(vec3![0.5, 0, 5], vec3![0, 0, -1]), 0.0, f64::MAX)
+            .unwrap();
+        assert_eq!(rec.t, 4.0);
+        assert_eq!(rec.normal, vec3![0, 0, 1]);
+        assert_eq!((rec.u, rec.v), (0.75, 0.5));
+        // from inside, the far side is hit with an outward normal
+        let rec = cube
+            .hit(Ray::new(Vec3::zeros(), vec3![0, -1, 0]), 0.0, f64::MAX)
+            .unwrap();
+        assert_eq!((rec.t, rec.normal), (1.0, vec3![0, -1, 0]));
+        // axis-aligned rays beside the box miss
+        let ray = Ray::new(vec3![2, 0, 5], vec3![0, 0, -1]);
+        assert!(cube.hit(ray, 0.0, f64::MAX).is_none());
+    }
+
+    #[test]
+    fn test_sampled_pdfs_integrate_to_one() {
+        let rng = &mut seeded_rng(0);
+        let lights: Vec<Box<dyn Hittable>> = vec![
+            Box::new(Quad::new(
+                vec3![-1, 2, -1],
+                vec3![2, 0, 0],
+                vec3![0, 0, 2],
+                grey(),
+            )),
+            Box::new(Disk::new(vec3![0, 2, 0], vec3![0, -1, 0], 1.0, grey())),
+        ];
+        for light in lights {
+            // integrate the pdf over the sphere of directions
+            let n = 100_000;
+            let sum = (0..n)
+                .map(|_| light.pdf_value(Vec3::zeros(), random_unit_vector(rng), 0.0))
+                .sum::<f64>();
+            let integral = sum * 4.0 * std::f64::consts::PI / n as f64;
+            assert!((integral - 1.0).abs() < 0.05, "{}", integral);
+
+            let point = light.sample_point(Vec3::zeros(), 0.0, rng).unwrap();
+            assert!((point.y() - 2.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_box_sampling() {
+        let rng = &mut seeded_rng(0);
+        let cube = BoxShape::new(vec3![-1, 2, -1], vec3![1, 4, 1], grey());
+        // only the bottom face, a sixth of the surface, is seen from below
+        let n = 100_000;
+        let sum = (0..n)
+            .map(|_| cube.pdf_value(Vec3::zeros(), random_unit_vector(rng), 0.0))
+            .sum::<f64>();
+        let integral = sum * 4.0 * std::f64::consts::PI / n as f64;
+        assert!((integral - 1.0 / 6.0).abs() < 0.01, "{}", integral);
+
+        for _ in 0..100 {
+            let point = cube.sample_point(Vec3::zeros(), 0.0, rng).unwrap();
+            let on_face = (0..3).any(|axis| {
+                (point[axis] - cube.min[axis]).abs() < 1e-9
+                    || (point[axis] - cube.max[axis]).abs() < 1e-9
+            });
+            assert!(on_face, "{:?}", point);
+        }
+    }
+}
@@ -0,0 +1,344 @@
+//! Disney's principled BSDF, with the parameters of Blender's Principled BSDF
+//! node, so that materials authored there carry over.
+
+use crate::{
+    material::facing_normal,
+    microfacet::{Frame, Ggx},
+    tonemap::luminance,
+    utils::{self, Rng},
+    HitRecord, Material, Ray, Texture, Vec3,
+};
+use serde::Deserialize;
+use std::f64::consts::PI;
+
+/// Everything about a [`Principled`] material but its base colour. Each
+/// parameter runs from 0 to 1, except `ior`, and the defaults are Blender's.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PrincipledParameters {
+    /// Blends from a dielectric to a metal tinted by the base colour.
+    pub metallic: f64,
+    pub roughness: f64,
+    /// The dielectric specular reflectance, with 0.5 for 4% at normal
+    /// incidence.
+    pub specular: f64,
+    /// Tints the dielectric specular reflection by the base colour.
+    pub specular_tint: f64,
+    /// Extra reflection at grazing angles, as off cloth.
+    pub sheen: f64,
+    pub sheen_tint: f64,
+    /// A second, white specular layer on top, as of varnish.
+    pub clearcoat: f64,
+    pub clearcoat_roughness: f64,
+    /// Blends from an opaque surface to glass tinted by the base colour.
+    pub transmission: f64,
+    /// The index of refraction of the glass that `transmission` blends to.
+    pub ior: f64,
+    /// Stretches the specular highlights along the tangent.
+    pub anisotropy: f64,
+}
+
+impl Default for PrincipledParameters {
+    fn default() -> Self {
+        Self {
+            metallic: 0.0,
+            roughness: 0.5,
+            specular: 0.5,
+            specular_tint: 0.0,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            clearcoat: 0.0,
+            clearcoat_roughness: 0.03,
+            transmission: 0.0,
+            ior: 1.45,
+            anisotropy: 0.0,
+        }
+    }
+}
+
+/// A blend of a diffuse base with sheen, a specular layer for both metals
+/// and dielectrics, a clearcoat and rough glass, sampled in proportion to
+/// roughly how much each contributes.
+#[derive(Debug, PartialEq)]
+pub struct Principled<T = Vec3> {
+    base_color: T,
+    parameters: PrincipledParameters,
+    specular: Ggx,
+    clearcoat: Ggx,
+}
+
+/// How much each lobe contributes at a point, given its base colour.
+struct Lobes {
+    base_color: Vec3,
+    diffuse: f64,
+    sheen: Vec3,
+    specular: f64,
+    specular_f0: Vec3,
+    clearcoat: f64,
+    glass: f64,
+}
+
+/// The weight for reflectance at normal incidence in Schlick's
+/// approximation of the Fresnel reflectance, at an angle with `cosine`.
+fn schlick_weight(cosine: f64) -> f64 {
+    (1.0 - cosine).clamp(0.0, 1.0).powi(5)
+}
+
+impl<T> Principled<T> {
+    pub fn new(base_color: T, parameters: PrincipledParameters) -> Self {
+        Self {
+            base_color,
+            specular: Ggx::from_roughness(parameters.roughness, parameters.anisotropy),
+            clearcoat: Ggx::from_roughness(parameters.clearcoat_roughness, 0.0),
+            parameters,
+        }
+    }
+}
+
+impl<T> Principled<T>
+where
+    T: Texture,
+{
+    fn lobes(&self, rec: &HitRecord) -> Lobes {
+        let p = &self.parameters;
+        let base_color = self.base_color.value(rec.u, rec.v, rec.point);
+        // the base colour's hue and saturation, at a luminance of one
+        let tint = match luminance(base_color) {
+            l if l > 0.0 => base_color / l,
+            _ => Vec3::ones(),
+        };
+        let dielectric_f0 = 0.08 * p.specular * Vec3::ones().lerp(tint, p.specular_tint);
+        let glass = (1.0 - p.metallic) * p.transmission;
+        Lobes {
+            base_color,
+            diffuse: (1.0 - p.metallic) * (1.0 - p.transmission),
+            sheen: p.sheen * Vec3::ones().lerp(tint, p.sheen_tint),
+            specular: 1.0 - glass,
+            specular_f0: dielectric_f0.lerp(base_color, p.metallic),
+            clearcoat: 0.25 * p.clearcoat,
+            glass,
+        }
+    }
+
+    /// The probabilities of sampling the diffuse, specular, clearcoat and
+    /// glass lobes, for light leaving along `wo`.
+    fn probabilities(lobes: &Lobes, wo: Vec3) -> [f64; 4] {
+        let fresnel = luminance(lobes.specular_f0.lerp(Vec3::ones(), schlick_weight(wo.z())));
+        let weights = [
+            lobes.diffuse,
+            lobes.specular * fresnel.max(0.1),
+            lobes.clearcoat * (0.04 + 0.96 * schlick_weight(wo.z())),
+            lobes.glass,
+        ];
+        let total: f64 = weights.iter().sum();
+        if total > 0.0 {
+            weights.map(|weight| weight / total)
+        } else {
+            [0.0; 4]
+        }
+    }
+
+    /// The BSDF times the cosine for scattering `wo` into `wi`, and the
+    /// density with which `scatter` samples `wi`, where `eta` is the index
+    /// of refraction below the surface relative to above it.
+    fn eval_pdf(&self, lobes: &Lobes, wo: Vec3, wi: Vec3, eta: f64) -> (Vec3, f64) {
+        let probabilities = Self::probabilities(lobes, wo);
+        let (mut value, mut pdf) = (Vec3::zeros(), 0.0);
+        if wo.z() <= 0.0 {
+            return (value, pdf);
+        }
+
+        if wi.z() > 0.0 {
+            let h = (wo + wi).unitize();
+            let cos_d = wi.dot(h);
+            // Burley's diffuse, with its retro-reflection at grazing angles
+            let fd90 = 0.5 + 2.0 * self.parameters.roughness * cos_d * cos_d;
+            let retro = (1.0 + (fd90 - 1.0) * schlick_weight(wi.z()))
+                * (1.0 + (fd90 - 1.0) * schlick_weight(wo.z()));
+            let diffuse = lobes.base_color * (retro / PI) + lobes.sheen * schlick_weight(cos_d);
+            value += lobes.diffuse * wi.z() * diffuse;
+            pdf += probabilities[0] * wi.z() / PI;
+
+            if let Some((h, reflection, reflection_pdf)) = self.specular.reflection(wo, wi) {
+                let fresnel = lobes
+                    .specular_f0
+                    .lerp(Vec3::ones(), schlick_weight(wo.dot(h)));
+                value += lobes.specular * reflection * fresnel;
+                pdf += probabilities[1] * reflection_pdf;
+            }
+            if let Some((h, reflection, reflection_pdf)) = self.clearcoat.reflection(wo, wi) {
+                let fresnel = 0.04 + 0.96 * schlick_weight(wo.dot(h));
+                value += Vec3::ones() * (lobes.clearcoat * reflection * fresnel);
+                pdf += probabilities[2] * reflection_pdf;
+            }
+        }
+
+        if lobes.glass > 0.0 {
+            let (glass, glass_pdf) = self.specular.dielectric(wo, wi, eta);
+            let tint = if wi.z() < 0.0 {
+                lobes.base_color
+            } else {
+                Vec3::ones()
+            };
+            value += lobes.glass * glass * tint;
+            pdf += probabilities[3] * glass_pdf;
+        }
+        (value, pdf)
+    }
+
+    /// The shading frame on the side `r_in` arrives from, with the glass'
+    /// index of refraction on the other side relative to it.
+    fn frame(&self, r_in: &Ray, rec: &HitRecord) -> (Frame, f64) {
+        let ior = self.parameters.ior;
+        let eta = if r_in.direction().dot(rec.normal) > 0.0 {
+            1.0 / ior
+        } else {
+            ior
+        };
+        (Frame::new(facing_normal(r_in, rec)), eta)
+    }
+
+    /// `eval_pdf` for the directions along `r_in` and `scattered`.
+    fn evaluate(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> (Vec3, f64) {
+        let (frame, eta) = self.frame(r_in, rec);
+        let wo = frame.to_local(-r_in.direction().unitize());
+        let wi = frame.to_local(scattered.direction().unitize());
+        self.eval_pdf(&self.lobes(rec), wo, wi, eta)
+    }
+}
+
+impl<T> Material for Principled<T>
+where
+    T: Texture,
+{
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord, rng: &mut Rng) -> Option<(Vec3, Ray)> {
+        let (frame, eta) = self.frame(r_in, rec);
+        let wo = frame.to_local(-r_in.direction().unitize());
+        if wo.z() <= 0.0 {
+            return None;
+        }
+        let lobes = self.lobes(rec);
+        let probabilities = Self::probabilities(&lobes, wo);
+
+        // pick a lobe to sample, but weight by the density of them all
+        let mut xi = utils::rand(rng);
+        let lobe = probabilities
+            .iter()
+            .position(|&p| {
+                xi -= p;
+                xi < 0.0
+            })
+            .unwrap_or(3);
+        let wi = match lobe {
+            0 => {
+                let direction = vec3![0, 0, 1] + utils::random_unit_vector(rng);
+                if direction.norm2() < 1e-12 {
+                    vec3![0, 0, 1]
+                } else {
+                    direction.unitize()
+                }
+            }
+            1 => self.specular.sample_reflection(wo, rng)?,
+            2 => self.clearcoat.sample_reflection(wo, rng)?,
+            _ => self.specular.sample_dielectric(wo, eta, rng)?,
+        };
+
+        let (value, pdf) = self.eval_pdf(&lobes, wo, wi, eta);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some((value / pdf, r_in.spawn(rec.point, frame.to_world(wi))))
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Vec3 {
+        self.evaluate(r_in, rec, scattered).0
+    }
+
+    fn pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        self.evaluate(r_in, rec, scattered).1
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.base_color.value(rec.u, rec.v, rec.point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Principled, PrincipledParameters};
+    use crate::{utils::seeded_rng, HitRecord, Material, Ray, Vec3};
+
+    #[test]
+    fn test_sampling_matches_eval() {
+        let rng = &mut seeded_rng(0);
+        let everything = PrincipledParameters {
+            metallic: 0.3,
+            roughness: 0.4,
+            specular_tint: 0.5,
+            sheen: 0.5,
+            clearcoat: 1.0,
+            transmission: 0.4,
+            anisotropy: 0.5,
+            ..Default::default()
+        };
+        let material = Principled::new(vec3![0.8, 0.4, 0.2], everything);
+        let rec = HitRecord {
+            t: 1.0,
+            point: Vec3::zeros(),
+            normal: vec3![0, 0, 1],
+            u: 0.0,
+            v: 0.0,
+            barycentrics: None,
+            material: &material,
+        };
+        // from outside and inside the surface
+        for direction in [vec3![1, 0.5, -1], vec3![1, 0.5, 1]] {
+            let r_in = Ray::new(Vec3::zeros() - direction, direction);
+            let mut refracted = 0;
+            for _ in 0..2000 {
+                if let Some((weight, scattered)) = material.scatter(&r_in, &rec, rng) {
+                    let pdf = material.pdf(&r_in, &rec, &scattered);
+                    let expected = material.eval(&r_in, &rec, &scattered) / pdf;
+                    assert!((weight - expected).norm() < 1e-9 * (1.0 + expected.norm()));
+                    if scattered.direction().dot(direction) > 0.0 {
+                        refracted += 1;
+                    }
+                }
+            }
+            assert!(refracted > 100, "{}", refracted);
+        }
+    }
+
+    #[test]
+    fn test_white_furnace() {
+        // a white diffuse surface reflects about everything, and rougher
+        // ones more at grazing angles
+        let rng = &mut seeded_rng(1);
+        let mut albedo = |roughness: f64, cos_theta: f64| {
+            let parameters = PrincipledParameters {
+                roughness,
+                specular: 0.0,
+                ..Default::default()
+            };
+            let material = Principled::new(Vec3::ones(), parameters);
+            let rec = HitRecord {
+                t: 1.0,
+                point: Vec3::zeros(),
+                normal: vec3![0, 0, 1],
+                u: 0.0,
+                v: 0.0,
+                barycentrics: None,
+                material: &material,
+            };
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+            let r_in = Ray::new(Vec3::zeros(), vec3![sin_theta, 0, -cos_theta]);
+            let n = 20_000;
+            let total = (0..n)
+                .filter_map(|_| material.scatter(&r_in, &rec, rng))
+                .fold(Vec3::zeros(), |total, (weight, _)| total + weight);
+            total.x() / n as f64
+        };
+        assert!((albedo(0.5, 1.0) - 1.0).abs() < 0.1);
+        assert!(albedo(1.0, 0.2) > albedo(0.0, 0.2));
+    }
+}
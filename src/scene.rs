@@ -0,0 +1,1878 @@
+//! Scene descriptions, either loaded from a TOML file or generated by one of
+//! the built-in scenes.
+
+use crate::{
+    environment::{self, Constant, Environment, Equirectangular},
+    noise::{Noise, Perlin, Simplex, Smoothing},
+    obj,
+    output::Image,
+    sky::{self, Sky},
+    tonemap::Operator,
+    transform::{self, Instance},
+    utils::{rand, randvec, seeded_rng, Rng},
+    voxel::VoxelGrid,
+    Aabb, BoxShape, Bvh, Camera, Checker, Cloud, Coefficients, Conductor, ConstantMedium,
+    Dielectric, DiffuseLight, DirectionalLight, Disk, Dispersion, Element, Ggx, Gradient,
+    GridMedium, HenyeyGreenstein, Hittable, ImageTexture, Isotropic, Lambertian, Light, Marble,
+    Material, Metal, MovingSphere, Plane, PointLight, Principled, PrincipledParameters, Quad,
+    RoughDielectric, Sphere, SpotLight, Texture, TransferFunction, Triangle, TriangleMesh, Vec3,
+    Wood, Wrap,
+};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+fn default_vup() -> [f64; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+fn default_fov() -> f64 {
+    20.0
+}
+
+/// Every parameter of [`Camera::new`] except the aspect ratio, which comes
+/// from the image dimensions.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraSettings {
+    pub look_from: [f64; 3],
+    pub look_at: [f64; 3],
+    #[serde(default = "default_vup")]
+    pub vup: [f64; 3],
+    /// Vertical field of view in degrees.
+    #[serde(default = "default_fov")]
+    pub fov: f64,
+    #[serde(default)]
+    pub aperture: f64,
+    /// Defaults to the distance between `look_from` and `look_at`.
+    pub focus_distance: Option<f64>,
+    /// When the shutter opens and closes, from 0 at the start of the frame
+    /// to 1 at its end.
+    #[serde(default)]
+    pub shutter: [f64; 2],
+}
+
+impl CameraSettings {
+    pub fn camera(&self, aspect: f64) -> Camera {
+        let look_from = Vec3::from(self.look_from);
+        let look_at = Vec3::from(self.look_at);
+        Camera::new(
+            look_from,
+            look_at,
+            self.vup.into(),
+            self.fov,
+            aspect,
+            self.aperture,
+            self.focus_distance
+                .unwrap_or_else(|| (look_from - look_at).norm()),
+        )
+        .with_shutter(self.shutter[0], self.shutter[1])
+    }
+}
+
+/// Render settings, which options given on the command line take precedence
+/// over.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RenderSettings {
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    pub samples: Option<u32>,
+    pub gamma: Option<f64>,
+    /// Stops of exposure for 8-bit output.
+    pub exposure: Option<f64>,
+    pub tonemap: Option<Operator>,
+    /// The most bounces a path takes.
+    pub max_depth: Option<usize>,
+    /// Bounces before Russian roulette can end a path.
+    pub min_depth: Option<usize>,
+}
+
+pub struct Scene {
+    /// Each object's position in the hierarchy is its ID in the object ID
+    /// layer.
+    pub world: Bvh<Arc<dyn Hittable + Send + Sync>>,
+    /// `None` if the camera is left to the command line.
+    pub camera: Option<CameraSettings>,
+    pub render: RenderSettings,
+    /// Radiance of rays that escape the scene.
+    pub environment: Arc<dyn Environment + Send + Sync>,
+    /// Emitting hittables, which are also part of `world`, to sample
+    /// explicitly.
+    pub lights: Vec<Arc<dyn Hittable + Send + Sync>>,
+    /// Punctual lights, which aren't part of `world`.
+    pub delta_lights: Vec<Arc<dyn Light + Send + Sync>>,
+}
+
+type SharedTexture = Arc<dyn Texture + Send + Sync>;
+type SharedHittable = Arc<dyn Hittable + Send + Sync>;
+/// A shape along with the parts of it to sample as lights.
+type ShapeAndLights = (SharedHittable, Vec<SharedHittable>);
+
+fn default_checker_size() -> f64 {
+    1.0
+}
+
+fn default_wrap() -> Wrap {
+    Wrap::Repeat
+}
+
+fn default_noise_scale() -> f64 {
+    1.0
+}
+
+fn default_smoothing() -> Smoothing {
+    Smoothing::Hermite
+}
+
+/// The noise behind a procedural texture, seeded with the render's seed
+/// unless it gives its own.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum NoiseDescription {
+    Perlin {
+        #[serde(default = "default_smoothing")]
+        smoothing: Smoothing,
+        seed: Option<u64>,
+    },
+    Simplex {
+        seed: Option<u64>,
+    },
+}
+
+impl Default for NoiseDescription {
+    fn default() -> Self {
+        NoiseDescription::Perlin {
+            smoothing: default_smoothing(),
+            seed: None,
+        }
+    }
+}
+
+impl NoiseDescription {
+    fn build(self, render_seed: u64) -> Arc<dyn Noise + Send + Sync> {
+        match self {
+            NoiseDescription::Perlin { smoothing, seed } => Arc::new(Perlin::new(
+                smoothing,
+                &mut seeded_rng(seed.unwrap_or(render_seed)),
+            )),
+            NoiseDescription::Simplex { seed } => {
+                Arc::new(Simplex::new(&mut seeded_rng(seed.unwrap_or(render_seed))))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum TextureDescription {
+    Solid {
+        color: [f64; 3],
+    },
+    Checker {
+        even: [f64; 3],
+        odd: [f64; 3],
+        #[serde(default = "default_checker_size")]
+        size: f64,
+    },
+    /// A PNG or PPM file, relative to the scene file.
+    Image {
+        path: PathBuf,
+        #[serde(default = "default_wrap")]
+        wrap: Wrap,
+    },
+    Gradient {
+        start: [f64; 3],
+        end: [f64; 3],
+    },
+    Marble {
+        #[serde(default)]
+        noise: NoiseDescription,
+        #[serde(default = "default_noise_scale")]
+        scale: f64,
+        light: [f64; 3],
+        dark: [f64; 3],
+    },
+    Wood {
+        #[serde(default)]
+        noise: NoiseDescription,
+        #[serde(default = "default_noise_scale")]
+        scale: f64,
+        light: [f64; 3],
+        dark: [f64; 3],
+    },
+    Cloud {
+        #[serde(default)]
+        noise: NoiseDescription,
+        #[serde(default = "default_noise_scale")]
+        scale: f64,
+        sky: [f64; 3],
+        cloud: [f64; 3],
+    },
+}
+
+impl TextureDescription {
+    fn build(self, base_dir: &Path, seed: u64) -> Result<SharedTexture> {
+        Ok(match self {
+            TextureDescription::Solid { color } => Arc::new(Vec3::from(color)),
+            TextureDescription::Checker { even, odd, size } => {
+                Arc::new(Checker::new(Vec3::from(even), Vec3::from(odd), size))
+            }
+            TextureDescription::Image { path, wrap } => {
+                Arc::new(ImageTexture::load(base_dir.join(path), wrap)?)
+            }
+            TextureDescription::Gradient { start, end } => {
+                Arc::new(Gradient::new(start.into(), end.into()))
+            }
+            TextureDescription::Marble {
+                noise,
+                scale,
+                light,
+                dark,
+            } => Arc::new(Marble::new(
+                noise.build(seed),
+                scale,
+                light.into(),
+                dark.into(),
+            )),
+            TextureDescription::Wood {
+                noise,
+                scale,
+                light,
+                dark,
+            } => Arc::new(Wood::new(
+                noise.build(seed),
+                scale,
+                light.into(),
+                dark.into(),
+            )),
+            TextureDescription::Cloud {
+                noise,
+                scale,
+                sky,
+                cloud,
+            } => Arc::new(Cloud::new(
+                noise.build(seed),
+                scale,
+                sky.into(),
+                cloud.into(),
+            )),
+        })
+    }
+}
+
+/// A colour given inline, or the name of a texture.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TextureRef {
+    Color([f64; 3]),
+    Name(String),
+}
+
+/// A dielectric's index of refraction, either the same for every wavelength
+/// or given by a dispersion model.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RefractiveIndex {
+    Constant(f64),
+    Dispersive(Dispersion),
+}
+
+/// A conductor's index of refraction, either measured for one of the preset
+/// metals or given as a complex `eta + i k` for red, green and blue.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ConductorIor {
+    Preset(Element),
+    Complex { eta: [f64; 3], k: [f64; 3] },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum MaterialDescription {
+    Lambertian {
+        albedo: TextureRef,
+    },
+    Metal {
+        albedo: TextureRef,
+        #[serde(default)]
+        fuzz: f64,
+    },
+    /// Glass, with an index of refraction that is either constant or
+    /// disperses light, and that absorbs `absorption` per unit distance.
+    Dielectric {
+        ref_idx: RefractiveIndex,
+        #[serde(default)]
+        absorption: [f64; 3],
+    },
+    /// A rough metal, with a GGX `roughness` from 0 to 1 that is stretched
+    /// along the tangent by `anisotropy`.
+    Conductor {
+        ior: ConductorIor,
+        #[serde(default)]
+        roughness: f64,
+        #[serde(default)]
+        anisotropy: f64,
+    },
+    /// Glass with a GGX `roughness`, like `conductor`'s.
+    RoughDielectric {
+        ref_idx: f64,
+        roughness: f64,
+        #[serde(default)]
+        anisotropy: f64,
+    },
+    /// Blender's Principled BSDF, with any parameters besides `base_color`
+    /// left out taking Blender's defaults.
+    Principled {
+        base_color: TextureRef,
+        #[serde(flatten)]
+        parameters: PrincipledParameters,
+    },
+    DiffuseLight {
+        emit: [f64; 3],
+    },
+    /// A phase function for `constant_medium` shapes that scatters equally
+    /// in every direction.
+    Isotropic {
+        albedo: TextureRef,
+    },
+    /// A phase function for `constant_medium` shapes that scatters forwards
+    /// for positive `g` and backwards for negative `g`.
+    HenyeyGreenstein {
+        albedo: TextureRef,
+        g: f64,
+    },
+}
+
+impl MaterialDescription {
+    /// Build the material, looking up named textures with `texture`.
+    fn build(
+        self,
+        texture: impl Fn(&str) -> Result<SharedTexture>,
+    ) -> Result<Arc<dyn Material + Send + Sync>> {
+        Ok(match self {
+            MaterialDescription::Lambertian {
+                albedo: TextureRef::Color(albedo),
+            } => Arc::new(Lambertian::new(Vec3::from(albedo))),
+            MaterialDescription::Lambertian {
+                albedo: TextureRef::Name(name),
+            } => Arc::new(Lambertian::new(texture(&name)?)),
+            MaterialDescription::Metal {
+                albedo: TextureRef::Color(albedo),
+                fuzz,
+            } => Arc::new(Metal::new(Vec3::from(albedo), fuzz)),
+            MaterialDescription::Metal {
+                albedo: TextureRef::Name(name),
+                fuzz,
+            } => Arc::new(Metal::new(texture(&name)?, fuzz)),
+            MaterialDescription::Dielectric {
+                ref_idx,
+                absorption,
+            } => Arc::new(match ref_idx {
+                RefractiveIndex::Constant(ref_idx) => {
+                    Dielectric::with_absorption(ref_idx, absorption.into())
+                }
+                RefractiveIndex::Dispersive(dispersion) => {
+                    Dielectric::dispersive(dispersion, absorption.into())
+                }
+            }),
+            MaterialDescription::Conductor {
+                ior,
+                roughness,
+                anisotropy,
+            } => {
+                let distribution = Ggx::from_roughness(roughness, anisotropy);
+                Arc::new(match ior {
+                    ConductorIor::Preset(element) => Conductor::preset(element, distribution),
+                    ConductorIor::Complex { eta, k } => {
+                        Conductor::new(eta.into(), k.into(), distribution)
+                    }
+                })
+            }
+            MaterialDescription::RoughDielectric {
+                ref_idx,
+                roughness,
+                anisotropy,
+            } => Arc::new(RoughDielectric::new(
+                ref_idx,
+                Ggx::from_roughness(roughness, anisotropy),
+            )),
+            MaterialDescription::Principled {
+                base_color: TextureRef::Color(base_color),
+                parameters,
+            } => Arc::new(Principled::new(Vec3::from(base_color), parameters)),
+            MaterialDescription::Principled {
+                base_color: TextureRef::Name(name),
+                parameters,
+            } => Arc::new(Principled::new(texture(&name)?, parameters)),
+            MaterialDescription::DiffuseLight { emit } => Arc::new(DiffuseLight::new(emit.into())),
+            MaterialDescription::Isotropic {
+                albedo: TextureRef::Color(albedo),
+            } => Arc::new(Isotropic::new(Vec3::from(albedo))),
+            MaterialDescription::Isotropic {
+                albedo: TextureRef::Name(name),
+            } => Arc::new(Isotropic::new(texture(&name)?)),
+            MaterialDescription::HenyeyGreenstein {
+                albedo: TextureRef::Color(albedo),
+                g,
+            } => Arc::new(HenyeyGreenstein::new(Vec3::from(albedo), g)),
+            MaterialDescription::HenyeyGreenstein {
+                albedo: TextureRef::Name(name),
+                g,
+            } => Arc::new(HenyeyGreenstein::new(texture(&name)?, g)),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ShapeDescription {
+    Sphere {
+        center: [f64; 3],
+        radius: f64,
+        material: String,
+    },
+    /// A sphere moving from `start` at time 0 to `end` at time 1.
+    MovingSphere {
+        start: [f64; 3],
+        end: [f64; 3],
+        radius: f64,
+        material: String,
+    },
+    Triangle {
+        vertices: [[f64; 3]; 3],
+        material: String,
+    },
+    /// An infinite plane through `point`.
+    Plane {
+        point: [f64; 3],
+        normal: [f64; 3],
+        material: String,
+    },
+    /// A parallelogram with a corner at `origin` and sides `u` and `v`.
+    Quad {
+        origin: [f64; 3],
+        u: [f64; 3],
+        v: [f64; 3],
+        material: String,
+    },
+    Disk {
+        center: [f64; 3],
+        normal: [f64; 3],
+        radius: f64,
+        material: String,
+    },
+    /// An axis-aligned box between opposite corners `min` and `max`.
+    Box {
+        min: [f64; 3],
+        max: [f64; 3],
+        material: String,
+    },
+    Mesh {
+        positions: Vec<[f64; 3]>,
+        indices: Vec<[usize; 3]>,
+        normals: Option<Vec<[f64; 3]>>,
+        uvs: Option<Vec<[f64; 2]>>,
+        material: String,
+    },
+    /// A Wavefront OBJ file, relative to the scene file.
+    Obj { path: PathBuf },
+    /// A copy of one of the scene's `objects`, placed by a transform, and
+    /// moving to the transform `end` over the frame if that is given.
+    Instance {
+        object: String,
+        end: Option<Box<TransformDescription>>,
+        #[serde(flatten)]
+        transform: Box<TransformDescription>,
+    },
+    /// Fog or smoke of `density` filling one of the scene's `objects`, which
+    /// scatters by the phase function `material`.
+    ConstantMedium {
+        boundary: String,
+        density: f64,
+        material: String,
+    },
+    /// A medium whose density varies over a voxel grid, which scatters by
+    /// the phase function `material`, and is coloured by the transfer
+    /// function `transfer`, white if that's empty.
+    GridMedium {
+        grid: Box<GridDescription>,
+        #[serde(default)]
+        absorption: f64,
+        #[serde(default = "default_scattering")]
+        scattering: f64,
+        #[serde(default)]
+        emission: f64,
+        #[serde(default)]
+        transfer: Vec<TransferStop>,
+        material: String,
+    },
+}
+
+fn default_scattering() -> f64 {
+    1.0
+}
+
+/// Where the densities of a `grid_medium` come from.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum GridDescription {
+    /// A Mitsuba `.vol` file, relative to the scene file.
+    File { path: PathBuf },
+    /// Billowing noise like the `cloud` texture's, sampled at `resolution`
+    /// points spanning the box between `min` and `max`, and fading out
+    /// towards the surface of the ellipsoid that fits in the box.
+    Noise {
+        #[serde(default)]
+        noise: NoiseDescription,
+        #[serde(default = "default_noise_scale")]
+        scale: f64,
+        resolution: [usize; 3],
+        min: [f64; 3],
+        max: [f64; 3],
+    },
+}
+
+impl GridDescription {
+    fn build(self, base_dir: &Path, seed: u64) -> Result<VoxelGrid> {
+        Ok(match self {
+            GridDescription::File { path } => VoxelGrid::load(base_dir.join(path))?,
+            GridDescription::Noise {
+                noise,
+                scale,
+                resolution,
+                min,
+                max,
+            } => {
+                if resolution.contains(&0) {
+                    bail!("Expected a resolution of at least one point along each axis");
+                }
+                let noise = noise.build(seed);
+                let bounds = Aabb::new(min.into(), max.into());
+                let radii = 0.5 * bounds.extent();
+                VoxelGrid::from_fn(resolution, bounds, |point| {
+                    let falloff = 1.0 - ((point - bounds.centroid()) / radii).norm2();
+                    (0.5 + noise.fbm(scale * point, 6)).clamp(0.0, 1.0) * falloff.max(0.0)
+                })
+            }
+        })
+    }
+}
+
+/// A colour that a transfer function gives to a density.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TransferStop {
+    density: f64,
+    color: [f64; 3],
+}
+
+/// A rotation of `degrees` about `axis`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RotationDescription {
+    axis: [f64; 3],
+    degrees: f64,
+}
+
+/// A scale, either the same along every axis or separately along each.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ScaleDescription {
+    Uniform(f64),
+    Axes([f64; 3]),
+}
+
+/// An affine transform: `matrix`, then `scale`, then `rotate`, then
+/// `translate`, each of which is optional.
+#[derive(Debug, Deserialize)]
+struct TransformDescription {
+    /// Row-major, with a last row of `[0, 0, 0, 1]`.
+    matrix: Option<[[f64; 4]; 4]>,
+    scale: Option<ScaleDescription>,
+    rotate: Option<RotationDescription>,
+    translate: Option<[f64; 3]>,
+}
+
+impl TransformDescription {
+    fn matrix(&self) -> Result<transform::Matrix> {
+        let mut matrix = transform::Matrix::identity();
+        if let Some(rows) = self.matrix {
+            if rows[3] != [0.0, 0.0, 0.0, 1.0] {
+                bail!("Expected the last row of `matrix` to be [0, 0, 0, 1]");
+            }
+            matrix = transform::Matrix::from_fn(|row, column| rows[row][column]);
+        }
+        if let Some(scale) = &self.scale {
+            let factors = match *scale {
+                ScaleDescription::Uniform(factor) => [factor; 3],
+                ScaleDescription::Axes(factors) => factors,
+            };
+            matrix = transform::scaling(factors.into()) * matrix;
+        }
+        if let Some(RotationDescription { axis, degrees }) = self.rotate {
+            if axis == [0.0; 3] {
+                bail!("Expected a nonzero rotation `axis`");
+            }
+            matrix = transform::rotation(axis.into(), degrees) * matrix;
+        }
+        if let Some(offset) = self.translate {
+            matrix = transform::translation(offset.into()) * matrix;
+        }
+        if !matrix.iter().all(|value| value.is_finite()) || matrix.determinant() == 0.0 {
+            bail!("Expected an invertible transform");
+        }
+        Ok(matrix)
+    }
+}
+
+fn default_sky_bottom() -> [f64; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+fn default_sky_top() -> [f64; 3] {
+    [0.5, 0.7, 1.0]
+}
+
+fn default_intensity() -> f64 {
+    1.0
+}
+
+fn default_turbidity() -> f64 {
+    3.0
+}
+
+fn default_ground_albedo() -> [f64; 3] {
+    [0.3, 0.3, 0.3]
+}
+
+/// Where the sun is in a sky, either directly or at a place and time.
+#[derive(Debug, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum SunPosition {
+    /// Degrees above the horizon, and clockwise from north, which is -z.
+    Angles { elevation: f64, azimuth: f64 },
+    /// Degrees north and east, the day of the year, and the hour in a time
+    /// zone `utc_offset` hours ahead of UTC.
+    Location {
+        latitude: f64,
+        longitude: f64,
+        day: f64,
+        time: f64,
+        #[serde(default)]
+        utc_offset: f64,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum EnvironmentDescription {
+    Constant {
+        color: [f64; 3],
+    },
+    /// Defaults to the white to sky blue gradient of scenes without an
+    /// environment.
+    Gradient {
+        #[serde(default = "default_sky_bottom")]
+        bottom: [f64; 3],
+        #[serde(default = "default_sky_top")]
+        top: [f64; 3],
+    },
+    /// An equirectangular `.hdr` or `.pfm` image relative to the scene file,
+    /// turned by `rotation` degrees about the y axis.
+    Image {
+        path: PathBuf,
+        #[serde(default)]
+        rotation: f64,
+        #[serde(default = "default_intensity")]
+        intensity: f64,
+    },
+    /// Daylight, in kilocandelas per square metre.
+    Sky {
+        sun: SunPosition,
+        #[serde(default = "default_turbidity")]
+        turbidity: f64,
+        #[serde(default = "default_ground_albedo")]
+        ground_albedo: [f64; 3],
+    },
+}
+
+impl EnvironmentDescription {
+    fn build(self, base_dir: &Path) -> Result<Arc<dyn Environment + Send + Sync>> {
+        Ok(match self {
+            EnvironmentDescription::Constant { color } => Arc::new(Constant::new(color.into())),
+            EnvironmentDescription::Gradient { bottom, top } => {
+                Arc::new(environment::Gradient::new(bottom.into(), top.into()))
+            }
+            EnvironmentDescription::Image {
+                path,
+                rotation,
+                intensity,
+            } => {
+                let image = Image::load(base_dir.join(path))?;
+                if image.width() == 0 || image.height() == 0 {
+                    bail!("Expected a non-empty image");
+                }
+                Arc::new(Equirectangular::new(image, rotation, intensity))
+            }
+            EnvironmentDescription::Sky {
+                sun,
+                turbidity,
+                ground_albedo,
+            } => {
+                if !(1.7..=10.0).contains(&turbidity) {
+                    bail!("Expected a turbidity from 1.7 to 10, got {}", turbidity);
+                }
+                let sun = match sun {
+                    SunPosition::Angles { elevation, azimuth } => {
+                        sky::sun_direction(elevation, azimuth)
+                    }
+                    SunPosition::Location {
+                        latitude,
+                        longitude,
+                        day,
+                        time,
+                        utc_offset,
+                    } => sky::solar_direction(latitude, longitude, day, time, utc_offset),
+                };
+                Arc::new(Sky::new(sun, turbidity, ground_albedo.into()))
+            }
+        })
+    }
+}
+
+/// A punctual light, with intensities in power per solid angle.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum LightDescription {
+    Point {
+        position: [f64; 3],
+        intensity: [f64; 3],
+    },
+    /// Full intensity within `inner_angle` degrees of `direction`, fading to
+    /// nothing at `outer_angle`.
+    Spot {
+        position: [f64; 3],
+        direction: [f64; 3],
+        intensity: [f64; 3],
+        inner_angle: f64,
+        outer_angle: f64,
+    },
+    /// Light travelling along `direction`, with `irradiance` on surfaces
+    /// facing it.
+    Directional {
+        direction: [f64; 3],
+        irradiance: [f64; 3],
+    },
+}
+
+impl LightDescription {
+    fn build(self) -> Result<Arc<dyn Light + Send + Sync>> {
+        Ok(match self {
+            LightDescription::Point {
+                position,
+                intensity,
+            } => Arc::new(PointLight::new(position.into(), intensity.into())),
+            LightDescription::Spot {
+                position,
+                direction,
+                intensity,
+                inner_angle,
+                outer_angle,
+            } => {
+                if !(0.0 <= inner_angle && inner_angle <= outer_angle && outer_angle <= 180.0) {
+                    bail!(
+                        "Expected 0 <= inner_angle <= outer_angle <= 180, got {} and {}",
+                        inner_angle,
+                        outer_angle
+                    );
+                }
+                Arc::new(SpotLight::new(
+                    position.into(),
+                    direction.into(),
+                    intensity.into(),
+                    inner_angle,
+                    outer_angle,
+                ))
+            }
+            LightDescription::Directional {
+                direction,
+                irradiance,
+            } => Arc::new(DirectionalLight::new(direction.into(), irradiance.into())),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFile {
+    camera: Option<CameraSettings>,
+    #[serde(default)]
+    render: RenderSettings,
+    /// A constant background, short for an `environment` of that colour.
+    background: Option<[f64; 3]>,
+    environment: Option<EnvironmentDescription>,
+    // kept as raw values so that errors can name the offending entry
+    #[serde(default)]
+    textures: BTreeMap<String, toml::Value>,
+    #[serde(default)]
+    materials: BTreeMap<String, toml::Value>,
+    /// Shapes that aren't part of the scene themselves, but are placed any
+    /// number of times by `instance` shapes.
+    #[serde(default)]
+    objects: BTreeMap<String, toml::Value>,
+    #[serde(default)]
+    shapes: Vec<toml::Value>,
+    #[serde(default)]
+    lights: Vec<toml::Value>,
+}
+
+fn to_vec3s(points: Vec<[f64; 3]>) -> Vec<Vec3> {
+    points.into_iter().map(Vec3::from).collect()
+}
+
+impl SceneFile {
+    fn build(self, base_dir: &Path, seed: u64) -> Result<Scene> {
+        let mut textures = BTreeMap::new();
+        for (name, value) in self.textures {
+            let description: TextureDescription = value
+                .try_into()
+                .with_context(|| format!("Invalid key `textures.{}`", name))?;
+            let texture = description
+                .build(base_dir, seed)
+                .with_context(|| format!("Unable to load key `textures.{}`", name))?;
+            textures.insert(name, texture);
+        }
+
+        let mut materials = BTreeMap::new();
+        for (name, value) in self.materials {
+            let description: MaterialDescription = value
+                .try_into()
+                .with_context(|| format!("Invalid key `materials.{}`", name))?;
+            let emissive = matches!(description, MaterialDescription::DiffuseLight { .. });
+            let texture = |texture: &str| match textures.get(texture) {
+                Some(texture) => Ok(Arc::clone(texture)),
+                None => bail!(
+                    "Unknown texture {:?} for key `materials.{}.albedo`, expected one of: {}",
+                    texture,
+                    name,
+                    textures.keys().cloned().collect::<Vec<_>>().join(", ")
+                ),
+            };
+            materials.insert(name.clone(), (description.build(texture)?, emissive));
+        }
+        let material = |key: &str, name: &str| match materials.get(name) {
+            Some((material, _)) => Ok(Arc::clone(material)),
+            None => bail!(
+                "Unknown material {:?} for key `{}.material`, expected one of: {}",
+                name,
+                key,
+                materials.keys().cloned().collect::<Vec<_>>().join(", ")
+            ),
+        };
+        let is_light = |name: &str| materials.get(name).is_some_and(|&(_, emissive)| emissive);
+
+        // build a shape, along with the parts of it to sample as lights
+        let build_shape = |key: &str, shape: ShapeDescription| -> Result<ShapeAndLights> {
+            let mut lights = Vec::new();
+            let emissive = match &shape {
+                ShapeDescription::Sphere { material, .. }
+                | ShapeDescription::MovingSphere { material, .. }
+                | ShapeDescription::Triangle { material, .. }
+                | ShapeDescription::Quad { material, .. }
+                | ShapeDescription::Disk { material, .. }
+                | ShapeDescription::Box { material, .. }
+                | ShapeDescription::Mesh { material, .. } => is_light(material),
+                // an infinite plane has no area to sample points from
+                ShapeDescription::Plane { .. }
+                | ShapeDescription::Obj { .. }
+                | ShapeDescription::Instance { .. }
+                | ShapeDescription::ConstantMedium { .. }
+                | ShapeDescription::GridMedium { .. } => false,
+            };
+            let shape: SharedHittable = match shape {
+                ShapeDescription::Sphere {
+                    center,
+                    radius,
+                    material: name,
+                } => Arc::new(Sphere::new(center.into(), radius, material(key, &name)?)),
+                ShapeDescription::MovingSphere {
+                    start,
+                    end,
+                    radius,
+                    material: name,
+                } => Arc::new(MovingSphere::new(
+                    start.into(),
+                    end.into(),
+                    radius,
+                    material(key, &name)?,
+                )),
+                ShapeDescription::Triangle {
+                    vertices: [a, b, c],
+                    material: name,
+                } => Arc::new(Triangle::new(
+                    a.into(),
+                    b.into(),
+                    c.into(),
+                    material(key, &name)?,
+                )),
+                ShapeDescription::Plane {
+                    point,
+                    normal,
+                    material: name,
+                } => Arc::new(Plane::new(
+                    point.into(),
+                    normal.into(),
+                    material(key, &name)?,
+                )),
+                ShapeDescription::Quad {
+                    origin,
+                    u,
+                    v,
+                    material: name,
+                } => Arc::new(Quad::new(
+                    origin.into(),
+                    u.into(),
+                    v.into(),
+                    material(key, &name)?,
+                )),
+                ShapeDescription::Disk {
+                    center,
+                    normal,
+                    radius,
+                    material: name,
+                } => Arc::new(Disk::new(
+                    center.into(),
+                    normal.into(),
+                    radius,
+                    material(key, &name)?,
+                )),
+                ShapeDescription::Box {
+                    min,
+                    max,
+                    material: name,
+                } => Arc::new(BoxShape::new(min.into(), max.into(), material(key, &name)?)),
+                ShapeDescription::Mesh {
+                    positions,
+                    indices,
+                    normals,
+                    uvs,
+                    material: name,
+                } => {
+                    let npositions = positions.len();
+                    if let Some(&bad) = indices.iter().flatten().find(|&&i| i >= npositions) {
+                        bail!(
+                            "Index {} is out of range for {} positions in key `{}.indices`",
+                            bad,
+                            npositions,
+                            key
+                        );
+                    }
+                    for (field, len) in [
+                        ("normals", normals.as_ref().map(Vec::len)),
+                        ("uvs", uvs.as_ref().map(Vec::len)),
+                    ] {
+                        if let Some(len) = len.filter(|&len| len != npositions) {
+                            bail!(
+                                "Expected {} entries in key `{}.{}`, one per position, found {}",
+                                npositions,
+                                key,
+                                field,
+                                len
+                            );
+                        }
+                    }
+                    Arc::new(TriangleMesh::new(
+                        to_vec3s(positions),
+                        indices,
+                        normals.map(to_vec3s),
+                        uvs,
+                        material(key, &name)?,
+                    ))
+                }
+                ShapeDescription::GridMedium {
+                    grid,
+                    absorption,
+                    scattering,
+                    emission,
+                    transfer,
+                    material: name,
+                } => {
+                    let grid = grid
+                        .build(base_dir, seed)
+                        .with_context(|| format!("Unable to load key `{}.grid`", key))?;
+                    let transfer = if transfer.is_empty() {
+                        TransferFunction::constant(Vec3::ones())
+                    } else {
+                        TransferFunction::new(
+                            transfer
+                                .into_iter()
+                                .map(|stop| (stop.density, stop.color.into()))
+                                .collect(),
+                        )
+                    };
+                    let coefficients = Coefficients {
+                        absorption,
+                        scattering,
+                        emission,
+                    };
+                    Arc::new(GridMedium::new(
+                        grid,
+                        coefficients,
+                        transfer,
+                        material(key, &name)?,
+                    ))
+                }
+                ShapeDescription::Obj { path } => {
+                    let model = obj::load_obj(base_dir.join(&path))
+                        .with_context(|| format!("Unable to load key `{}.path`", key))?;
+                    // the emissive meshes within are sampled, not the whole model
+                    for light in model.lights {
+                        lights.push(light as SharedHittable);
+                    }
+                    Arc::new(model.meshes)
+                }
+                ShapeDescription::Instance { .. } | ShapeDescription::ConstantMedium { .. } => {
+                    bail!(
+                        "Instances and media can only be placed in `shapes`, not key `{}`",
+                        key
+                    )
+                }
+            };
+            if emissive {
+                lights.push(Arc::clone(&shape));
+            }
+            Ok((shape, lights))
+        };
+
+        let mut objects = BTreeMap::new();
+        for (name, value) in self.objects {
+            let key = format!("objects.{}", name);
+            let shape: ShapeDescription = value
+                .try_into()
+                .with_context(|| format!("Invalid key `{}`", key))?;
+            objects.insert(name, build_shape(&key, shape)?);
+        }
+
+        let find_object = |key: &str, name: &str| match objects.get(name) {
+            Some(entry) => Ok(entry),
+            None => bail!(
+                "Unknown object {:?} for key `{}`, expected one of: {}",
+                name,
+                key,
+                objects.keys().cloned().collect::<Vec<_>>().join(", ")
+            ),
+        };
+
+        let mut shapes = Vec::with_capacity(self.shapes.len());
+        let mut lights = Vec::new();
+        for (index, value) in self.shapes.into_iter().enumerate() {
+            let key = format!("shapes[{}]", index);
+            let shape: ShapeDescription = value
+                .try_into()
+                .with_context(|| format!("Invalid key `{}`", key))?;
+            let (shape, shape_lights) = match shape {
+                ShapeDescription::Instance {
+                    object,
+                    end,
+                    transform,
+                } => {
+                    let (shape, object_lights) = find_object(&format!("{}.object", key), &object)?;
+                    let transform = transform
+                        .matrix()
+                        .with_context(|| format!("Invalid key `{}`", key))?;
+                    let end = end
+                        .map(|end| -> Result<_> {
+                            let end = end.matrix()?;
+                            transform::check_motion(&transform, &end)?;
+                            Ok(end)
+                        })
+                        .transpose()
+                        .with_context(|| format!("Invalid key `{}.end`", key))?;
+                    // the object's lights are placed along with it
+                    let place = |shape: &SharedHittable| -> SharedHittable {
+                        match end {
+                            Some(end) => {
+                                Arc::new(Instance::moving(Arc::clone(shape), transform, end))
+                            }
+                            None => Arc::new(Instance::new(Arc::clone(shape), transform)),
+                        }
+                    };
+                    (place(shape), object_lights.iter().map(place).collect())
+                }
+                ShapeDescription::ConstantMedium {
+                    boundary,
+                    density,
+                    material: name,
+                } => {
+                    let (boundary, _) = find_object(&format!("{}.boundary", key), &boundary)?;
+                    let medium: SharedHittable = Arc::new(ConstantMedium::new(
+                        Arc::clone(boundary),
+                        density,
+                        material(&key, &name)?,
+                    ));
+                    (medium, Vec::new())
+                }
+                shape => build_shape(&key, shape)?,
+            };
+            lights.extend(shape_lights);
+            shapes.push(shape);
+        }
+
+        let mut delta_lights = Vec::with_capacity(self.lights.len());
+        for (index, value) in self.lights.into_iter().enumerate() {
+            let key = format!("lights[{}]", index);
+            let light: LightDescription = value
+                .try_into()
+                .with_context(|| format!("Invalid key `{}`", key))?;
+            delta_lights.push(
+                light
+                    .build()
+                    .with_context(|| format!("Invalid key `{}`", key))?,
+            );
+        }
+
+        let environment: Arc<dyn Environment + Send + Sync> =
+            match (self.background, self.environment) {
+                (Some(_), Some(_)) => {
+                    bail!("Expected either `background` or `environment`, not both")
+                }
+                (Some(color), None) => Arc::new(Constant::new(color.into())),
+                (None, Some(environment)) => environment
+                    .build(base_dir)
+                    .context("Unable to load key `environment`")?,
+                (None, None) => Arc::new(environment::Gradient::default()),
+            };
+
+        Ok(Scene {
+            world: Bvh::new(shapes),
+            camera: self.camera,
+            render: self.render,
+            environment,
+            lights,
+            delta_lights,
+        })
+    }
+}
+
+impl Scene {
+    /// Parse a scene from TOML source, resolving relative paths against
+    /// `base_dir` and seeding noise that doesn't give its own seed with
+    /// `seed`.
+    pub fn from_toml(source: &str, base_dir: &Path, seed: u64) -> Result<Self> {
+        let file: SceneFile = toml::from_str(source)?;
+        file.build(base_dir, seed)
+    }
+
+    /// Load a scene file, seeding its noise as `from_toml` does.
+    pub fn load(path: impl AsRef<Path>, seed: u64) -> Result<Self> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read scene {}", path.display()))?;
+        Self::from_toml(
+            &source,
+            path.parent().unwrap_or_else(|| Path::new("")),
+            seed,
+        )
+        .with_context(|| format!("Unable to load scene {}", path.display()))
+    }
+}
+
+/// The cover scene of "Ray Tracing in One Weekend": three large spheres
+/// surrounded by a grid of small, randomly placed ones.
+pub fn random_scene(ball_density: i32, rng: &mut Rng) -> Scene {
+    let ground = Plane::new(
+        Vec3::zeros(),
+        vec3![0, 1, 0],
+        Lambertian::new(vec3![0.5, 0.5, 0.5]),
+    );
+    let world = Bvh::new(
+        vec![
+            Sphere::new(vec3![-4, 1, 0], 1.0, Lambertian::new(vec3![0.4, 0.2, 0.1])),
+            Sphere::new(vec3![0, 1, 0], 1.0, Dielectric::new(1.5)),
+            Sphere::new(vec3![4, 1, 0], 1.0, Metal::new(vec3![0.7, 0.6, 0.5], 0.0)),
+        ]
+        .into_iter()
+        .chain(
+            itertools::iproduct!(-ball_density..ball_density, -ball_density..ball_density)
+                .filter_map(|(a, b)| {
+                    let center = vec3![a as f64 + 0.9 * rand(rng), 0.2, b as f64 + 0.9 * rand(rng)];
+                    if (center - vec3![4, 0.2, 0]).norm() <= 0.9 {
+                        return None;
+                    }
+
+                    Some(match rand(rng) {
+                        chosen if chosen < 0.8 => {
+                            Sphere::new(center, 0.2, Lambertian::new(randvec(rng) * randvec(rng)))
+                        }
+                        chosen if chosen < 0.95 => Sphere::new(
+                            center,
+                            0.2,
+                            Metal::new((randvec(rng) + 1.0) * 0.5, 0.5 * rand(rng)),
+                        ),
+                        _ => Sphere::new(center, 0.2, Dielectric::new(1.5)),
+                    })
+                }),
+        )
+        .map(|sphere| Arc::new(sphere) as Arc<dyn Hittable + Send + Sync>)
+        .chain(std::iter::once(
+            Arc::new(ground) as Arc<dyn Hittable + Send + Sync>
+        ))
+        .collect::<Vec<_>>(),
+    );
+    Scene {
+        world,
+        camera: None,
+        render: Default::default(),
+        environment: Arc::new(environment::Gradient::default()),
+        lights: Vec::new(),
+        delta_lights: Vec::new(),
+    }
+}
+
+/// The parallelogram with consecutive corners `a`, `b`, `c` and `d`.
+fn quad([a, b, _, d]: [Vec3; 4], material: &Arc<dyn Material + Send + Sync>) -> Quad {
+    Quad::new(a, b - a, d - a, Arc::clone(material))
+}
+
+/// A box spanning `size` from the origin, rotated about the y axis by
+/// `angle` degrees and then moved by `offset`.
+fn rotated_box(
+    size: Vec3,
+    angle: f64,
+    offset: Vec3,
+    material: &Arc<dyn Material + Send + Sync>,
+) -> Arc<dyn Hittable + Send + Sync> {
+    let cube = BoxShape::new(Vec3::zeros(), size, Arc::clone(material));
+    Arc::new(Instance::new(
+        Arc::new(cube),
+        transform::translation(offset) * transform::rotation(vec3![0, 1, 0], angle),
+    ))
+}
+
+/// The Cornell box, lit only by the area light in its ceiling.
+pub fn cornell_box() -> Scene {
+    let red: Arc<dyn Material + Send + Sync> = Arc::new(Lambertian::new(vec3![0.65, 0.05, 0.05]));
+    let white: Arc<dyn Material + Send + Sync> = Arc::new(Lambertian::new(vec3![0.73, 0.73, 0.73]));
+    let green: Arc<dyn Material + Send + Sync> = Arc::new(Lambertian::new(vec3![0.12, 0.45, 0.15]));
+    let light: Arc<dyn Material + Send + Sync> = Arc::new(DiffuseLight::new(vec3![15, 15, 15]));
+
+    let ceiling_light = Arc::new(quad(
+        [
+            vec3![213, 554, 227],
+            vec3![343, 554, 227],
+            vec3![343, 554, 332],
+            vec3![213, 554, 332],
+        ],
+        &light,
+    ));
+
+    let world = Bvh::<Arc<dyn Hittable + Send + Sync>>::new(vec![
+        Arc::new(quad(
+            [
+                vec3![555, 0, 0],
+                vec3![555, 555, 0],
+                vec3![555, 555, 555],
+                vec3![555, 0, 555],
+            ],
+            &green,
+        )),
+        Arc::new(quad(
+            [
+                vec3![0, 0, 0],
+                vec3![0, 0, 555],
+                vec3![0, 555, 555],
+                vec3![0, 555, 0],
+            ],
+            &red,
+        )),
+        Arc::clone(&ceiling_light) as Arc<dyn Hittable + Send + Sync>,
+        Arc::new(quad(
+            [
+                vec3![0, 0, 0],
+                vec3![555, 0, 0],
+                vec3![555, 0, 555],
+                vec3![0, 0, 555],
+            ],
+            &white,
+        )),
+        Arc::new(quad(
+            [
+                vec3![0, 555, 0],
+                vec3![0, 555, 555],
+                vec3![555, 555, 555],
+                vec3![555, 555, 0],
+            ],
+            &white,
+        )),
+        Arc::new(quad(
+            [
+                vec3![0, 0, 555],
+                vec3![555, 0, 555],
+                vec3![555, 555, 555],
+                vec3![0, 555, 555],
+            ],
+            &white,
+        )),
+        rotated_box(vec3![165, 330, 165], 15.0, vec3![265, 0, 295], &white),
+        rotated_box(vec3![165, 165, 165], -18.0, vec3![130, 0, 65], &white),
+    ]);
+
+    Scene {
+        world,
+        camera: Some(CameraSettings {
+            look_from: [278.0, 278.0, -800.0],
+            look_at: [278.0, 278.0, 0.0],
+            vup: default_vup(),
+            fov: 40.0,
+            aperture: 0.0,
+            focus_distance: Some(10.0),
+            shutter: [0.0, 0.0],
+        }),
+        render: RenderSettings {
+            width: Some(400),
+            height: Some(400),
+            samples: Some(200),
+            ..Default::default()
+        },
+        environment: Arc::new(Constant::new(Vec3::zeros())),
+        lights: vec![ceiling_light],
+        delta_lights: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CameraSettings, NoiseDescription, Scene};
+    use crate::{noise::Noise, utils::seeded_rng, Hittable, Ray, Vec3};
+    use std::path::Path;
+
+    fn error_of(source: &str) -> String {
+        match Scene::from_toml(source, Path::new(""), 0) {
+            Ok(_) => panic!("expected an error"),
+            Err(error) => format!("{:#}", error),
+        }
+    }
+
+    #[test]
+    fn test_example_scene() {
+        let scene = Scene::load(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/scenes/three_spheres.toml"),
+            0,
+        )
+        .unwrap();
+        let camera = scene.camera.unwrap();
+        assert_eq!(camera.vup, [0.0, 1.0, 0.0]);
+        assert_eq!(camera.fov, 30.0);
+        assert_eq!(scene.render.samples, Some(64));
+        let ray = Ray::new(vec3![0, 1, 10], vec3![0, 0, -1]);
+        assert!(scene.world.hit(ray, 0.001, f64::MAX).is_some());
+    }
+
+    #[test]
+    fn test_cornell_box_walls() {
+        let scene = super::cornell_box();
+        let origin = vec3![278, 400, 278];
+        let rng = &mut crate::utils::seeded_rng(0);
+        for _ in 0..1000 {
+            // the front of the box is open towards the camera at -z
+            let dir = crate::utils::randvec(rng) - 0.5;
+            let ray = Ray::new(origin, vec3![dir.x(), dir.y(), dir.z().abs()]);
+            assert!(scene.world.hit(ray, 0.001, f64::MAX).is_some());
+        }
+        // straight up into the light
+        let rec = scene
+            .world
+            .hit(Ray::new(origin, vec3![0, 1, 0]), 0.001, f64::MAX)
+            .unwrap();
+        assert_eq!(rec.material.emitted(&rec), vec3![15, 15, 15]);
+    }
+
+    #[test]
+    fn test_camera_defaults() {
+        let camera: CameraSettings =
+            toml::from_str("look_from = [0, 0, 4]\nlook_at = [0, 0, 0]\n").unwrap();
+        assert_eq!(camera.vup, [0.0, 1.0, 0.0]);
+        assert_eq!(camera.fov, 20.0);
+        assert_eq!(camera.aperture, 0.0);
+        assert_eq!(camera.focus_distance, None);
+    }
+
+    #[test]
+    fn test_errors_point_at_key() {
+        let error =
+            error_of("[camera]\nlook_from = [0, 0, 4]\nlook_at = [0, 0, 0]\nfov = \"wide\"\n");
+        assert!(error.contains("camera.fov"), "{}", error);
+
+        let error = error_of(
+            "[materials.glass]\ntype = \"dielectric\"\nref_idx = 1.5\n\n\
+             [[shapes]]\ntype = \"sphere\"\ncenter = [0, 0, 0]\nradius = 1\nmaterial = \"glas\"\n",
+        );
+        assert_eq!(
+            error,
+            "Unknown material \"glas\" for key `shapes[0].material`, expected one of: glass"
+        );
+
+        let error = error_of(
+            "[materials.red]\ntype = \"lambertian\"\nalbedo = [1, 0, 0]\n\n\
+             [[shapes]]\ntype = \"mesh\"\npositions = [[0, 0, 0]]\nindices = [[0, 0, 1]]\nmaterial = \"red\"\n",
+        );
+        assert!(error.contains("`shapes[0].indices`"), "{}", error);
+
+        let error = error_of(
+            "[materials.red]\ntype = \"lambertian\"\nalbedo = [1, 0, 0]\n\n\
+             [[shapes]]\ntype = \"sphere\"\ncenter = [0, 0, 0]\nradius = 1\nmaterial = \"red\"\n\n\
+             [[shapes]]\ntype = \"sphere\"\ncenter = [0, 0, 0]\nmaterial = \"red\"\n",
+        );
+        assert_eq!(error, "Invalid key `shapes[1]`: missing field `radius`");
+
+        let error = error_of(
+            "[textures.tiles]\ntype = \"checker\"\neven = [1, 1, 1]\nodd = [0, 0, 0]\n\n\
+             [materials.floor]\ntype = \"lambertian\"\nalbedo = \"tile\"\n",
+        );
+        assert_eq!(
+            error,
+            "Unknown texture \"tile\" for key `materials.floor.albedo`, expected one of: tiles"
+        );
+    }
+
+    #[test]
+    fn test_noise_textures() {
+        let source = |noise: &str| {
+            format!(
+                "[textures.stone]\ntype = \"marble\"\n{}scale = 4\n\
+                 light = [1, 1, 1]\ndark = [0, 0, 0]\n",
+                noise
+            )
+        };
+        for noise in &[
+            "",
+            "noise = { type = \"perlin\", smoothing = \"trilinear\" }\n",
+            "noise = { type = \"simplex\", seed = 3 }\n",
+        ] {
+            Scene::from_toml(&source(noise), Path::new(""), 0).unwrap();
+        }
+        let error = error_of(&source(
+            "noise = { type = \"simplex\", smoothing = \"hermite\" }\n",
+        ));
+        assert!(error.contains("`textures.stone`"), "{}", error);
+    }
+
+    #[test]
+    fn test_noise_follows_render_seed() {
+        let point = vec3![0.3, 1.7, -2.1];
+        let noise = |description: &str, render_seed| {
+            toml::from_str::<NoiseDescription>(description)
+                .unwrap()
+                .build(render_seed)
+                .noise(point)
+        };
+        let perlin = "type = \"perlin\"";
+        assert_eq!(noise(perlin, 1), noise(perlin, 1));
+        assert_ne!(noise(perlin, 1), noise(perlin, 2));
+        // unless the noise brings its own seed
+        let simplex = "type = \"simplex\"\nseed = 3";
+        assert_eq!(noise(simplex, 1), noise(simplex, 2));
+    }
+
+    #[test]
+    fn test_instances() {
+        let source = |transform: &str| {
+            format!(
+                "[materials.lamp]\ntype = \"diffuse_light\"\nemit = [1, 1, 1]\n\n\
+                 [objects.ball]\ntype = \"sphere\"\ncenter = [0, 0, 0]\nradius = 1\nmaterial = \"lamp\"\n\n\
+                 [[shapes]]\ntype = \"instance\"\nobject = \"ball\"\n{}\n\n\
+                 [[shapes]]\ntype = \"instance\"\nobject = \"ball\"\ntranslate = [0, 0, -10]\n",
+                transform
+            )
+        };
+        let scene = Scene::from_toml(
+            &source("scale = [2, 1, 1]\nrotate = { axis = [0, 0, 1], degrees = 90 }\ntranslate = [5, 0, 0]"),
+            Path::new(""), 0,
+        )
+        .unwrap();
+        assert_eq!(scene.lights.len(), 2);
+        // the ellipsoid is stood upright, so is two tall at x = 5
+        let rec = scene
+            .world
+            .hit(Ray::new(vec3![5, 10, 0], vec3![0, -1, 0]), 0.001, f64::MAX)
+            .unwrap();
+        assert!((rec.point - vec3![5, 2, 0]).norm() < 1e-9);
+        assert!(scene
+            .world
+            .hit(Ray::new(vec3![0, 0, 5], vec3![0, 0, -1]), 0.001, f64::MAX)
+            .is_some());
+
+        let error = error_of(&source("scale = [1, 0, 1]"));
+        assert_eq!(
+            error,
+            "Invalid key `shapes[0]`: Expected an invertible transform"
+        );
+        let error = error_of(&source("translat = [1, 0, 1]"));
+        assert!(error.contains("`shapes[0]`"), "{}", error);
+        let error = error_of(&source("").replace("object = \"ball\"", "object = \"bal\""));
+        assert_eq!(
+            error,
+            "Unknown object \"bal\" for key `shapes[0].object`, expected one of: ball"
+        );
+    }
+
+    #[test]
+    fn test_obj_lights() {
+        let dir = std::env::temp_dir().join(format!("raytracer-scene-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("lamp.mtl"),
+            "newmtl glow\nKe 4 4 4\nnewmtl grey\nKd 0.5 0.5 0.5\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("lamp.obj"),
+            "mtllib lamp.mtl\nv -1 -1 0\nv 1 -1 0\nv 0 1 0\nusemtl glow\nf 1 2 3\n\
+             v -1 -1 2\nv 1 -1 2\nv 0 1 2\nusemtl grey\nf 4 5 6\n",
+        )
+        .unwrap();
+        let scene = Scene::from_toml(
+            "[objects.lamp]\ntype = \"obj\"\npath = \"lamp.obj\"\n\n\
+             [[shapes]]\ntype = \"obj\"\npath = \"lamp.obj\"\n\n\
+             [[shapes]]\ntype = \"instance\"\nobject = \"lamp\"\ntranslate = [0, 10, 0]\n",
+            &dir,
+            0,
+        )
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        // only the glowing triangle of each copy is sampled, where it's placed
+        assert_eq!(scene.lights.len(), 2);
+        let point = scene.lights[1]
+            .sample_point(Vec3::zeros(), 0.0, &mut seeded_rng(0))
+            .unwrap();
+        assert!(point.y() >= 9.0, "{:?}", point);
+    }
+
+    #[test]
+    fn test_motion() {
+        let scene = Scene::from_toml(
+            "[camera]\nlook_from = [0, 0, 10]\nlook_at = [0, 0, 0]\nshutter = [0, 1]\n\n\
+             [materials.grey]\ntype = \"lambertian\"\nalbedo = [0.5, 0.5, 0.5]\n\n\
+             [objects.ball]\ntype = \"sphere\"\ncenter = [0, 0, 0]\nradius = 1\nmaterial = \"grey\"\n\n\
+             [[shapes]]\ntype = \"moving_sphere\"\nstart = [0, 0, 0]\nend = [4, 0, 0]\nradius = 1\nmaterial = \"grey\"\n\n\
+             [[shapes]]\ntype = \"instance\"\nobject = \"ball\"\ntranslate = [0, 5, 0]\nend = { translate = [4, 5, 0] }\n",
+            Path::new(""), 0,
+        )
+        .unwrap();
+        assert_eq!(scene.camera.as_ref().unwrap().shutter, [0.0, 1.0]);
+        for &y in &[0.0, 5.0] {
+            let hit = |time| {
+                let ray = Ray::with_time(vec3![4, y, 10], vec3![0, 0, -1], time);
+                scene.world.hit(ray, 0.001, f64::MAX).is_some()
+            };
+            assert!(!hit(0.0));
+            assert!(hit(1.0));
+        }
+
+        let error = error_of(
+            "[materials.grey]\ntype = \"lambertian\"\nalbedo = [0.5, 0.5, 0.5]\n\n\
+             [objects.ball]\ntype = \"sphere\"\ncenter = [0, 0, 0]\nradius = 1\nmaterial = \"grey\"\n\n\
+             [[shapes]]\ntype = \"instance\"\nobject = \"ball\"\n\
+             end = { rotate = { axis = [0, 1, 0], degrees = 180 } }\n",
+        );
+        assert_eq!(
+            error,
+            "Invalid key `shapes[0].end`: \
+             Expected the start and end to be rotated less than half a turn apart"
+        );
+    }
+
+    #[test]
+    fn test_media() {
+        let source = |boundary: &str| {
+            format!(
+                "[materials.glass]\ntype = \"dielectric\"\nref_idx = 1.5\n\n\
+                 [materials.smoke]\ntype = \"henyey_greenstein\"\nalbedo = [0.8, 0.8, 0.8]\ng = 0.5\n\n\
+                 [objects.ball]\ntype = \"sphere\"\ncenter = [0, 0, 0]\nradius = 1\nmaterial = \"glass\"\n\n\
+                 [[shapes]]\ntype = \"sphere\"\ncenter = [0, 0, 0]\nradius = 1\nmaterial = \"glass\"\n\n\
+                 [[shapes]]\ntype = \"constant_medium\"\nboundary = \"{}\"\ndensity = 2\nmaterial = \"smoke\"\n",
+                boundary
+            )
+        };
+        let scene = Scene::from_toml(&source("ball"), Path::new(""), 0).unwrap();
+        assert!(scene.lights.is_empty());
+        // the glass is still what rays hit, with the smoke inside it
+        let ray = Ray::new(vec3![0, 0, 5], vec3![0, 0, -1]);
+        assert_eq!(
+            scene.world.hit(ray, 0.001, f64::MAX).map(|rec| rec.t),
+            Some(4.0)
+        );
+        let hit = scene.world.medium_hit(ray, 0.001, f64::MAX).unwrap();
+        assert_eq!((hit.t_enter, hit.t_exit), (4.0, 6.0));
+        let transmittance = hit
+            .medium
+            .transmittance(&hit.ray, 4.0, 6.0, &mut seeded_rng(0));
+        assert_eq!(transmittance, (-4.0_f64).exp());
+
+        let error = error_of(&source("bal"));
+        assert_eq!(
+            error,
+            "Unknown object \"bal\" for key `shapes[1].boundary`, expected one of: ball"
+        );
+    }
+
+    #[test]
+    fn test_grid_media() {
+        let source = |grid: &str| {
+            format!(
+                "[materials.fog]\ntype = \"isotropic\"\nalbedo = [1, 1, 1]\n\n\
+                 [objects.cloud]\ntype = \"grid_medium\"\ngrid = {}\nemission = 2\n\
+                 transfer = [{{ density = 0, color = [0, 0, 1] }}, {{ density = 1, color = [1, 0, 0] }}]\n\
+                 material = \"fog\"\n\n\
+                 [[shapes]]\ntype = \"instance\"\nobject = \"cloud\"\ntranslate = [0, 0, -10]\n",
+                grid
+            )
+        };
+        let scene = Scene::from_toml(
+            &source(
+                "{ type = \"noise\", resolution = [8, 8, 8], min = [-1, -1, -1], max = [1, 1, 1] }",
+            ),
+            Path::new(""),
+            0,
+        )
+        .unwrap();
+        let ray = Ray::new(Vec3::zeros(), vec3![0, 0, -1]);
+        assert!(scene.world.hit(ray, 0.001, f64::MAX).is_none());
+        let hit = scene.world.medium_hit(ray, 0.001, f64::MAX).unwrap();
+        assert_eq!((hit.t_enter, hit.t_exit), (9.0, 11.0));
+
+        let error = error_of(&source("{ type = \"file\", path = \"missing.vol\" }"));
+        assert!(
+            error.starts_with("Unable to load key `objects.cloud.grid`: Unable to read voxel grid"),
+            "{}",
+            error
+        );
+        let error = error_of(&source(
+            "{ type = \"noise\", resolution = [0, 8, 8], min = [-1, -1, -1], max = [1, 1, 1] }",
+        ));
+        assert!(error.contains("at least one point"), "{}", error);
+    }
+
+    #[test]
+    fn test_microfacet_materials() {
+        let source = |ior: &str| {
+            format!(
+                "[materials.metal]\ntype = \"conductor\"\nior = {}\nroughness = 0.3\n\n\
+                 [materials.frosted]\ntype = \"rough_dielectric\"\nref_idx = 1.5\nroughness = 0.2\n\n\
+                 [[shapes]]\ntype = \"sphere\"\ncenter = [0, 0, 0]\nradius = 1\nmaterial = \"metal\"\n\n\
+                 [[shapes]]\ntype = \"sphere\"\ncenter = [0, 0, 3]\nradius = 1\nmaterial = \"frosted\"\n",
+                ior
+            )
+        };
+        let albedo = |ior: &str| {
+            let scene = Scene::from_toml(&source(ior), Path::new(""), 0).unwrap();
+            let ray = Ray::new(vec3![0, 0, -5], vec3![0, 0, 1]);
+            let rec = scene.world.hit(ray, 0.001, f64::MAX).unwrap();
+            rec.material.albedo(&rec)
+        };
+        // a perfect mirror reflects everything
+        assert!((albedo("{ eta = [0, 0, 0], k = [1e6, 1e6, 1e6] }") - Vec3::ones()).norm() < 1e-5);
+        let gold = albedo("\"gold\"");
+        assert!(gold.x() > gold.y() && gold.y() > gold.z());
+
+        let error = error_of(&source("\"tin\""));
+        assert!(
+            error.starts_with("Invalid key `materials.metal`"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_principled() {
+        let source = |parameters: &str| {
+            format!(
+                "[materials.paint]\ntype = \"principled\"\nbase_color = [0.8, 0.1, 0.1]\n{}\n\
+                 [[shapes]]\ntype = \"sphere\"\ncenter = [0, 0, 0]\nradius = 1\nmaterial = \"paint\"\n",
+                parameters
+            )
+        };
+        let scene = Scene::from_toml(
+            &source("clearcoat = 1\nroughness = 0.2\n"),
+            Path::new(""),
+            0,
+        )
+        .unwrap();
+        let ray = Ray::new(vec3![0, 0, -5], vec3![0, 0, 1]);
+        let rec = scene.world.hit(ray, 0.001, f64::MAX).unwrap();
+        assert_eq!(rec.material.albedo(&rec), vec3![0.8, 0.1, 0.1]);
+        let scattered = Ray::new(rec.point, vec3![0, 0.1, -1]);
+        assert!(rec.material.pdf(&ray, &rec, &scattered) > 0.0);
+
+        let error = error_of(&source("metalic = 1\n"));
+        assert!(error.contains("metalic"), "{}", error);
+    }
+
+    #[test]
+    fn test_dielectrics() {
+        let source = |ref_idx: &str| {
+            format!(
+                "[materials.glass]\ntype = \"dielectric\"\nref_idx = {}\nabsorption = [0, 0.5, 0.5]\n\n\
+                 [[shapes]]\ntype = \"sphere\"\ncenter = [0, 0, 0]\nradius = 1\nmaterial = \"glass\"\n",
+                ref_idx
+            )
+        };
+        let rng = &mut seeded_rng(0);
+        for ref_idx in [
+            "1.5",
+            "{ type = \"cauchy\", a = 1.5, b = 0.004 }",
+            "{ type = \"sellmeier\", b = [1.04, 0.23, 1.01], c = [0.006, 0.02, 103.6] }",
+        ] {
+            let scene = Scene::from_toml(&source(ref_idx), Path::new(""), 0).unwrap();
+            // leaving the sphere after crossing it absorbs green and blue
+            let ray = Ray::new(vec3![0, 0, -1], vec3![0, 0, 1]).with_wavelength(600.0);
+            let rec = scene.world.hit(ray, 0.001, f64::MAX).unwrap();
+            let (weight, _) = rec.material.scatter(&ray, &rec, rng).unwrap();
+            assert_eq!(weight, vec3![1, (-1.0_f64).exp(), (-1.0_f64).exp()]);
+        }
+
+        let error = error_of(&source("{ type = \"cauchy\", a = 1.5 }"));
+        assert!(
+            error.starts_with("Invalid key `materials.glass`"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_environments() {
+        let up = vec3![0, 1, 0];
+        let radiance = |source: &str, base_dir: &Path| {
+            let scene = Scene::from_toml(source, base_dir, 0).unwrap();
+            scene.environment.radiance(up)
+        };
+        assert_eq!(radiance("", Path::new("")), vec3![0.5, 0.7, 1.0]);
+        assert_eq!(
+            radiance("background = [1, 2, 3]", Path::new("")),
+            vec3![1, 2, 3]
+        );
+        assert_eq!(
+            radiance(
+                "environment = { type = \"gradient\", top = [0, 0, 4] }",
+                Path::new("")
+            ),
+            vec3![0, 0, 4]
+        );
+
+        // a 1x2 image, bright above and dark below
+        let dir = std::env::temp_dir().join(format!("raytracer-env-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut pfm = b"PF\n1 2\n-1.0\n".to_vec();
+        for value in [0.0_f32, 0.0, 0.0, 3.0, 2.0, 1.0] {
+            pfm.extend_from_slice(&value.to_le_bytes());
+        }
+        std::fs::write(dir.join("sky.pfm"), pfm).unwrap();
+        let image = "environment = { type = \"image\", path = \"sky.pfm\", intensity = 2 }";
+        assert_eq!(radiance(image, &dir), vec3![6, 4, 2]);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let error = error_of(image);
+        assert!(
+            error.starts_with("Unable to load key `environment`"),
+            "{}",
+            error
+        );
+        let error = error_of(
+            "background = [0, 0, 0]\nenvironment = { type = \"constant\", color = [1, 1, 1] }",
+        );
+        assert!(error.contains("not both"), "{}", error);
+
+        // the sun in the south, by angles or at noon in London in June
+        let sky = |sun: &str| {
+            format!(
+                "environment = {{ type = \"sky\", turbidity = 2.5, sun = {} }}",
+                sun
+            )
+        };
+        let south = radiance(&sky("{ elevation = 30, azimuth = 180 }"), Path::new(""));
+        assert!(south.z() > 0.0);
+        let up = |source: &str| {
+            let scene = Scene::from_toml(source, Path::new(""), 0).unwrap();
+            (
+                scene.environment.radiance(vec3![0, 1, 0]),
+                scene.environment.radiance(vec3![0, 0.3, 1]),
+            )
+        };
+        let (zenith, towards_sun) = up(&sky(
+            "{ latitude = 51.5, longitude = 0, day = 172, time = 13, utc_offset = 1 }",
+        ));
+        assert!(towards_sun.y() > zenith.y());
+        let error = error_of(&sky("{ elevation = 30 }"));
+        assert!(error.contains("SunPosition"), "{}", error);
+        let error = error_of(
+            "environment = { type = \"sky\", turbidity = 0.5, sun = { elevation = 30, azimuth = 0 } }",
+        );
+        assert!(error.contains("turbidity from 1.7 to 10"), "{}", error);
+        let error = error_of(&sky("{ elevation = 30, azimuth = 0, day = 3 }"));
+        assert!(error.contains("SunPosition"), "{}", error);
+    }
+
+    #[test]
+    fn test_punctual_lights() {
+        let scene = Scene::from_toml(
+            "[[lights]]\ntype = \"point\"\nposition = [0, 4, 0]\nintensity = [16, 16, 16]\n\n\
+             [[lights]]\ntype = \"spot\"\nposition = [0, 4, 0]\ndirection = [0, -1, 0]\n\
+             intensity = [16, 16, 16]\ninner_angle = 20\nouter_angle = 30\n\n\
+             [[lights]]\ntype = \"directional\"\ndirection = [0, -1, 0]\nirradiance = [1, 2, 3]\n",
+            Path::new(""),
+            0,
+        )
+        .unwrap();
+        assert!(scene.lights.is_empty());
+        let irradiance = |point: Vec3| {
+            scene
+                .delta_lights
+                .iter()
+                .map(|light| {
+                    light
+                        .illuminate(point)
+                        .map_or(Vec3::zeros(), |illumination| illumination.irradiance)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            irradiance(Vec3::zeros()),
+            vec![vec3![1, 1, 1], vec3![1, 1, 1], vec3![1, 2, 3]]
+        );
+        assert_eq!(irradiance(vec3![4, 0, 0])[1], Vec3::zeros());
+
+        let error = error_of(
+            "[[lights]]\ntype = \"spot\"\nposition = [0, 0, 0]\ndirection = [0, -1, 0]\n\
+             intensity = [1, 1, 1]\ninner_angle = 40\nouter_angle = 30\n",
+        );
+        assert_eq!(
+            error,
+            "Invalid key `lights[0]`: Expected 0 <= inner_angle <= outer_angle <= 180, got 40 and 30"
+        );
+        let error = error_of("[[lights]]\ntype = \"point\"\nposition = [0, 0, 0]\n");
+        assert!(error.starts_with("Invalid key `lights[0]`"), "{}", error);
+    }
+
+    #[test]
+    fn test_textured_material() {
+        let scene = Scene::from_toml(
+            "[textures.sky]\ntype = \"gradient\"\nstart = [1, 1, 1]\nend = [0, 0, 1]\n\n\
+             [materials.ball]\ntype = \"lambertian\"\nalbedo = \"sky\"\n\n\
+             [[shapes]]\ntype = \"sphere\"\ncenter = [0, 0, 0]\nradius = 1\nmaterial = \"ball\"\n",
+            Path::new(""),
+            0,
+        )
+        .unwrap();
+        // the north pole of a sphere has v = 1
+        let rec = scene
+            .world
+            .hit(Ray::new(vec3![0, 5, 0], vec3![0, -1, 0]), 0.001, f64::MAX)
+            .unwrap();
+        assert!((rec.material.albedo(&rec) - vec3![0, 0, 1]).norm() < 1e-9);
+    }
+}
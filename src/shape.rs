@@ -1,17 +1,191 @@
-use crate::{HitRecord, Material, Ray, Vec3};
+use crate::{
+    utils::{self, Rng},
+    Aabb, HitRecord, Material, MediumHit, Ray, Vec3,
+};
+use std::{f64::consts::PI, sync::Arc};
 
 pub trait Hittable {
-    fn hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<HitRecord>;
+    fn hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>>;
+
+    /// Whether `ray` hits anything within `(t_min, t_max)`, which can stop at
+    /// the first hit found rather than the closest and skip filling in a
+    /// `HitRecord`, as shadow rays need.
+    fn occluded(&self, ray: Ray, t_min: f64, t_max: f64) -> bool {
+        self.hit(ray, t_min, t_max).is_some()
+    }
+
+    /// The box enclosing the hittable, or `None` if it is unbounded.
+    fn bounding_box(&self) -> Option<Aabb>;
+
+    /// Sample a point on the surface visible from `origin` at `time`, so
+    /// that the hittable can be used as a light. `None` if it can't be
+    /// sampled.
+    fn sample_point(&self, _origin: Vec3, _time: f64, _rng: &mut Rng) -> Option<Vec3> {
+        None
+    }
+
+    /// The solid angle density with which `sample_point` generates
+    /// `direction` from `origin` at `time`.
+    fn pdf_value(&self, _origin: Vec3, _direction: Vec3, _time: f64) -> f64 {
+        0.0
+    }
+
+    /// The nearest stretch of `ray` within `[t_min, t_max]` that passes
+    /// through a participating medium. Media have no surface, so `hit` never
+    /// finds them.
+    fn medium_hit(&self, _ray: Ray, _t_min: f64, _t_max: f64) -> Option<MediumHit<'_>> {
+        None
+    }
+}
+
+macro_rules! forward_hittable {
+    ($pointer:ident) => {
+        impl<H> Hittable for $pointer<H>
+        where
+            H: Hittable + ?Sized,
+        {
+            fn hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+                self.as_ref().hit(ray, t_min, t_max)
+            }
+
+            fn occluded(&self, ray: Ray, t_min: f64, t_max: f64) -> bool {
+                self.as_ref().occluded(ray, t_min, t_max)
+            }
+
+            fn bounding_box(&self) -> Option<Aabb> {
+                self.as_ref().bounding_box()
+            }
+
+            fn sample_point(&self, origin: Vec3, time: f64, rng: &mut Rng) -> Option<Vec3> {
+                self.as_ref().sample_point(origin, time, rng)
+            }
+
+            fn pdf_value(&self, origin: Vec3, direction: Vec3, time: f64) -> f64 {
+                self.as_ref().pdf_value(origin, direction, time)
+            }
+
+            fn medium_hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<MediumHit<'_>> {
+                self.as_ref().medium_hit(ray, t_min, t_max)
+            }
+        }
+    };
+}
+
+forward_hittable!(Box);
+forward_hittable!(Arc);
+
+/// Convert a density with respect to surface area at `point` into one with
+/// respect to solid angle as seen from `origin`.
+pub(crate) fn area_to_solid_angle(area_pdf: f64, origin: Vec3, point: Vec3, normal: Vec3) -> f64 {
+    let to_point = point - origin;
+    let distance2 = to_point.norm2();
+    let cosine = normal.dot(to_point).abs() / distance2.sqrt();
+    if cosine > 0.0 {
+        area_pdf * distance2 / cosine
+    } else {
+        0.0
+    }
+}
+
+/// Spherical `(u, v)` coordinates of a point on the unit sphere, with `v`
+/// running from the south to the north pole.
+pub(crate) fn sphere_uv(p: Vec3) -> (f64, f64) {
+    let phi = (-p.z()).atan2(p.x()) + std::f64::consts::PI;
+    let theta = (-p.y()).acos();
+    (phi / (2.0 * PI), theta / PI)
+}
+
+/// The nearest `t` within `(t_min, t_max)` at which `ray` meets the sphere.
+fn intersect_sphere(center: Vec3, radius: f64, ray: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+    let oc = ray.origin() - center;
+    let dir = ray.direction();
+    let a = dir.norm2();
+    let b = oc.dot(dir);
+    let c = oc.norm2() - radius.powi(2);
+    let disc = b.powi(2) - a * c;
+    if disc <= 0.0 {
+        return None;
+    }
+    let disc_sqrt = disc.sqrt();
+    [(-b - disc_sqrt) / a, (-b + disc_sqrt) / a]
+        .iter()
+        .copied()
+        .find(|&t| t < t_max && t > t_min)
+}
+
+fn hit_sphere<'a>(
+    center: Vec3,
+    radius: f64,
+    material: &'a dyn Material,
+    ray: Ray,
+    t_min: f64,
+    t_max: f64,
+) -> Option<HitRecord<'a>> {
+    let t = intersect_sphere(center, radius, &ray, t_min, t_max)?;
+    let point = ray.point(t);
+    let normal = (point - center) / radius;
+    let (u, v) = sphere_uv(normal);
+    Some(HitRecord {
+        t,
+        point,
+        normal,
+        u,
+        v,
+        barycentrics: None,
+        material,
+    })
+}
+
+/// Samples the cone of directions subtended by the sphere, or its whole
+/// surface from inside.
+fn sample_sphere(center: Vec3, radius: f64, origin: Vec3, rng: &mut Rng) -> Vec3 {
+    let to_center = center - origin;
+    let distance2 = to_center.norm2();
+    let radius2 = radius.powi(2);
+    if distance2 <= radius2 {
+        return center + radius * utils::random_unit_vector(rng);
+    }
+
+    let cos_theta_max = (1.0 - radius2 / distance2).sqrt();
+    let cos_theta = 1.0 + utils::rand(rng) * (cos_theta_max - 1.0);
+    let sin_theta = (1.0 - cos_theta.powi(2)).max(0.0).sqrt();
+    let phi = 2.0 * PI * utils::rand(rng);
+
+    let w = to_center.unitize();
+    let (u, v) = utils::orthonormal_basis(w);
+    let direction = sin_theta * phi.cos() * u + sin_theta * phi.sin() * v + cos_theta * w;
+    // distance along the direction to the near side of the sphere
+    let distance = distance2.sqrt();
+    let t = distance * cos_theta - (radius2 - distance2 * sin_theta.powi(2)).max(0.0).sqrt();
+    origin + t * direction
+}
+
+fn sphere_pdf(center: Vec3, radius: f64, origin: Vec3, direction: Vec3) -> f64 {
+    let ray = Ray::new(origin, direction);
+    let t = match intersect_sphere(center, radius, &ray, 0.001, f64::MAX) {
+        Some(t) => t,
+        None => return 0.0,
+    };
+    let distance2 = (center - origin).norm2();
+    let radius2 = radius.powi(2);
+    if distance2 <= radius2 {
+        let point = ray.point(t);
+        let area = 4.0 * PI * radius2;
+        area_to_solid_angle(area.recip(), origin, point, (point - center) / radius)
+    } else {
+        let cos_theta_max = (1.0 - radius2 / distance2).sqrt();
+        1.0 / (2.0 * PI * (1.0 - cos_theta_max))
+    }
 }
 
 pub struct Sphere {
     center: Vec3,
     radius: f64,
-    material: Box<dyn Material + Sync>,
+    material: Box<dyn Material + Send + Sync>,
 }
 
 impl Sphere {
-    pub fn new(center: Vec3, radius: f64, material: impl Material + Sync + 'static) -> Self {
+    pub fn new(center: Vec3, radius: f64, material: impl Material + Send + Sync + 'static) -> Self {
         Self {
             center,
             radius,
@@ -21,45 +195,94 @@ impl Sphere {
 }
 
 impl Hittable for Sphere {
-    fn hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        let radius = self.radius;
-        let center = self.center;
-
-        let oc = ray.origin() - center;
-        let dir = ray.direction();
-        let a = dir.norm2();
-        let b = oc.dot(dir);
-        let c = oc.norm2() - radius.powi(2);
-        let disc = b.powi(2) - a * c;
-        if disc > 0.0 {
-            let disc_sqrt = disc.sqrt();
-            let t = (-b - disc_sqrt) / a;
-            if t < t_max && t > t_min {
-                let point = ray.point(t);
-                Some(HitRecord {
-                    t,
-                    point,
-                    normal: (point - center) / radius,
-                    material: self.material.as_ref(),
-                })
-            } else {
-                let t = (-b + disc_sqrt) / a;
-                if t < t_max && t > t_min {
-                    let point = ray.point(t);
-                    Some(HitRecord {
-                        t,
-                        point,
-                        normal: (point - center) / radius,
-                        material: self.material.as_ref(),
-                    })
-                } else {
-                    None
-                }
-            }
-        } else {
-            None
+    fn hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        hit_sphere(
+            self.center,
+            self.radius,
+            self.material.as_ref(),
+            ray,
+            t_min,
+            t_max,
+        )
+    }
+
+    fn occluded(&self, ray: Ray, t_min: f64, t_max: f64) -> bool {
+        intersect_sphere(self.center, self.radius, &ray, t_min, t_max).is_some()
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let radius = vec3![self.radius, self.radius, self.radius];
+        Some(Aabb::new(self.center - radius, self.center + radius))
+    }
+
+    fn sample_point(&self, origin: Vec3, _time: f64, rng: &mut Rng) -> Option<Vec3> {
+        Some(sample_sphere(self.center, self.radius, origin, rng))
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, _time: f64) -> f64 {
+        sphere_pdf(self.center, self.radius, origin, direction)
+    }
+}
+
+/// A sphere moving in a straight line from `start` at time 0 to `end` at
+/// time 1, staying put outside that interval.
+pub struct MovingSphere {
+    start: Vec3,
+    end: Vec3,
+    radius: f64,
+    material: Box<dyn Material + Send + Sync>,
+}
+
+impl MovingSphere {
+    pub fn new(
+        start: Vec3,
+        end: Vec3,
+        radius: f64,
+        material: impl Material + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            start,
+            end,
+            radius,
+            material: Box::new(material),
         }
     }
+
+    fn center(&self, time: f64) -> Vec3 {
+        self.start.lerp(self.end, time.clamp(0.0, 1.0))
+    }
+}
+
+impl Hittable for MovingSphere {
+    fn hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        hit_sphere(
+            self.center(ray.time()),
+            self.radius,
+            self.material.as_ref(),
+            ray,
+            t_min,
+            t_max,
+        )
+    }
+
+    fn occluded(&self, ray: Ray, t_min: f64, t_max: f64) -> bool {
+        intersect_sphere(self.center(ray.time()), self.radius, &ray, t_min, t_max).is_some()
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let radius = vec3![self.radius, self.radius, self.radius];
+        let start = Aabb::new(self.start - radius, self.start + radius);
+        let end = Aabb::new(self.end - radius, self.end + radius);
+        Some(start.union(&end))
+    }
+
+    fn sample_point(&self, origin: Vec3, time: f64, rng: &mut Rng) -> Option<Vec3> {
+        Some(sample_sphere(self.center(time), self.radius, origin, rng))
+    }
+
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, time: f64) -> f64 {
+        sphere_pdf(self.center(time), self.radius, origin, direction)
+    }
 }
 
 pub struct HittableList<H> {
@@ -76,7 +299,7 @@ impl<H> Hittable for HittableList<H>
 where
     H: Hittable,
 {
-    fn hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
         let mut rec = None;
         let mut closest_so_far = t_max;
 
@@ -88,4 +311,66 @@ where
         }
         rec
     }
+
+    fn occluded(&self, ray: Ray, t_min: f64, t_max: f64) -> bool {
+        self.hittables
+            .iter()
+            .any(|hittable| hittable.occluded(ray, t_min, t_max))
+    }
+
+    fn medium_hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<MediumHit<'_>> {
+        let mut rec = None;
+        let mut closest_so_far = t_max;
+
+        for hittable in &self.hittables {
+            if let Some(temp_rec) = hittable.medium_hit(ray, t_min, closest_so_far) {
+                closest_so_far = temp_rec.t_enter;
+                rec = Some(temp_rec);
+            }
+        }
+        rec
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let (first, rest) = self.hittables.split_first()?;
+        rest.iter()
+            .try_fold(first.bounding_box()?, |acc, hittable| {
+                Some(acc.union(&hittable.bounding_box()?))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MovingSphere;
+    use crate::{Hittable, Lambertian, Ray, Vec3};
+
+    #[test]
+    fn test_moving_sphere() {
+        let sphere = MovingSphere::new(
+            Vec3::zeros(),
+            vec3![4, 0, 0],
+            1.0,
+            Lambertian::new(vec3![0.5, 0.5, 0.5]),
+        );
+        let hit_at = |time| {
+            let ray = Ray::with_time(vec3![2, 5, 0], vec3![0, -1, 0], time);
+            sphere.hit(ray, 0.0, f64::MAX).map(|rec| rec.t)
+        };
+        assert_eq!(hit_at(0.0), None);
+        assert_eq!(hit_at(0.5), Some(4.0));
+        assert_eq!(hit_at(1.0), None);
+        // the sphere stays at its end after time 1
+        let ray = Ray::with_time(vec3![4, 5, 0], vec3![0, -1, 0], 3.0);
+        assert!(sphere.hit(ray, 0.0, f64::MAX).is_some());
+
+        let bounds = sphere.bounding_box().unwrap();
+        assert_eq!(
+            (bounds.min(), bounds.max()),
+            (vec3![-1, -1, -1], vec3![5, 1, 1])
+        );
+        let pdf = sphere.pdf_value(vec3![2, 5, 0], vec3![0, -1, 0], 0.5);
+        assert!(pdf > 0.0);
+        assert_eq!(sphere.pdf_value(vec3![2, 5, 0], vec3![0, -1, 0], 0.0), 0.0);
+    }
 }
@@ -0,0 +1,363 @@
+//! Daylight: Preetham et al.'s analytic model of the clear sky, "A Practical
+//! Analytic Model for Daylight", with the sun's disk and the ground below the
+//! horizon, and where the sun is at a given place and time.
+//!
+//! Radiance is in kilocandelas per square metre, so the sun at noon lights the
+//! ground with roughly 100,000 lux and renders want a few stops less
+//! exposure.
+
+use crate::{
+    environment::Environment,
+    tonemap::luminance,
+    utils::{self, Rng},
+    Vec3,
+};
+use std::f64::consts::PI;
+
+/// The sun's angular radius, in radians.
+const SUN_RADIUS: f64 = 0.004_65;
+/// The sun's illuminance outside the atmosphere, in kilolux.
+const SOLAR_ILLUMINANCE: f64 = 128.0;
+/// The wavelengths, in micrometres, at which the atmosphere's transmittance
+/// gives the sun's red, green and blue.
+const WAVELENGTHS: [f64; 3] = [0.68, 0.55, 0.44];
+
+/// The coefficients A to E of Perez et al.'s sky luminance distribution.
+type Perez = [f64; 5];
+
+/// Perez et al.'s function for the relative brightness towards a point of the
+/// sky `theta` from the zenith and `gamma` from the sun.
+fn perez([a, b, c, d, e]: Perez, cos_theta: f64, gamma: f64) -> f64 {
+    // the model's singular at the horizon
+    (1.0 + a * (b / cos_theta.max(0.01)).exp())
+        * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+/// The number of steps in angle from the zenith when summing the sky's
+/// irradiance, with twice as many around the horizon.
+const IRRADIANCE_STEPS: usize = 64;
+
+/// A clear sky, lit by the sun, over a uniformly grey or coloured ground.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sky {
+    sun: Vec3,
+    /// The zenith's luminance and chromaticity.
+    zenith: [f64; 3],
+    /// The Perez coefficients of the luminance and the two chromaticities.
+    coefficients: [Perez; 3],
+    /// The Perez function towards the zenith, which the sky is relative to.
+    normalization: [f64; 3],
+    sun_radiance: Vec3,
+    ground: Vec3,
+    /// The chance of sampling a direction towards the sun rather than
+    /// uniformly.
+    sun_probability: f64,
+}
+
+impl Sky {
+    /// The sky with the sun towards `sun`, which needn't be a unit vector,
+    /// through air of the given turbidity: 2 for very clear air, 3 for a
+    /// clear day and 6 or more for haze. The ground reflects the light of the
+    /// sky and sun with `ground_albedo`. The sky is dark once the sun sets.
+    pub fn new(sun: Vec3, turbidity: f64, ground_albedo: Vec3) -> Self {
+        let sun = sun.unitize();
+        let t = turbidity;
+        let theta = sun.y().clamp(-1.0, 1.0).acos();
+        let (theta2, theta3) = (theta * theta, theta * theta * theta);
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta);
+        let zenith = [
+            ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0),
+            t * t * (0.00166 * theta3 - 0.00375 * theta2 + 0.00209 * theta)
+                + t * (-0.02903 * theta3 + 0.06377 * theta2 - 0.03202 * theta + 0.00394)
+                + (0.11693 * theta3 - 0.21196 * theta2 + 0.06052 * theta + 0.25886),
+            t * t * (0.00275 * theta3 - 0.00610 * theta2 + 0.00317 * theta)
+                + t * (-0.04214 * theta3 + 0.08970 * theta2 - 0.04153 * theta + 0.00516)
+                + (0.15346 * theta3 - 0.26756 * theta2 + 0.06670 * theta + 0.26688),
+        ];
+        let coefficients = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+        let mut normalization = [0.0; 3];
+        for (value, &coefficients) in normalization.iter_mut().zip(&coefficients) {
+            *value = perez(coefficients, 1.0, theta);
+        }
+
+        let above_horizon = sun.y() > 0.0;
+        let sun_radiance = if above_horizon {
+            SOLAR_ILLUMINANCE / (PI * SUN_RADIUS * SUN_RADIUS) * Vec3::from(transmittance(t, theta))
+        } else {
+            Vec3::zeros()
+        };
+        let mut sky = Self {
+            sun,
+            zenith,
+            coefficients,
+            normalization,
+            sun_radiance,
+            ground: Vec3::zeros(),
+            sun_probability: 0.0,
+        };
+        if !above_horizon {
+            sky.zenith[0] = 0.0;
+            return sky;
+        }
+
+        // the ground is a diffuse plane lit by the sky and the sun
+        let sun_irradiance = sun_radiance * (PI * SUN_RADIUS * SUN_RADIUS) * sun.y();
+        let sky_irradiance = sky.irradiance();
+        sky.ground = ground_albedo * (sun_irradiance + sky_irradiance) / PI;
+        let sun_power = luminance(sun_irradiance);
+        sky.sun_probability = (sun_power / (sun_power + luminance(sky_irradiance))).clamp(0.1, 0.9);
+        sky
+    }
+
+    /// The radiance of the sky alone, without the sun, towards `direction`,
+    /// a unit vector above the horizon.
+    fn sky_radiance(&self, direction: Vec3) -> Vec3 {
+        let cos_gamma = direction.dot(self.sun).clamp(-1.0, 1.0);
+        let mut values = [0.0; 3];
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = self.zenith[i] * perez(self.coefficients[i], direction.y(), cos_gamma.acos())
+                / self.normalization[i];
+        }
+        let [luminance, x, y] = values;
+        xyy_to_rgb(x, y, luminance)
+    }
+
+    /// The sky's irradiance on the horizontal ground, summed over a grid.
+    fn irradiance(&self) -> Vec3 {
+        let (n, m) = (IRRADIANCE_STEPS, 2 * IRRADIANCE_STEPS);
+        let (d_theta, d_phi) = (0.5 * PI / n as f64, 2.0 * PI / m as f64);
+        let mut irradiance = Vec3::zeros();
+        for i in 0..n {
+            let (sin_theta, cos_theta) = ((i as f64 + 0.5) * d_theta).sin_cos();
+            for j in 0..m {
+                let (sin_phi, cos_phi) = ((j as f64 + 0.5) * d_phi).sin_cos();
+                let direction = vec3![sin_theta * cos_phi, cos_theta, sin_theta * sin_phi];
+                irradiance += self.sky_radiance(direction) * (cos_theta * sin_theta);
+            }
+        }
+        irradiance * (d_theta * d_phi)
+    }
+
+    fn sun_cos_max() -> f64 {
+        SUN_RADIUS.cos()
+    }
+}
+
+/// The fraction of each of the sun's red, green and blue getting through the
+/// atmosphere at `theta` from the zenith, by Rayleigh scattering off the air
+/// and Ångström's attenuation by aerosols.
+fn transmittance(turbidity: f64, theta: f64) -> [f64; 3] {
+    // Kasten's relative optical mass, the path length relative to the zenith's
+    let mass = 1.0 / (theta.cos() + 0.15 * (93.885 - theta.to_degrees()).powf(-1.253));
+    let beta = 0.04608 * turbidity - 0.04586;
+    let mut transmittance = [0.0; 3];
+    for (value, &lambda) in transmittance.iter_mut().zip(&WAVELENGTHS) {
+        let rayleigh = 0.008735 * lambda.powf(-4.08);
+        let aerosol = beta * lambda.powf(-1.3);
+        *value = (-(rayleigh + aerosol) * mass).exp();
+    }
+    transmittance
+}
+
+/// Linear sRGB from CIE xyY.
+fn xyy_to_rgb(x: f64, y: f64, luminance: f64) -> Vec3 {
+    if y <= 0.0 {
+        return Vec3::zeros();
+    }
+    let (cie_x, cie_z) = (x / y * luminance, (1.0 - x - y) / y * luminance);
+    vec3![
+        3.2406 * cie_x - 1.5372 * luminance - 0.4986 * cie_z,
+        -0.9689 * cie_x + 1.8758 * luminance + 0.0415 * cie_z,
+        0.0557 * cie_x - 0.2040 * luminance + 1.0570 * cie_z
+    ]
+    .sup(Vec3::zeros())
+}
+
+impl Environment for Sky {
+    fn radiance(&self, direction: Vec3) -> Vec3 {
+        let direction = direction.unitize();
+        if direction.y() < 0.0 {
+            return self.ground;
+        }
+        let sky = self.sky_radiance(direction);
+        if direction.dot(self.sun) >= Self::sun_cos_max() {
+            sky + self.sun_radiance
+        } else {
+            sky
+        }
+    }
+
+    fn sampled(&self) -> bool {
+        self.sun_probability > 0.0
+    }
+
+    /// Towards the sun's disk, or uniformly.
+    fn sample(&self, rng: &mut Rng) -> Vec3 {
+        if utils::rand(rng) >= self.sun_probability {
+            return utils::random_unit_vector(rng);
+        }
+        let cos_theta = 1.0 - utils::rand(rng) * (1.0 - Self::sun_cos_max());
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * utils::rand(rng);
+        let (s, t) = utils::orthonormal_basis(self.sun);
+        sin_theta * (phi.cos() * s + phi.sin() * t) + cos_theta * self.sun
+    }
+
+    fn pdf(&self, direction: Vec3) -> f64 {
+        let uniform = (1.0 - self.sun_probability) / (4.0 * PI);
+        if direction.unitize().dot(self.sun) >= Self::sun_cos_max() {
+            uniform + self.sun_probability / (2.0 * PI * (1.0 - Self::sun_cos_max()))
+        } else {
+            uniform
+        }
+    }
+}
+
+/// The direction of the sun at elevation degrees above the horizon and
+/// azimuth degrees clockwise from north, taking north as -z, east as +x and
+/// up as +y.
+pub fn sun_direction(elevation: f64, azimuth: f64) -> Vec3 {
+    let (sin_elevation, cos_elevation) = elevation.to_radians().sin_cos();
+    let (sin_azimuth, cos_azimuth) = azimuth.to_radians().sin_cos();
+    vec3![
+        cos_elevation * sin_azimuth,
+        sin_elevation,
+        -cos_elevation * cos_azimuth
+    ]
+}
+
+/// Where the sun is, as for [`sun_direction`], seen from `latitude` degrees
+/// north and `longitude` degrees east on day `day` of the year, from 1 on the
+/// 1st of January, at `hour` o'clock in a time zone `utc_offset` hours ahead of
+/// UTC. This is NOAA's approximation, good to a fraction of a degree.
+pub fn solar_direction(
+    latitude: f64,
+    longitude: f64,
+    day: f64,
+    hour: f64,
+    utc_offset: f64,
+) -> Vec3 {
+    let utc_hour = hour - utc_offset;
+    let gamma = 2.0 * PI / 365.0 * (day - 1.0 + (utc_hour - 12.0) / 24.0);
+    let (sin1, cos1) = gamma.sin_cos();
+    let (sin2, cos2) = (2.0 * gamma).sin_cos();
+    let (sin3, cos3) = (3.0 * gamma).sin_cos();
+    // in minutes
+    let equation_of_time =
+        229.18 * (0.000075 + 0.001868 * cos1 - 0.032077 * sin1 - 0.014615 * cos2 - 0.040849 * sin2);
+    let declination = 0.006918 - 0.399912 * cos1 + 0.070257 * sin1 - 0.006758 * cos2
+        + 0.000907 * sin2
+        - 0.002697 * cos3
+        + 0.00148 * sin3;
+    let solar_minutes = utc_hour * 60.0 + equation_of_time + 4.0 * longitude;
+    let hour_angle = (solar_minutes / 4.0 - 180.0).to_radians();
+
+    let (sin_latitude, cos_latitude) = latitude.to_radians().sin_cos();
+    let (sin_declination, cos_declination) = declination.sin_cos();
+    let east = -cos_declination * hour_angle.sin();
+    let north = cos_latitude * sin_declination - sin_latitude * cos_declination * hour_angle.cos();
+    let up = sin_latitude * sin_declination + cos_latitude * cos_declination * hour_angle.cos();
+    vec3![east, up, -north]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{solar_direction, sun_direction, Sky};
+    use crate::{environment::Environment, tonemap::luminance, utils::seeded_rng, Vec3};
+
+    #[test]
+    fn test_sun_position() {
+        let elevation = |direction: Vec3| direction.y().asin().to_degrees();
+        assert!((sun_direction(90.0, 123.0) - vec3![0, 1, 0]).norm() < 1e-12);
+        assert!((sun_direction(0.0, 90.0) - vec3![1, 0, 0]).norm() < 1e-12);
+
+        // London at the June solstice: due south at noon GMT, 62 degrees up
+        let noon = solar_direction(51.5, 0.0, 172.0, 12.0, 0.0);
+        assert!((elevation(noon) - 62.0).abs() < 0.5, "{}", elevation(noon));
+        assert!(noon.x().abs() < 0.02 && noon.z() > 0.0);
+        // and in the east in the morning, summer time and all
+        let morning = solar_direction(51.5, 0.0, 172.0, 8.0, 1.0);
+        assert!(morning.x() > 0.5 && morning.y() > 0.0);
+        // but set at midnight
+        assert!(solar_direction(51.5, 0.0, 172.0, 0.0, 0.0).y() < 0.0);
+        // Sydney, 151 degrees east and 10 hours ahead, sees the sun in the
+        // north at noon
+        let sydney = solar_direction(-33.9, 151.2, 172.0, 12.0, 10.0);
+        assert!(sydney.z() < 0.0 && sydney.x().abs() < 0.1);
+    }
+
+    #[test]
+    fn test_sky() {
+        let sky = Sky::new(sun_direction(45.0, 180.0), 3.0, vec3![0.3, 0.3, 0.3]);
+        let zenith = sky.radiance(vec3![0, 1, 0]);
+        // a few kilocandelas per square metre, bluish
+        assert!(luminance(zenith) > 1.0 && luminance(zenith) < 20.0);
+        assert!(zenith.z() > zenith.x());
+        // brighter around the sun than opposite it
+        let near_sun = sky.radiance(sun_direction(40.0, 170.0));
+        let opposite = sky.radiance(sun_direction(40.0, 0.0));
+        assert!(luminance(near_sun) > luminance(opposite));
+        // the sun's disk lights the ground with tens of kilolux, and is
+        // yellower than outside the atmosphere
+        let sun = sky.radiance(sun_direction(45.0, 180.0)) - sky.sky_radiance(sky.sun);
+        let illuminance = luminance(sun) * std::f64::consts::PI * super::SUN_RADIUS.powi(2);
+        assert!(illuminance > 50.0 && illuminance < 128.0, "{}", illuminance);
+        assert!(sun.x() > sun.y() && sun.y() > sun.z());
+        assert!(luminance(sky.radiance(vec3![0, -1, 0])) > 0.0);
+
+        let night = Sky::new(sun_direction(-10.0, 0.0), 3.0, Vec3::ones());
+        assert_eq!(night.radiance(vec3![0, 1, 0]), Vec3::zeros());
+        assert!(!night.sampled());
+    }
+
+    #[test]
+    fn test_sky_sampling() {
+        let sky = Sky::new(sun_direction(30.0, 90.0), 2.5, Vec3::zeros());
+        assert!(sky.sampled());
+        let rng = &mut seeded_rng(0);
+        let n = 100_000;
+        // estimates of the horizontal ground's illuminance, by sampling the
+        // sky and sun, and by the sun and the sky's quadrature
+        let sampled = (0..n)
+            .map(|_| {
+                let direction = sky.sample(rng);
+                assert!((direction.norm() - 1.0).abs() < 1e-9);
+                luminance(sky.radiance(direction)) * direction.y().max(0.0) / sky.pdf(direction)
+            })
+            .sum::<f64>()
+            / n as f64;
+        let sun = super::SOLAR_ILLUMINANCE
+            * luminance(Vec3::from(super::transmittance(2.5, sky.sun.y().acos())))
+            * sky.sun.y();
+        let expected = sun + luminance(sky.irradiance());
+        assert!(
+            (sampled - expected).abs() < 0.02 * expected,
+            "{} {}",
+            sampled,
+            expected
+        );
+    }
+}
@@ -0,0 +1,514 @@
+use crate::{noise::Noise, Vec3};
+use anyhow::{bail, Context, Result};
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+/// A colour that varies over a surface.
+pub trait Texture {
+    /// The colour at surface coordinates `(u, v)` and position `point`.
+    fn value(&self, u: f64, v: f64, point: Vec3) -> Vec3;
+}
+
+/// A constant colour.
+impl Texture for Vec3 {
+    fn value(&self, _u: f64, _v: f64, _point: Vec3) -> Vec3 {
+        *self
+    }
+}
+
+impl<T> Texture for Arc<T>
+where
+    T: Texture + ?Sized,
+{
+    fn value(&self, u: f64, v: f64, point: Vec3) -> Vec3 {
+        self.as_ref().value(u, v, point)
+    }
+}
+
+/// Alternating cubes of two textures, filling space.
+#[derive(Debug, PartialEq)]
+pub struct Checker<E, O> {
+    even: E,
+    odd: O,
+    size: f64,
+}
+
+impl<E, O> Checker<E, O> {
+    /// Cubes of side `size`, with the one touching the origin from above
+    /// being `even`.
+    pub fn new(even: E, odd: O, size: f64) -> Self {
+        Self { even, odd, size }
+    }
+}
+
+impl<E, O> Texture for Checker<E, O>
+where
+    E: Texture,
+    O: Texture,
+{
+    fn value(&self, u: f64, v: f64, point: Vec3) -> Vec3 {
+        let parity = point
+            .into_array()
+            .iter()
+            .map(|coordinate| (coordinate / self.size).floor() as i64)
+            .sum::<i64>()
+            .rem_euclid(2);
+        if parity == 0 {
+            self.even.value(u, v, point)
+        } else {
+            self.odd.value(u, v, point)
+        }
+    }
+}
+
+/// A linear blend from `start` at `v = 0` to `end` at `v = 1`.
+#[derive(Debug, PartialEq)]
+pub struct Gradient {
+    start: Vec3,
+    end: Vec3,
+}
+
+impl Gradient {
+    pub fn new(start: Vec3, end: Vec3) -> Self {
+        Self { start, end }
+    }
+}
+
+impl Texture for Gradient {
+    fn value(&self, _u: f64, v: f64, _point: Vec3) -> Vec3 {
+        self.start.lerp(self.end, v.clamp(0.0, 1.0))
+    }
+}
+
+/// Marble: bands of `dark` and `light` along `z`, distorted by turbulence
+/// into veins.
+#[derive(Debug, PartialEq)]
+pub struct Marble<N> {
+    noise: N,
+    scale: f64,
+    light: Vec3,
+    dark: Vec3,
+}
+
+impl<N> Marble<N> {
+    /// Marble with noise features about `1 / scale` across.
+    pub fn new(noise: N, scale: f64, light: Vec3, dark: Vec3) -> Self {
+        Self {
+            noise,
+            scale,
+            light,
+            dark,
+        }
+    }
+}
+
+impl<N: Noise> Texture for Marble<N> {
+    fn value(&self, _u: f64, _v: f64, point: Vec3) -> Vec3 {
+        let point = self.scale * point;
+        let phase = point.z() + 10.0 * self.noise.turbulence(point, 7);
+        self.dark.lerp(self.light, 0.5 * (1.0 + phase.sin()))
+    }
+}
+
+/// Wood: growth rings around the `y` axis, fading from `light` to `dark`
+/// across each ring and wobbled by noise.
+#[derive(Debug, PartialEq)]
+pub struct Wood<N> {
+    noise: N,
+    scale: f64,
+    light: Vec3,
+    dark: Vec3,
+}
+
+impl<N> Wood<N> {
+    /// Wood with rings `1 / scale` apart.
+    pub fn new(noise: N, scale: f64, light: Vec3, dark: Vec3) -> Self {
+        Self {
+            noise,
+            scale,
+            light,
+            dark,
+        }
+    }
+}
+
+impl<N: Noise> Texture for Wood<N> {
+    fn value(&self, _u: f64, _v: f64, point: Vec3) -> Vec3 {
+        let point = self.scale * point;
+        let radius = point.x().hypot(point.z()) + 0.5 * self.noise.fbm(point, 4);
+        let ring = radius.rem_euclid(1.0);
+        self.light.lerp(self.dark, ring * ring)
+    }
+}
+
+/// Clouds: fractal noise blending `sky` into `cloud`.
+#[derive(Debug, PartialEq)]
+pub struct Cloud<N> {
+    noise: N,
+    scale: f64,
+    sky: Vec3,
+    cloud: Vec3,
+}
+
+impl<N> Cloud<N> {
+    /// Clouds with the largest puffs about `1 / scale` across.
+    pub fn new(noise: N, scale: f64, sky: Vec3, cloud: Vec3) -> Self {
+        Self {
+            noise,
+            scale,
+            sky,
+            cloud,
+        }
+    }
+}
+
+impl<N: Noise> Texture for Cloud<N> {
+    fn value(&self, _u: f64, _v: f64, point: Vec3) -> Vec3 {
+        let density = 0.5 + self.noise.fbm(self.scale * point, 6);
+        self.sky.lerp(self.cloud, density.clamp(0.0, 1.0))
+    }
+}
+
+/// How texture coordinates outside `[0, 1]` are brought back inside.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Wrap {
+    /// Tile the image.
+    Repeat,
+    /// Tile the image, flipping every other copy.
+    Mirror,
+    /// Extend the edge texels.
+    Clamp,
+}
+
+impl Wrap {
+    /// Bring texel index `i` into `0..len`.
+    fn apply(self, i: i64, len: usize) -> usize {
+        let len = len as i64;
+        let i = match self {
+            Wrap::Repeat => i.rem_euclid(len),
+            Wrap::Mirror => {
+                let i = i.rem_euclid(2 * len);
+                if i < len {
+                    i
+                } else {
+                    2 * len - 1 - i
+                }
+            }
+            Wrap::Clamp => i.clamp(0, len - 1),
+        };
+        i as usize
+    }
+}
+
+/// The inverse of the sRGB transfer function.
+fn srgb_to_linear(value: f64) -> f64 {
+    if value <= 0.040_45 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// A bilinearly filtered image, with `v` running from the bottom row up.
+#[derive(Debug, PartialEq)]
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    /// Linear colours, starting at the top left.
+    pixels: Vec<Vec3>,
+    wrap: Wrap,
+}
+
+impl ImageTexture {
+    pub fn new(width: usize, height: usize, pixels: Vec<Vec3>, wrap: Wrap) -> Self {
+        assert!(width > 0 && height > 0, "textures can't be empty");
+        assert_eq!(
+            pixels.len(),
+            width * height,
+            "expected one pixel per width * height"
+        );
+        Self {
+            width,
+            height,
+            pixels,
+            wrap,
+        }
+    }
+
+    /// Load an sRGB encoded PNG or PPM image.
+    pub fn load(path: impl AsRef<Path>, wrap: Wrap) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("Unable to open texture {}", path.display()))?;
+        let reader = BufReader::new(file);
+        let (width, height, pixels) = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("png") => read_png(reader),
+            Some(ext) if ext.eq_ignore_ascii_case("ppm") => read_ppm(reader),
+            _ => bail!("Expected a .png or .ppm texture"),
+        }
+        .with_context(|| format!("Unable to read texture {}", path.display()))?;
+        if width == 0 || height == 0 {
+            bail!("Texture {} is empty", path.display());
+        }
+        let pixels = pixels
+            .into_iter()
+            .map(|pixel| pixel.into_array().map(srgb_to_linear).into())
+            .collect();
+        Ok(Self::new(width, height, pixels, wrap))
+    }
+
+    fn texel(&self, x: i64, y: i64) -> Vec3 {
+        self.pixels[self.wrap.apply(y, self.height) * self.width + self.wrap.apply(x, self.width)]
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: f64, v: f64, _point: Vec3) -> Vec3 {
+        // texel centres are at half integers
+        let x = u * self.width as f64 - 0.5;
+        let y = (1.0 - v) * self.height as f64 - 0.5;
+        if !(x.is_finite() && y.is_finite()) {
+            return Vec3::zeros();
+        }
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.texel(x0, y0).lerp(self.texel(x0 + 1, y0), tx);
+        let bottom = self.texel(x0, y0 + 1).lerp(self.texel(x0 + 1, y0 + 1), tx);
+        top.lerp(bottom, ty)
+    }
+}
+
+/// Pixels in `[0, 1]`, starting at the top left.
+type Pixels = (usize, usize, Vec<Vec3>);
+
+fn read_png(reader: impl std::io::Read) -> Result<Pixels> {
+    let mut decoder = png::Decoder::new(reader);
+    // expand palettes and low bit depths to whole bytes
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    let bytes = &buffer[..info.buffer_size()];
+
+    let channels = info.color_type.samples();
+    let samples = match info.bit_depth {
+        png::BitDepth::Eight => bytes
+            .iter()
+            .map(|&byte| f64::from(byte) / f64::from(u8::MAX))
+            .collect::<Vec<_>>(),
+        png::BitDepth::Sixteen => bytes
+            .chunks_exact(2)
+            .map(|pair| f64::from(u16::from_be_bytes([pair[0], pair[1]])) / f64::from(u16::MAX))
+            .collect(),
+        depth => bail!("Unsupported bit depth {:?}", depth),
+    };
+    let pixels = samples
+        .chunks_exact(channels)
+        .map(|pixel| match pixel {
+            // grey, with or without alpha
+            [grey] | [grey, _] => Vec3::from([*grey; 3]),
+            [r, g, b, ..] => vec3![*r, *g, *b],
+            [] => unreachable!("chunks are never empty"),
+        })
+        .collect();
+    Ok((info.width as usize, info.height as usize, pixels))
+}
+
+/// The next whitespace separated token of a PPM header, skipping comments
+/// that run to the end of the line.
+fn ppm_token<'a>(data: &'a [u8], pos: &mut usize) -> Result<&'a [u8]> {
+    loop {
+        match data.get(*pos) {
+            Some(b'#') => {
+                while data.get(*pos).is_some_and(|&byte| byte != b'\n') {
+                    *pos += 1;
+                }
+            }
+            Some(byte) if byte.is_ascii_whitespace() => *pos += 1,
+            Some(_) => break,
+            None => bail!("Unexpected end of file"),
+        }
+    }
+    let start = *pos;
+    while data
+        .get(*pos)
+        .is_some_and(|byte| !byte.is_ascii_whitespace())
+    {
+        *pos += 1;
+    }
+    Ok(&data[start..*pos])
+}
+
+fn ppm_number(data: &[u8], pos: &mut usize) -> Result<usize> {
+    let token = ppm_token(data, pos)?;
+    std::str::from_utf8(token)?.parse().with_context(|| {
+        format!(
+            "Expected a number, got {:?}",
+            String::from_utf8_lossy(token)
+        )
+    })
+}
+
+fn read_ppm(mut reader: impl std::io::BufRead) -> Result<Pixels> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    let pos = &mut 0;
+    let number = |pos: &mut usize| ppm_number(&data, pos);
+
+    let magic = ppm_token(&data, pos)?;
+    let (width, height, max) = (number(pos)?, number(pos)?, number(pos)?);
+    if !(1..=usize::from(u16::MAX)).contains(&max) {
+        bail!("Invalid maximum value {}", max);
+    }
+    let len = width
+        .checked_mul(height)
+        .and_then(|n| n.checked_mul(3))
+        .with_context(|| format!("Image size {}x{} is too large", width, height))?;
+    let samples = match magic {
+        b"P3" => (0..len).map(|_| number(pos)).collect::<Result<Vec<_>>>()?,
+        b"P6" => {
+            // a single whitespace byte separates the header from the data
+            let start = *pos + 1;
+            let size = if max > 255 { 2 } else { 1 };
+            let body = len
+                .checked_mul(size)
+                .and_then(|n| n.checked_add(start))
+                .and_then(|end| data.get(start..end))
+                .context("Unexpected end of file")?;
+            if size == 2 {
+                body.chunks_exact(2)
+                    .map(|pair| usize::from(u16::from_be_bytes([pair[0], pair[1]])))
+                    .collect()
+            } else {
+                body.iter().map(|&byte| usize::from(byte)).collect()
+            }
+        }
+        _ => bail!("Expected a P3 or P6 PPM"),
+    };
+    if let Some(sample) = samples.iter().find(|&&sample| sample > max) {
+        bail!("Sample {} exceeds the maximum value {}", sample, max);
+    }
+    let pixels = samples
+        .chunks_exact(3)
+        .map(|rgb| vec3![rgb[0] as f64, rgb[1] as f64, rgb[2] as f64] / max as f64)
+        .collect();
+    Ok((width, height, pixels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_ppm, Checker, Cloud, Gradient, ImageTexture, Marble, Texture, Wood, Wrap};
+    use crate::{
+        noise::{Perlin, Simplex, Smoothing},
+        utils::{randvec, seeded_rng},
+        Vec3,
+    };
+    use std::sync::Arc;
+
+    #[test]
+    fn test_checker() {
+        let checker = Checker::new(vec3![1, 1, 1], vec3![0, 0, 0], 0.5);
+        let at = |x: f64, y: f64, z: f64| checker.value(0.0, 0.0, vec3![x, y, z]).x();
+        assert_eq!(at(0.1, 0.1, 0.1), 1.0);
+        assert_eq!(at(0.6, 0.1, 0.1), 0.0);
+        assert_eq!(at(0.6, 0.6, 0.1), 1.0);
+        assert_eq!(at(-0.1, 0.1, 0.1), 0.0);
+        assert_eq!(at(-0.1, -0.1, -0.1), 0.0);
+    }
+
+    #[test]
+    fn test_gradient() {
+        let gradient = Gradient::new(vec3![0, 0, 0], vec3![1, 2, 4]);
+        assert_eq!(gradient.value(0.3, 0.5, Vec3::zeros()), vec3![0.5, 1, 2]);
+        assert_eq!(gradient.value(0.3, 2.0, Vec3::zeros()), vec3![1, 2, 4]);
+    }
+
+    #[test]
+    fn test_noise_textures_stay_between_colors() {
+        let rng = &mut seeded_rng(0);
+        let perlin = Arc::new(Perlin::new(Smoothing::Hermite, rng));
+        let (white, black) = (vec3![1, 1, 1], Vec3::zeros());
+        let textures: Vec<Box<dyn Texture>> = vec![
+            Box::new(Marble::new(Arc::clone(&perlin), 4.0, white, black)),
+            Box::new(Wood::new(Arc::clone(&perlin), 4.0, white, black)),
+            Box::new(Cloud::new(Simplex::new(rng), 4.0, white, black)),
+        ];
+        for texture in textures {
+            let values = (0..1000)
+                .map(|_| texture.value(0.0, 0.0, randvec(rng)).x())
+                .collect::<Vec<_>>();
+            assert!(values.iter().all(|value| (0.0..=1.0).contains(value)));
+            // and actually varies
+            let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+            let max = values.iter().cloned().fold(0.0, f64::max);
+            assert!(max - min > 0.5, "{} {}", min, max);
+        }
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(Wrap::Repeat.apply(-1, 4), 3);
+        assert_eq!(Wrap::Repeat.apply(5, 4), 1);
+        assert_eq!(Wrap::Mirror.apply(-1, 4), 0);
+        assert_eq!(Wrap::Mirror.apply(4, 4), 3);
+        assert_eq!(Wrap::Mirror.apply(9, 4), 1);
+        assert_eq!(Wrap::Clamp.apply(-3, 4), 0);
+        assert_eq!(Wrap::Clamp.apply(7, 4), 3);
+    }
+
+    #[test]
+    fn test_bilinear() {
+        // black on the left, white on the right
+        let pixels = vec![vec3![0, 0, 0], vec3![1, 1, 1]];
+        let clamped = ImageTexture::new(2, 1, pixels.clone(), Wrap::Clamp);
+        let repeated = ImageTexture::new(2, 1, pixels, Wrap::Repeat);
+        let value = |texture: &ImageTexture, u| texture.value(u, 0.5, Vec3::zeros()).x();
+        assert_eq!(value(&clamped, 0.25), 0.0);
+        assert_eq!(value(&clamped, 0.5), 0.5);
+        assert_eq!(value(&clamped, 0.75), 1.0);
+        assert_eq!(value(&clamped, 1.0), 1.0);
+        // halfway between the last texel and the first again
+        assert_eq!(value(&repeated, 1.0), 0.5);
+        assert_eq!(value(&repeated, 0.0), 0.5);
+    }
+
+    #[test]
+    fn test_read_ppm() {
+        let (width, height, pixels) =
+            read_ppm(&b"P3\n# a comment\n2 1 255\n255 0 0  0 0 51\n"[..]).unwrap();
+        assert_eq!((width, height), (2, 1));
+        assert_eq!(pixels, [vec3![1, 0, 0], vec3![0, 0, 0.2]]);
+
+        let (_, _, binary) = read_ppm(&b"P6 2 1\n255\n\xff\0\0\0\0\x33"[..]).unwrap();
+        assert_eq!(binary, pixels);
+
+        assert!(read_ppm(&b"P6 2 1\n255\n\xff\0"[..]).is_err());
+        assert!(read_ppm(&b"P3 1 1 100 200 0 0"[..]).is_err());
+        assert!(read_ppm(&b"P6 1 1\n100\n\xff\0\0"[..]).is_err());
+        // headers too large to add up
+        let huge = format!("P6 {} 2\n65535\n\0", usize::MAX / 8);
+        assert!(read_ppm(huge.as_bytes()).is_err());
+        let huge = format!("P6 {} {}\n255\n\0", usize::MAX, usize::MAX);
+        assert!(read_ppm(huge.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_load_png() {
+        let image = crate::output::Image::new(2, 1, vec![vec3![1, 0, 0], vec3![0.5, 0.5, 0.5]]);
+        let path = std::env::temp_dir().join(format!("texture-{}.png", std::process::id()));
+        image
+            .save(
+                &path,
+                crate::output::Format::Png,
+                &crate::tonemap::ToneMap::default(),
+            )
+            .unwrap();
+        let texture = ImageTexture::load(&path, Wrap::Clamp).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(texture.value(0.25, 0.5, Vec3::zeros()), vec3![1, 0, 0]);
+        // sRGB encoding and decoding round trips up to quantization
+        let grey = texture.value(0.75, 0.5, Vec3::zeros());
+        assert!((grey.x() - 0.5).abs() < 0.005, "{:?}", grey);
+    }
+}
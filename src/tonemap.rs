@@ -0,0 +1,455 @@
+//! Turning linear radiance into display ready 8-bit colour.
+
+use crate::{
+    output::Image,
+    utils::{self, seeded_rng},
+    Vec3,
+};
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::{str::FromStr, sync::OnceLock};
+
+/// A curve compressing scene radiance into displayable `[0, 1]` values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operator {
+    /// Clip anything brighter than 1.
+    Clamp,
+    /// `L / (1 + L)` on luminance.
+    Reinhard,
+    /// Reinhard's operator with a white point that maps to 1.
+    ExtendedReinhard,
+    /// Narkowicz's fit of the ACES filmic curve.
+    Aces,
+    /// John Hable's filmic curve from Uncharted 2.
+    Hable,
+}
+
+impl Operator {
+    pub const NAMES: &'static [&'static str] =
+        &["clamp", "reinhard", "extended_reinhard", "aces", "hable"];
+}
+
+impl FromStr for Operator {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "clamp" => Operator::Clamp,
+            "reinhard" => Operator::Reinhard,
+            "extended_reinhard" => Operator::ExtendedReinhard,
+            "aces" => Operator::Aces,
+            "hable" => Operator::Hable,
+            _ => bail!("Unknown tone mapping operator `{}`", s),
+        })
+    }
+}
+
+/// The encoding of display values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transfer {
+    /// The piecewise sRGB curve.
+    Srgb,
+    /// A pure power law with the given gamma.
+    Gamma(f64),
+}
+
+/// Noise added before quantization, trading banding for fine grain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dither {
+    None,
+    /// An 8x8 Bayer matrix.
+    Ordered,
+    /// A 64x64 blue noise mask, which has no visible pattern.
+    BlueNoise,
+}
+
+impl Dither {
+    pub const NAMES: &'static [&'static str] = &["none", "ordered", "blue_noise"];
+
+    /// The quantization threshold in `[0, 1)` for the pixel at `(x, y)`.
+    fn threshold(self, x: usize, y: usize) -> f64 {
+        match self {
+            Dither::None => 0.5,
+            Dither::Ordered => (f64::from(BAYER[y % 8][x % 8]) + 0.5) / 64.0,
+            Dither::BlueNoise => {
+                blue_noise()[(y % BLUE_NOISE_SIZE) * BLUE_NOISE_SIZE + x % BLUE_NOISE_SIZE]
+            }
+        }
+    }
+}
+
+impl FromStr for Dither {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "none" => Dither::None,
+            "ordered" => Dither::Ordered,
+            "blue_noise" => Dither::BlueNoise,
+            _ => bail!("Unknown dithering `{}`", s),
+        })
+    }
+}
+
+/// The whole path from linear radiance to 8-bit values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ToneMap {
+    /// Stops by which to scale radiance before tone mapping.
+    pub exposure: f64,
+    pub operator: Operator,
+    /// The exposed luminance that extended Reinhard maps to white, or `None`
+    /// for the brightest pixel of the image.
+    pub white: Option<f64>,
+    pub transfer: Transfer,
+    pub dither: Dither,
+}
+
+impl Default for ToneMap {
+    fn default() -> Self {
+        Self {
+            exposure: 0.0,
+            operator: Operator::Clamp,
+            white: None,
+            transfer: Transfer::Srgb,
+            dither: Dither::None,
+        }
+    }
+}
+
+pub(crate) fn luminance(color: Vec3) -> f64 {
+    color.dot(vec3![0.2126, 0.7152, 0.0722])
+}
+
+/// Scale `color` so that its luminance becomes `f` of its luminance.
+fn map_luminance(color: Vec3, f: impl Fn(f64) -> f64) -> Vec3 {
+    let l = luminance(color);
+    if l > 0.0 {
+        color * (f(l) / l)
+    } else {
+        Vec3::zeros()
+    }
+}
+
+fn map_channels(color: Vec3, f: impl Fn(f64) -> f64) -> Vec3 {
+    color.into_array().map(f).into()
+}
+
+fn hable(x: f64) -> f64 {
+    const A: f64 = 0.15;
+    const B: f64 = 0.50;
+    const C: f64 = 0.10;
+    const D: f64 = 0.20;
+    const E: f64 = 0.02;
+    const F: f64 = 0.30;
+    (x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F) - E / F
+}
+
+/// Hable's linear white point.
+const HABLE_WHITE: f64 = 11.2;
+
+fn srgb(value: f64) -> f64 {
+    if value <= 0.003_130_8 {
+        12.92 * value
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+impl ToneMap {
+    /// Map exposed linear radiance to linear display values, given the white
+    /// point.
+    fn map(&self, color: Vec3, white: f64) -> Vec3 {
+        let color = map_channels(color, |channel| channel.max(0.0));
+        let mapped = match self.operator {
+            Operator::Clamp => color,
+            Operator::Reinhard => map_luminance(color, |l| l / (1.0 + l)),
+            Operator::ExtendedReinhard => {
+                let white2 = white.powi(2);
+                map_luminance(color, |l| l * (1.0 + l / white2) / (1.0 + l))
+            }
+            Operator::Aces => map_channels(color, |x| {
+                (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+            }),
+            Operator::Hable => map_channels(color, |x| hable(x) / hable(HABLE_WHITE)),
+        };
+        map_channels(mapped, |channel| channel.clamp(0.0, 1.0))
+    }
+
+    /// Apply the transfer function to a display value in `[0, 1]`.
+    fn encode(&self, value: f64) -> f64 {
+        match self.transfer {
+            Transfer::Srgb => srgb(value),
+            Transfer::Gamma(gamma) => value.powf(gamma.recip()),
+        }
+    }
+
+    /// Tone map, encode and quantize every pixel of `image` into RGB bytes.
+    pub fn apply(&self, image: &Image) -> Vec<u8> {
+        let scale = 2f64.powf(self.exposure);
+        let white = self.white.unwrap_or_else(|| {
+            image
+                .pixels()
+                .iter()
+                .map(|&pixel| luminance(pixel) * scale)
+                .filter(|l| l.is_finite())
+                .fold(0.0, f64::max)
+        });
+        // a white point below 1 would brighten the image instead
+        let white = white.max(1.0);
+
+        let width = image.width().max(1);
+        image
+            .pixels()
+            .iter()
+            .enumerate()
+            .flat_map(|(index, &pixel)| {
+                let threshold = self.dither.threshold(index % width, index / width);
+                self.map(pixel * scale, white)
+                    .into_array()
+                    .map(|value| quantize(self.encode(value), threshold))
+            })
+            .collect()
+    }
+}
+
+/// Quantize `value` in `[0, 1]`, rounding up if its fractional part in 8-bit
+/// steps is at least `1 - threshold`, so a threshold of 0.5 rounds to nearest.
+fn quantize(value: f64, threshold: f64) -> u8 {
+    let value = value * f64::from(u8::MAX) + threshold;
+    if value.is_nan() {
+        0
+    } else {
+        value.floor().clamp(0.0, f64::from(u8::MAX)) as u8
+    }
+}
+
+#[rustfmt::skip]
+const BAYER: [[u8; 8]; 8] = [
+    [ 0, 32,  8, 40,  2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44,  4, 36, 14, 46,  6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [ 3, 35, 11, 43,  1, 33,  9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47,  7, 39, 13, 45,  5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+const BLUE_NOISE_SIZE: usize = 64;
+
+/// Thresholds in `[0, 1)` for a tileable blue noise mask.
+fn blue_noise() -> &'static [f64] {
+    static MASK: OnceLock<Vec<f64>> = OnceLock::new();
+    MASK.get_or_init(|| {
+        let n = BLUE_NOISE_SIZE * BLUE_NOISE_SIZE;
+        void_and_cluster(BLUE_NOISE_SIZE, 1.5)
+            .into_iter()
+            .map(|rank| (rank as f64 + 0.5) / n as f64)
+            .collect()
+    })
+}
+
+/// The Gaussian weighted density of set pixels around every pixel of a
+/// toroidal `size * size` grid, kept up to date as pixels are toggled.
+struct Energy {
+    size: usize,
+    kernel: Vec<f64>,
+    energy: Vec<f64>,
+}
+
+impl Energy {
+    fn new(size: usize, sigma: f64) -> Self {
+        let kernel = (0..size * size)
+            .map(|index| {
+                // wrapped distance from the origin
+                let wrap = |d: usize| d.min(size - d) as f64;
+                let (dx, dy) = (wrap(index % size), wrap(index / size));
+                (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+            })
+            .collect();
+        Self {
+            size,
+            kernel,
+            energy: vec![0.0; size * size],
+        }
+    }
+
+    fn add(&mut self, index: usize, sign: f64) {
+        let size = self.size;
+        let (x, y) = (index % size, index / size);
+        for (other, energy) in self.energy.iter_mut().enumerate() {
+            let dx = (other % size + size - x) % size;
+            let dy = (other / size + size - y) % size;
+            *energy += sign * self.kernel[dy * size + dx];
+        }
+    }
+
+    /// The set pixel with the most set neighbours.
+    fn tightest_cluster(&self, pattern: &[bool]) -> usize {
+        (0..pattern.len())
+            .filter(|&index| pattern[index])
+            .max_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+            .expect("at least one pixel is set")
+    }
+
+    /// The unset pixel with the fewest set neighbours.
+    fn largest_void(&self, pattern: &[bool]) -> usize {
+        (0..pattern.len())
+            .filter(|&index| !pattern[index])
+            .min_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+            .expect("at least one pixel is unset")
+    }
+}
+
+/// Ulichney's void and cluster method: the rank of every pixel in a
+/// dither array whose every threshold level is evenly spread.
+fn void_and_cluster(size: usize, sigma: f64) -> Vec<usize> {
+    let n = size * size;
+    let rng = &mut seeded_rng(0);
+
+    // a random initial pattern, relaxed until it is evenly spread
+    let mut pattern = vec![false; n];
+    let mut energy = Energy::new(size, sigma);
+    let mut ones = 0;
+    while ones < n / 10 {
+        let index = ((utils::rand(rng) * n as f64) as usize).min(n - 1);
+        if !pattern[index] {
+            pattern[index] = true;
+            energy.add(index, 1.0);
+            ones += 1;
+        }
+    }
+    loop {
+        let cluster = energy.tightest_cluster(&pattern);
+        pattern[cluster] = false;
+        energy.add(cluster, -1.0);
+        let void = energy.largest_void(&pattern);
+        pattern[void] = true;
+        energy.add(void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; n];
+
+    // rank the initial pattern by removing its tightest clusters
+    let initial_energy = energy.energy.clone();
+    let mut removing = pattern.clone();
+    for rank in (0..ones).rev() {
+        let cluster = energy.tightest_cluster(&removing);
+        removing[cluster] = false;
+        energy.add(cluster, -1.0);
+        ranks[cluster] = rank;
+    }
+    energy.energy = initial_energy;
+
+    // then fill the largest voids until every pixel is ranked
+    for rank in ones..n {
+        let void = energy.largest_void(&pattern);
+        pattern[void] = true;
+        energy.add(void, 1.0);
+        ranks[void] = rank;
+    }
+    ranks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{blue_noise, quantize, srgb, Dither, Operator, ToneMap, Transfer};
+    use crate::{output::Image, Vec3};
+
+    fn apply(tonemap: ToneMap, pixels: Vec<Vec3>) -> Vec<u8> {
+        tonemap.apply(&Image::new(pixels.len(), 1, pixels))
+    }
+
+    #[test]
+    fn test_srgb() {
+        assert_eq!(srgb(0.0), 0.0);
+        assert!((srgb(1.0) - 1.0).abs() < 1e-12);
+        // the two pieces meet
+        let knee = 0.003_130_8;
+        assert!((srgb(knee) - (1.055 * f64::powf(knee, 1.0 / 2.4) - 0.055)).abs() < 1e-6);
+        assert!((srgb(0.5) - 0.735_356_983).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_quantize_rounds_and_clamps() {
+        assert_eq!(quantize(0.5, 0.5), 128);
+        assert_eq!(quantize(127.4 / 255.0, 0.5), 127);
+        assert_eq!(quantize(2.0, 0.5), 255);
+        assert_eq!(quantize(-1.0, 0.5), 0);
+        assert_eq!(quantize(f64::NAN, 0.5), 0);
+    }
+
+    #[test]
+    fn test_operators() {
+        let pixels = vec![
+            vec3![0, 0, 0],
+            vec3![0.18, 0.18, 0.18],
+            vec3![100, 100, 100],
+        ];
+        for &operator in &[
+            Operator::Clamp,
+            Operator::Reinhard,
+            Operator::ExtendedReinhard,
+            Operator::Aces,
+            Operator::Hable,
+        ] {
+            let tonemap = ToneMap {
+                operator,
+                ..ToneMap::default()
+            };
+            let bytes = apply(tonemap, pixels.clone());
+            assert_eq!(&bytes[..3], [0, 0, 0], "{:?}", operator);
+            assert!(bytes[3] > 0 && bytes[3] < bytes[6], "{:?}", operator);
+        }
+        // extended Reinhard maps the brightest pixel to white
+        let tonemap = ToneMap {
+            operator: Operator::ExtendedReinhard,
+            ..ToneMap::default()
+        };
+        assert_eq!(&apply(tonemap, pixels)[6..], [255, 255, 255]);
+    }
+
+    #[test]
+    fn test_exposure() {
+        let tonemap = |exposure| ToneMap {
+            exposure,
+            transfer: Transfer::Gamma(1.0),
+            ..ToneMap::default()
+        };
+        let pixels = vec![vec3![0.25, 0.25, 0.25]];
+        assert_eq!(apply(tonemap(0.0), pixels.clone()), [64, 64, 64]);
+        assert_eq!(apply(tonemap(1.0), pixels.clone()), [128, 128, 128]);
+        assert_eq!(apply(tonemap(-1.0), pixels), [32, 32, 32]);
+    }
+
+    #[test]
+    fn test_dithering_preserves_mean() {
+        // a value between two steps should average out to it
+        let value = 100.3 / 255.0;
+        for &dither in &[Dither::Ordered, Dither::BlueNoise] {
+            let tonemap = ToneMap {
+                transfer: Transfer::Gamma(1.0),
+                dither,
+                ..ToneMap::default()
+            };
+            let image = Image::new(64, 64, vec![Vec3::from([value; 3]); 64 * 64]);
+            let bytes = tonemap.apply(&image);
+            let mean = bytes.iter().map(|&b| f64::from(b)).sum::<f64>() / bytes.len() as f64;
+            assert!((mean - 100.3).abs() < 0.02, "{:?} {}", dither, mean);
+            assert!(bytes.iter().all(|&b| b == 100 || b == 101));
+        }
+    }
+
+    #[test]
+    fn test_blue_noise_is_a_permutation() {
+        let mut mask = blue_noise().to_vec();
+        mask.sort_by(f64::total_cmp);
+        for (rank, threshold) in mask.iter().enumerate() {
+            assert_eq!(*threshold, (rank as f64 + 0.5) / mask.len() as f64);
+        }
+    }
+}
@@ -0,0 +1,438 @@
+//! Affine transforms, and instances that place a shared object with one.
+
+use crate::{utils::Rng, Aabb, HitRecord, Hittable, MediumHit, Ray, Vec3};
+use anyhow::{bail, Result};
+use nalgebra as na;
+use std::{borrow::Cow, sync::Arc};
+
+/// A 4x4 homogeneous transform acting on column vectors.
+pub type Matrix = na::Matrix4<f64>;
+
+pub fn translation(offset: Vec3) -> Matrix {
+    Matrix::new_translation(&offset.into_inner())
+}
+
+/// A right-handed rotation by `degrees` about `axis` through the origin.
+pub fn rotation(axis: Vec3, degrees: f64) -> Matrix {
+    Matrix::from_axis_angle(&axis.into_unit(), degrees.to_radians())
+}
+
+/// Scaling about the origin by a separate factor along each axis.
+pub fn scaling(factors: Vec3) -> Matrix {
+    Matrix::new_nonuniform_scaling(&factors.into_inner())
+}
+
+fn transform_point(matrix: &Matrix, point: Vec3) -> Vec3 {
+    matrix
+        .transform_point(&na::Point3::from(point.into_inner()))
+        .coords
+        .into()
+}
+
+fn transform_vector(matrix: &Matrix, vector: Vec3) -> Vec3 {
+    matrix.transform_vector(&vector.into_inner()).into()
+}
+
+fn linear_part(matrix: &Matrix) -> na::Matrix3<f64> {
+    matrix.fixed_slice::<na::U3, na::U3>(0, 0).into_owned()
+}
+
+/// # Panics
+///
+/// If `transform` isn't affine.
+fn assert_affine(transform: &Matrix) {
+    assert_eq!(
+        transform.row(3),
+        na::RowVector4::new(0.0, 0.0, 0.0, 1.0),
+        "instance transforms must be affine"
+    );
+}
+
+/// A transform together with what's needed to apply it to rays, normals and
+/// densities.
+#[derive(Clone)]
+struct Frame {
+    to_world: Matrix,
+    to_object: Matrix,
+    /// The inverse transpose of the linear part of `to_world`, which keeps
+    /// normals perpendicular to the transformed surface.
+    normal_to_world: na::Matrix3<f64>,
+    /// The determinant of the linear part of `to_object`.
+    det_to_object: f64,
+}
+
+impl Frame {
+    fn new(transform: Matrix) -> Self {
+        let to_object = transform
+            .try_inverse()
+            .expect("instance transforms must be invertible");
+        let linear_to_object = linear_part(&to_object);
+        Self {
+            to_world: transform,
+            to_object,
+            normal_to_world: linear_to_object.transpose(),
+            det_to_object: linear_to_object.determinant(),
+        }
+    }
+
+    /// `ray` in object space. The direction isn't normalized, so distances
+    /// along the ray carry over.
+    fn object_ray(&self, ray: Ray) -> Ray {
+        ray.spawn(
+            transform_point(&self.to_object, ray.origin()),
+            transform_vector(&self.to_object, ray.direction()),
+        )
+    }
+}
+
+/// An affine transform split into a translation, a rotation and a stretch,
+/// which interpolate without the shearing and shrinking that blending
+/// matrices directly would cause.
+struct Pose {
+    translation: na::Vector3<f64>,
+    rotation: na::UnitQuaternion<f64>,
+    /// Symmetric, and either positive or negative definite.
+    stretch: na::Matrix3<f64>,
+}
+
+impl Pose {
+    /// Split `transform` using the polar decomposition of its linear part.
+    fn new(transform: &Matrix) -> Self {
+        let svd = linear_part(transform).svd(true, true);
+        let (u, v_t) = (svd.u.unwrap(), svd.v_t.unwrap());
+        let mut rotation = u * v_t;
+        let mut stretch = v_t.transpose() * na::Matrix3::from_diagonal(&svd.singular_values) * v_t;
+        // keep the rotation proper, moving any mirroring into the stretch
+        if rotation.determinant() < 0.0 {
+            rotation = -rotation;
+            stretch = -stretch;
+        }
+        Self {
+            translation: transform.fixed_slice::<na::U3, na::U1>(0, 3).into_owned(),
+            rotation: na::UnitQuaternion::from_rotation_matrix(
+                &na::Rotation3::from_matrix_unchecked(rotation),
+            ),
+            stretch,
+        }
+    }
+
+    /// The transform a fraction `t` of the way from `self` to `end`.
+    fn lerp(&self, end: &Self, t: f64) -> Matrix {
+        let rotation = self.rotation.slerp(&end.rotation, t);
+        let stretch = self.stretch * (1.0 - t) + end.stretch * t;
+        let mut matrix = Matrix::identity();
+        matrix
+            .fixed_slice_mut::<na::U3, na::U3>(0, 0)
+            .copy_from(&(rotation.to_rotation_matrix().matrix() * stretch));
+        matrix
+            .fixed_slice_mut::<na::U3, na::U1>(0, 3)
+            .copy_from(&self.translation.lerp(&end.translation, t));
+        matrix
+    }
+}
+
+/// Check that [`Instance::moving`] can move an object from `start` to `end`.
+pub fn check_motion(start: &Matrix, end: &Matrix) -> Result<()> {
+    for transform in &[start, end] {
+        if transform.row(3) != na::RowVector4::new(0.0, 0.0, 0.0, 1.0) {
+            bail!("Expected an affine transform");
+        }
+        if linear_part(transform).determinant() == 0.0 {
+            bail!("Expected an invertible transform");
+        }
+    }
+    let mirrored = |transform: &Matrix| linear_part(transform).determinant() < 0.0;
+    if mirrored(start) != mirrored(end) {
+        bail!("Expected the start and end to both mirror the object, or neither");
+    }
+    let (start, end) = (Pose::new(start), Pose::new(end));
+    if start.rotation.angle_to(&end.rotation) > std::f64::consts::PI - 1e-9 {
+        bail!("Expected the start and end to be rotated less than half a turn apart");
+    }
+    Ok(())
+}
+
+enum Motion {
+    Static(Frame),
+    /// Moving from `start` at time 0 to `end` at time 1.
+    Moving {
+        start: Pose,
+        end: Pose,
+    },
+}
+
+/// An object placed in the world by an affine transform, which may change
+/// over the frame.
+///
+/// Rays are moved into the object's space rather than the object into the
+/// world, so any number of instances can share one object.
+pub struct Instance {
+    object: Arc<dyn Hittable + Send + Sync>,
+    motion: Motion,
+}
+
+impl Instance {
+    /// # Panics
+    ///
+    /// If `transform` isn't affine or isn't invertible.
+    pub fn new(object: Arc<dyn Hittable + Send + Sync>, transform: Matrix) -> Self {
+        assert_affine(&transform);
+        Self {
+            object,
+            motion: Motion::Static(Frame::new(transform)),
+        }
+    }
+
+    /// An instance moving from `start` at time 0 to `end` at time 1, and
+    /// staying put outside that interval.
+    ///
+    /// The translation, rotation and stretch of the transforms are
+    /// interpolated separately, with rotations taking the shorter way round.
+    ///
+    /// # Panics
+    ///
+    /// If either transform isn't affine or isn't invertible, if only one of
+    /// them mirrors the object, or if they are rotated exactly half a turn
+    /// apart, which leaves the way round ambiguous.
+    pub fn moving(object: Arc<dyn Hittable + Send + Sync>, start: Matrix, end: Matrix) -> Self {
+        if let Err(error) = check_motion(&start, &end) {
+            panic!("{}", error);
+        }
+        let (start, end) = (Pose::new(&start), Pose::new(&end));
+        Self {
+            object,
+            motion: Motion::Moving { start, end },
+        }
+    }
+
+    fn frame(&self, time: f64) -> Cow<'_, Frame> {
+        match &self.motion {
+            Motion::Static(frame) => Cow::Borrowed(frame),
+            Motion::Moving { start, end } => {
+                Cow::Owned(Frame::new(start.lerp(end, time.clamp(0.0, 1.0))))
+            }
+        }
+    }
+}
+
+/// How many times a moving instance's bounds are evaluated at.
+const BOUNDS_STEPS: usize = 32;
+
+impl Hittable for Instance {
+    fn hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let frame = self.frame(ray.time());
+        let rec = self.object.hit(frame.object_ray(ray), t_min, t_max)?;
+        let normal = Vec3::from(frame.normal_to_world * rec.normal.into_inner()).unitize();
+        Some(HitRecord {
+            point: transform_point(&frame.to_world, rec.point),
+            normal,
+            ..rec
+        })
+    }
+
+    fn occluded(&self, ray: Ray, t_min: f64, t_max: f64) -> bool {
+        let frame = self.frame(ray.time());
+        self.object.occluded(frame.object_ray(ray), t_min, t_max)
+    }
+
+    fn medium_hit(&self, ray: Ray, t_min: f64, t_max: f64) -> Option<MediumHit<'_>> {
+        let frame = self.frame(ray.time());
+        self.object.medium_hit(frame.object_ray(ray), t_min, t_max)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let bounds = self.object.bounding_box()?;
+        let (min, max) = (bounds.min(), bounds.max());
+        let corners = itertools::iproduct!(0..2, 0..2, 0..2)
+            .map(|(i, j, k)| {
+                let pick = |bit, axis: usize| if bit == 0 { min[axis] } else { max[axis] };
+                vec3![pick(i, 0), pick(j, 1), pick(k, 2)]
+            })
+            .collect::<Vec<_>>();
+        let transformed = |transform: &Matrix| {
+            corners.iter().fold(Aabb::empty(), |bounds, &corner| {
+                bounds.grow(transform_point(transform, corner))
+            })
+        };
+
+        match &self.motion {
+            Motion::Static(frame) => Some(transformed(&frame.to_world)),
+            Motion::Moving { start, end } => {
+                // Bound the boxes at evenly spaced times, then pad them by
+                // half the furthest any point of the object can travel
+                // between two of those times, which covers the points in
+                // between.
+                let steps = (0..=BOUNDS_STEPS)
+                    .map(|step| transformed(&start.lerp(end, step as f64 / BOUNDS_STEPS as f64)));
+                let bounds = steps.fold(Aabb::empty(), |bounds, step| bounds.union(&step));
+
+                let radius = corners
+                    .iter()
+                    .map(|corner| corner.norm())
+                    .fold(0.0, f64::max);
+                let stretched = start.stretch.norm().max(end.stretch.norm()) * radius;
+                let travel = start.rotation.angle_to(&end.rotation) * stretched
+                    + (end.stretch - start.stretch).norm() * radius
+                    + (end.translation - start.translation).norm();
+                let pad = 0.5 * travel / BOUNDS_STEPS as f64;
+                let pad = vec3![pad, pad, pad];
+                Some(Aabb::new(bounds.min() - pad, bounds.max() + pad))
+            }
+        }
+    }
+
+    fn sample_point(&self, origin: Vec3, time: f64, rng: &mut Rng) -> Option<Vec3> {
+        let frame = self.frame(time);
+        let point =
+            self.object
+                .sample_point(transform_point(&frame.to_object, origin), time, rng)?;
+        Some(transform_point(&frame.to_world, point))
+    }
+
+    /// The object's density for the corresponding object space direction,
+    /// times the Jacobian `|det L| / |L ω|³` of mapping unit directions `ω`
+    /// through the linear part `L` of the inverse transform.
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, time: f64) -> f64 {
+        let frame = self.frame(time);
+        let direction = transform_vector(&frame.to_object, direction.unitize());
+        let pdf = self
+            .object
+            .pdf_value(transform_point(&frame.to_object, origin), direction, time);
+        pdf * frame.det_to_object.abs() / direction.norm().powi(3)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_motion, rotation, scaling, translation, Instance, Matrix};
+    use crate::{
+        utils::{random_unit_vector, seeded_rng},
+        BoxShape, Hittable, Lambertian, Quad, Ray, Sphere, Vec3,
+    };
+    use std::sync::Arc;
+
+    fn grey() -> Lambertian {
+        Lambertian::new(vec3![0.5, 0.5, 0.5])
+    }
+
+    #[test]
+    fn test_translated_sphere() {
+        let sphere = Arc::new(Sphere::new(Vec3::zeros(), 1.0, grey()));
+        let instance = Instance::new(sphere, translation(vec3![0, 0, -5]));
+        let rec = instance
+            .hit(Ray::new(Vec3::zeros(), vec3![0, 0, -2]), 0.0, f64::MAX)
+            .unwrap();
+        assert_eq!(rec.t, 2.0);
+        assert_eq!(rec.point, vec3![0, 0, -4]);
+        assert_eq!(rec.normal, vec3![0, 0, 1]);
+        let bounds = instance.bounding_box().unwrap();
+        assert_eq!(bounds.min(), vec3![-1, -1, -6]);
+    }
+
+    #[test]
+    fn test_normals_use_inverse_transpose() {
+        // an ellipsoid twice as wide along x
+        let sphere = Arc::new(Sphere::new(Vec3::zeros(), 1.0, grey()));
+        let instance = Instance::new(sphere, scaling(vec3![2, 1, 1]));
+        let rng = &mut seeded_rng(0);
+        for _ in 0..100 {
+            let direction = random_unit_vector(rng);
+            let rec = instance
+                .hit(Ray::new(Vec3::zeros(), direction), 0.0, f64::MAX)
+                .unwrap();
+            // the gradient of x²/4 + y² + z²
+            let p = rec.point;
+            let expected = vec3![p.x() / 4.0, p.y(), p.z()].unitize();
+            assert!((rec.normal - expected).norm() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_rotated_box_bounds() {
+        let cube = Arc::new(BoxShape::new(Vec3::zeros(), vec3![1, 1, 1], grey()));
+        let instance = Instance::new(cube, rotation(vec3![0, 1, 0], 45.0));
+        let bounds = instance.bounding_box().unwrap();
+        let half_diagonal = 0.5_f64.sqrt();
+        assert!((bounds.min() - vec3![0, 0, -half_diagonal]).norm() < 1e-9);
+        assert!((bounds.max() - vec3![2.0 * half_diagonal, 1, half_diagonal]).norm() < 1e-9);
+    }
+
+    #[test]
+    fn test_pdf_integrates_to_one() {
+        // a light stretched, sheared and moved, so that solid angles change
+        let light = Arc::new(Quad::new(
+            vec3![-0.5, 0, -0.5],
+            vec3![1, 0, 0],
+            vec3![0, 0, 1],
+            grey(),
+        ));
+        let mut shear = scaling(vec3![3, 1, 0.5]);
+        shear[(0, 1)] = 0.5;
+        let instance = Instance::new(light, translation(vec3![0.5, 2, 0]) * shear);
+        let rng = &mut seeded_rng(0);
+        let n = 200_000;
+        let sum = (0..n)
+            .map(|_| instance.pdf_value(Vec3::zeros(), random_unit_vector(rng), 0.0))
+            .sum::<f64>();
+        let integral = sum * 4.0 * std::f64::consts::PI / n as f64;
+        assert!((integral - 1.0).abs() < 0.05, "{}", integral);
+
+        let point = instance.sample_point(Vec3::zeros(), 0.0, rng).unwrap();
+        assert!((point.y() - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_moving_instance() {
+        let cube = Arc::new(BoxShape::new(vec3![-1, -1, -1], vec3![1, 1, 1], grey()));
+        let instance = Instance::moving(
+            cube,
+            Matrix::identity(),
+            translation(vec3![10, 0, 0]) * rotation(vec3![0, 0, 1], 90.0) * scaling(vec3![3, 1, 1]),
+        );
+        let hit = |origin, direction, time| {
+            instance
+                .hit(Ray::with_time(origin, direction, time), 0.0, f64::MAX)
+                .map(|rec| rec.point)
+        };
+        // halfway there, four wide and two tall and turned by 45 degrees, so
+        // going down through the center leaves through the top face
+        let point = hit(vec3![5, 10, 0], vec3![0, -1, 0], 0.5).unwrap();
+        assert!(
+            (point - vec3![5, 2.0_f64.sqrt(), 0]).norm() < 1e-9,
+            "{:?}",
+            point
+        );
+        // ending upright, three tall
+        let point = hit(vec3![10, 10, 0], vec3![0, -1, 0], 1.0).unwrap();
+        assert!((point - vec3![10, 3, 0]).norm() < 1e-9);
+
+        let bounds = instance.bounding_box().unwrap();
+        let rng = &mut seeded_rng(0);
+        for _ in 0..10_000 {
+            let time = crate::utils::rand(rng);
+            let origin = 20.0 * random_unit_vector(rng) + vec3![5, 0, 0];
+            let target = vec3![10.0 * crate::utils::rand(rng), 0, 0];
+            if let Some(point) = hit(origin, target - origin, time) {
+                assert_eq!(bounds.grow(point), bounds);
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_motion() {
+        let start = Matrix::identity();
+        assert!(check_motion(&start, &rotation(vec3![0, 1, 0], 179.0)).is_ok());
+        assert!(check_motion(&start, &rotation(vec3![0, 1, 0], 180.0)).is_err());
+        assert!(check_motion(&start, &scaling(vec3![-1, 1, 1])).is_err());
+        let mirror = scaling(vec3![-1, 1, 1]);
+        assert!(check_motion(&mirror, &(rotation(vec3![0, 1, 0], 90.0) * mirror)).is_ok());
+        assert!(check_motion(&start, &scaling(vec3![0, 1, 1])).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "invertible")]
+    fn test_singular_transform() {
+        let sphere = Arc::new(Sphere::new(Vec3::zeros(), 1.0, grey()));
+        Instance::new(sphere, scaling(vec3![1, 0, 1]));
+    }
+}
@@ -1,5 +1,5 @@
 use nalgebra as na;
-use std::ops::{Add, AddAssign, Div, DivAssign, Mul, Neg, Sub};
+use std::ops::{Add, AddAssign, Div, DivAssign, Index, Mul, MulAssign, Neg, Sub};
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub struct Vec3(na::Vector3<f64>);
@@ -83,6 +83,14 @@ impl Vec3 {
         self.0.cross(&other.into().0).into()
     }
 
+    pub fn inf(&self, other: impl Into<Self>) -> Self {
+        self.0.inf(&other.into().0).into()
+    }
+
+    pub fn sup(&self, other: impl Into<Self>) -> Self {
+        self.0.sup(&other.into().0).into()
+    }
+
     pub fn powf(&self, n: f64) -> Self {
         self.0.map(|value| f64::powf(value, n)).into()
     }
@@ -144,6 +152,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_is_empty() {
         let u = crate::vec3![0, 0, 0];
         assert_eq!(u.is_empty(), false);
@@ -193,6 +202,28 @@ mod tests {
         ]);
         assert_eq!(result, expected);
     }
+
+    #[test]
+    fn test_inf_sup() {
+        let u = Vec3::from([1.0, -2.0, 3.0]);
+        let v = Vec3::from([0.0, 2.0, 5.0]);
+        assert_eq!(u.inf(v), Vec3::from([0.0, -2.0, 3.0]));
+        assert_eq!(u.sup(v), Vec3::from([1.0, 2.0, 5.0]));
+    }
+
+    #[test]
+    fn test_index() {
+        let u = Vec3::from([1.0, 2.0, 3.0]);
+        assert_eq!([u[0], u[1], u[2]], [u.x(), u.y(), u.z()]);
+    }
+}
+
+impl Index<usize> for Vec3 {
+    type Output = f64;
+
+    fn index(&self, index: usize) -> &f64 {
+        &self.0[index]
+    }
 }
 
 impl Add<f64> for Vec3 {
@@ -279,6 +310,12 @@ impl Mul for Vec3 {
     }
 }
 
+impl MulAssign for Vec3 {
+    fn mul_assign(&mut self, other: Self) {
+        self.0.component_mul_assign(&other.0)
+    }
+}
+
 impl Div<f64> for Vec3 {
     type Output = Self;
 
@@ -0,0 +1,258 @@
+//! Voxel grids for heterogeneous media, loaded from Mitsuba's `.vol` files or
+//! sampled from a function such as noise.
+
+use crate::{Aabb, Vec3};
+use anyhow::{bail, Context, Result};
+use std::{
+    convert::{TryFrom, TryInto},
+    path::Path,
+};
+
+const HEADER_LEN: usize = 48;
+/// The `.vol` encoding of little-endian 32-bit floats, the only one read.
+const FLOAT32: i32 = 1;
+
+/// Values on a regular lattice of points spanning a box, from one corner to
+/// the opposite one, with any number of channels at each point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxelGrid {
+    resolution: [usize; 3],
+    channels: usize,
+    bounds: Aabb,
+    /// Channels vary fastest, then x, then y, then z.
+    data: Vec<f32>,
+}
+
+impl VoxelGrid {
+    /// Panics if any dimension of `resolution` or `channels` is zero, or if
+    /// `data` doesn't hold exactly one value per channel per lattice point.
+    pub fn new(resolution: [usize; 3], channels: usize, bounds: Aabb, data: Vec<f32>) -> Self {
+        assert!(
+            resolution.iter().all(|&n| n > 0) && channels > 0,
+            "voxel grids need at least one point and channel"
+        );
+        assert_eq!(
+            data.len(),
+            resolution.iter().product::<usize>() * channels,
+            "voxel grids need a value per channel per point"
+        );
+        Self {
+            resolution,
+            channels,
+            bounds,
+            data,
+        }
+    }
+
+    /// A single channel grid of `f` at every lattice point.
+    pub fn from_fn(resolution: [usize; 3], bounds: Aabb, mut f: impl FnMut(Vec3) -> f64) -> Self {
+        let [nx, ny, nz] = resolution;
+        let data = itertools::iproduct!(0..nz, 0..ny, 0..nx)
+            .map(|(k, j, i)| {
+                let fraction = |index: usize, n: usize| {
+                    if n > 1 {
+                        index as f64 / (n - 1) as f64
+                    } else {
+                        0.5
+                    }
+                };
+                let offset = vec3![fraction(i, nx), fraction(j, ny), fraction(k, nz)];
+                f(bounds.min() + offset * bounds.extent()) as f32
+            })
+            .collect();
+        Self::new(resolution, 1, bounds, data)
+    }
+
+    /// Read a grid in Mitsuba's `.vol` format: the bytes `VOL` and the
+    /// version 3, then little-endian the encoding (1 for 32-bit floats), the
+    /// x, y and z resolutions and the number of channels as 32-bit integers,
+    /// the minimum and maximum corners of the bounds as 32-bit floats, and
+    /// finally the values as 32-bit floats.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Unable to read voxel grid {}", path.display()))?;
+        Self::parse(&bytes).with_context(|| format!("Invalid voxel grid {}", path.display()))
+    }
+
+    /// Parse the contents of a `.vol` file, as described for [`VoxelGrid::load`].
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_LEN || &bytes[..3] != b"VOL" {
+            bail!("Expected a header starting with `VOL`");
+        }
+        if bytes[3] != 3 {
+            bail!("Expected version 3, got {}", bytes[3]);
+        }
+        let word = |index: usize| -> [u8; 4] {
+            let start = 4 + 4 * index;
+            bytes[start..start + 4].try_into().expect("four bytes")
+        };
+        let int = |index| i32::from_le_bytes(word(index));
+        let float = |index| f64::from(f32::from_le_bytes(word(index)));
+
+        let encoding = int(0);
+        if encoding != FLOAT32 {
+            bail!(
+                "Expected 32-bit float values (encoding {}), got encoding {}",
+                FLOAT32,
+                encoding
+            );
+        }
+        let mut counts = [0usize; 4];
+        for (count, index) in counts.iter_mut().zip(1..) {
+            *count = match usize::try_from(int(index)) {
+                Ok(count) if count > 0 => count,
+                _ => bail!(
+                    "Expected positive resolutions and channels, got {}",
+                    int(index)
+                ),
+            };
+        }
+        let [nx, ny, nz, channels] = counts;
+        let bounds = Aabb::new(
+            vec3![float(5), float(6), float(7)],
+            vec3![float(8), float(9), float(10)],
+        );
+
+        let values = &bytes[HEADER_LEN..];
+        let count = nx
+            .checked_mul(ny)
+            .and_then(|n| n.checked_mul(nz))
+            .and_then(|n| n.checked_mul(channels))
+            .with_context(|| {
+                format!(
+                    "Grid of {}x{}x{} points with {} channels is too large",
+                    nx, ny, nz, channels
+                )
+            })?;
+        if count.checked_mul(4) != Some(values.len()) {
+            bail!(
+                "Expected {} values for {}x{}x{} points with {} channels, found {} bytes",
+                count,
+                nx,
+                ny,
+                nz,
+                channels,
+                values.len()
+            );
+        }
+        let data = values
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().expect("four bytes")))
+            .collect();
+        Ok(Self::new([nx, ny, nz], channels, bounds, data))
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.bounds
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn at(&self, [i, j, k]: [usize; 3], channel: usize) -> f64 {
+        let [nx, ny, _] = self.resolution;
+        f64::from(self.data[((k * ny + j) * nx + i) * self.channels + channel])
+    }
+
+    /// The trilinear interpolation of `channel` at `point`, or zero outside
+    /// the grid's bounds.
+    pub fn value(&self, point: Vec3, channel: usize) -> f64 {
+        let (min, max) = (self.bounds.min(), self.bounds.max());
+        if (0..3).any(|axis| point[axis] < min[axis] || point[axis] > max[axis]) {
+            return 0.0;
+        }
+        let mut cells = [(0, 0, 0.0); 3];
+        for (axis, cell) in cells.iter_mut().enumerate() {
+            let n = self.resolution[axis];
+            let extent = max[axis] - min[axis];
+            if n == 1 || extent <= 0.0 {
+                continue;
+            }
+            let coordinate = (point[axis] - min[axis]) / extent * (n - 1) as f64;
+            let index = (coordinate.floor() as usize).min(n - 2);
+            *cell = (index, index + 1, coordinate - index as f64);
+        }
+
+        let [(i0, i1, wx), (j0, j1, wy), (k0, k1, wz)] = cells;
+        let lerp = |a: f64, b: f64, w: f64| a + w * (b - a);
+        let row = |j, k| {
+            lerp(
+                self.at([i0, j, k], channel),
+                self.at([i1, j, k], channel),
+                wx,
+            )
+        };
+        let slice = |k| lerp(row(j0, k), row(j1, k), wy);
+        lerp(slice(k0), slice(k1), wz)
+    }
+
+    /// The largest value of `channel`, which bounds its interpolation.
+    pub fn max(&self, channel: usize) -> f64 {
+        self.data
+            .iter()
+            .skip(channel)
+            .step_by(self.channels)
+            .fold(f64::NEG_INFINITY, |max, &value| max.max(f64::from(value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VoxelGrid;
+    use crate::{Aabb, Vec3};
+
+    /// A `.vol` file of a two channel grid.
+    fn vol_file(resolution: [i32; 3], bounds: [f32; 6], values: &[f32]) -> Vec<u8> {
+        let mut bytes = b"VOL\x03".to_vec();
+        for int in [1, resolution[0], resolution[1], resolution[2], 2] {
+            bytes.extend_from_slice(&int.to_le_bytes());
+        }
+        for float in bounds.iter().chain(values) {
+            bytes.extend_from_slice(&float.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_parse() {
+        // a density ramp along x, with emission only at the far end
+        let values = [0.0, 0.0, 1.0, 0.0, 2.0, 0.0, 3.0, 5.0];
+        let bytes = vol_file([4, 1, 1], [0.0, -1.0, -1.0, 3.0, 1.0, 1.0], &values);
+        let grid = VoxelGrid::parse(&bytes).unwrap();
+        assert_eq!(grid.channels(), 2);
+        assert_eq!(grid.bounds(), Aabb::new(vec3![0, -1, -1], vec3![3, 1, 1]));
+        assert_eq!(grid.value(vec3![1.5, 0.3, -0.2], 0), 1.5);
+        assert_eq!(grid.value(vec3![2.5, 0, 0], 1), 2.5);
+        assert_eq!(grid.value(vec3![3, 1, 1], 0), 3.0);
+        assert_eq!(grid.value(vec3![3.5, 0, 0], 0), 0.0);
+        assert_eq!((grid.max(0), grid.max(1)), (3.0, 5.0));
+
+        let error = |bytes: &[u8]| format!("{:#}", VoxelGrid::parse(bytes).unwrap_err());
+        assert_eq!(
+            error(&bytes[..bytes.len() - 4]),
+            "Expected 8 values for 4x1x1 points with 2 channels, found 28 bytes"
+        );
+        assert!(error(&bytes[1..]).contains("`VOL`"));
+        let huge = vol_file([i32::MAX; 3], [0.0, 0.0, 0.0, 1.0, 1.0, 1.0], &values);
+        assert!(error(&huge).contains("too large"));
+        let mut half_floats = bytes.clone();
+        half_floats[4] = 2;
+        assert!(error(&half_floats).contains("got encoding 2"));
+    }
+
+    #[test]
+    fn test_trilinear() {
+        let bounds = Aabb::new(Vec3::zeros(), vec3![2, 2, 2]);
+        // trilinear interpolation reproduces linear functions exactly
+        let linear = |p: Vec3| 1.0 + p.x() - 2.0 * p.y() + 0.5 * p.z();
+        let grid = VoxelGrid::from_fn([3, 5, 2], bounds, linear);
+        let rng = &mut crate::utils::seeded_rng(0);
+        for _ in 0..100 {
+            let point = 2.0 * crate::utils::randvec(rng);
+            assert!((grid.value(point, 0) - linear(point)).abs() < 1e-5);
+        }
+        assert!((grid.max(0) - 4.0).abs() < 1e-6);
+    }
+}